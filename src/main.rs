@@ -1,18 +1,20 @@
-mod serve;
-use serve::api::v1::API;
-use serve::authorizer::Authorizer;
-use serve::configuration::ConfigWrapper;
-use serve::database::DatabaseController;
-use serve::emailer::Emailer;
-use serve::oauth::Oauth;
-use serve::server::Server;
+use qamaits::serve::api::errors;
+use qamaits::serve::api::v1::API;
+use qamaits::serve::authorizer::Authorizer;
+use qamaits::serve::configuration::ConfigWrapper;
+use qamaits::serve::database::DatabaseController;
+use qamaits::serve::emailer::Emailer;
+use qamaits::serve::oauth::Oauth;
+use qamaits::serve::server::Server;
+use qamaits::serve::uploader::Uploader;
 use std::fs;
 use std::fs::{File, OpenOptions};
 use std::io::Write;
 use std::path::Path;
 use std::process::Command;
 use std::sync::{Arc, Mutex};
-use std::time::SystemTime;
+use std::thread;
+use std::time::{Duration, SystemTime};
 use warp::filters::log::Info;
 use warp::Filter;
 
@@ -24,15 +26,12 @@ async fn main() {
             let mut authorizer = Authorizer::new();
             let emailer = Emailer::new(20);
             let mailer = Arc::new(Mutex::new(emailer));
-            let serve = Arc::new(Mutex::new(server.clone()));
-            let conf = Arc::new(Mutex::new(config.clone().configuration.clone()));
-            let con = Arc::clone(&conf.clone());
+            let conf = Arc::new(config.clone().configuration.clone());
+            let con = Arc::clone(&conf);
             let mut auths: Vec<String> = Vec::new();
             for i in 0..config.clone().configuration.oauth.auths.len() {
                 let auth = config.configuration.oauth.auths[i].clone();
-                let serve = Arc::clone(&serve);
-                let server = serve.lock().unwrap();
-                match DatabaseController::get_oauth_record(&server.clone(), auth.clone().name) {
+                match DatabaseController::get_oauth_record(&server, auth.clone().name) {
                     Ok(conf) => {
                         config.configuration.oauth.auths[i] = conf;
                     }
@@ -58,7 +57,6 @@ async fn main() {
 
                 let out: String;
 
-                let con = con.lock().unwrap();
                 let path = Path::new(&con.server.access_log);
 
                 let mut file_obj: File;
@@ -102,10 +100,26 @@ async fn main() {
                 file_obj.write(out.as_bytes()).unwrap();
             });
 
+            // Accounts whose deletion grace period ran out are removed hourly
+            let purger = server.clone();
+            let purge_config = Arc::clone(&conf);
+            thread::spawn(move || loop {
+                if let Err(e) = DatabaseController::purge_deleted_users(&purger, &purge_config) {
+                    println!("{:?}", e);
+                }
+                thread::sleep(Duration::from_secs(3600));
+            });
+
             let base = warp::path::end().and(warp::fs::dir("www"));
             let assets = warp::path("assets").and(warp::fs::dir("www/assets"));
             let stat = warp::path("static").and(warp::fs::dir("www/static"));
-            let api_routing = API::setup(mailer, serve, conf);
+            let media = Uploader::new(
+                server.clone(),
+                config.clone().configuration.media,
+                config.clone().configuration.roles,
+            )
+            .route();
+            let api_routing = API::setup(mailer, server.clone(), conf);
             let oauth = authorizer.clone().route();
             let robots =
                 warp::path("robots.txt").map(|| fs::read_to_string("www/robots.txt").unwrap());
@@ -116,9 +130,11 @@ async fn main() {
                 .or(base)
                 .or(assets)
                 .or(stat)
+                .or(media)
                 .or(api_routing)
                 .or(oauth)
                 .or(base_files)
+                .recover(errors::handle_rejection)
                 .with(log);
 
             println!(
@@ -126,7 +142,7 @@ async fn main() {
                 config.clone().configuration.server.address,
                 server.clone().port
             );
-            println!("Database: {}", server.clone().database.database.name());
+            println!("Database: {}", server.clone().database.storage.name());
             Command::new("./qamaits-redirect")
                 .arg("-host")
                 .arg(config.clone().configuration.server.hostname)
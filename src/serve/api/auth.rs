@@ -0,0 +1,152 @@
+use super::super::database::DatabaseController;
+use super::super::database_errors::DatabaseError;
+use super::super::database_structures::{AccessRecord, Principal, SessionClient, User};
+use super::super::server::Server;
+use std::net::{IpAddr, SocketAddr};
+use warp::reject::{Reject, Rejection};
+use warp::Filter;
+
+#[derive(Debug)]
+pub struct Unauthorized {
+    pub details: String,
+}
+
+impl Reject for Unauthorized {}
+
+impl Unauthorized {
+    pub fn new(msg: &str) -> Unauthorized {
+        Unauthorized {
+            details: msg.to_string(),
+        }
+    }
+}
+
+fn bearer_token(header: &str) -> Option<&str> {
+    let mut parts = header.splitn(2, ' ');
+    match (parts.next(), parts.next()) {
+        (Some(scheme), Some(token)) if scheme.eq_ignore_ascii_case("bearer") && !token.trim().is_empty() => {
+            Some(token.trim())
+        }
+        _ => None,
+    }
+}
+
+async fn resolve<T, F>(server: Server, header: String, find: F) -> Result<T, Rejection>
+where
+    T: Send + 'static,
+    F: FnOnce(&Server, &str) -> Result<T, DatabaseError> + Send + 'static,
+{
+    match bearer_token(&header) {
+        Some(token) => {
+            let token = token.to_string();
+            match server.run(move |server| find(server, &token)).await {
+                Ok(found) => Ok(found),
+                Err(e @ DatabaseError::InvalidCredentialsError(_)) | Err(e @ DatabaseError::NotFoundError(_)) => {
+                    Err(warp::reject::custom(Unauthorized::new(&format!("{}", e))))
+                }
+                // Anything else is a server fault, left to `handle_rejection` so its text isn't leaked
+                Err(e) => Err(warp::reject::custom(e)),
+            }
+        }
+        None => Err(warp::reject::custom(Unauthorized::new(
+            "The Authorization header must be of the form: Bearer <token>",
+        ))),
+    }
+}
+
+/// Extracts the `Principal` behind the `Authorization: Bearer <token>` access token, rejecting with
+/// `Unauthorized` when the header is missing, malformed, unknown or expired.
+pub fn authenticated(server: Server) -> impl Filter<Extract = (Principal,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization").and_then(move |header: Option<String>| {
+        let server = server.clone();
+        async move {
+            match header {
+                Some(header) => resolve(server, header, DatabaseController::find_principal).await,
+                None => Err(warp::reject::custom(Unauthorized::new(
+                    "Missing Authorization header",
+                ))),
+            }
+        }
+    })
+}
+
+/// Like `authenticated` but lets anonymous requests through as `None`. A header that is present
+/// but invalid is still rejected.
+pub fn optional(server: Server) -> impl Filter<Extract = (Option<Principal>,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization").and_then(move |header: Option<String>| {
+        let server = server.clone();
+        async move {
+            match header {
+                Some(header) => resolve(server, header, DatabaseController::find_principal)
+                    .await
+                    .map(Some),
+                None => Ok(None),
+            }
+        }
+    })
+}
+
+/// Like `authenticated` but loads the whole `User`, for routes that read or change the account.
+pub fn account(server: Server) -> impl Filter<Extract = (User,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization").and_then(move |header: Option<String>| {
+        let server = server.clone();
+        async move {
+            match header {
+                Some(header) => resolve(server, header, DatabaseController::find_user_by_access_token).await,
+                None => Err(warp::reject::custom(Unauthorized::new(
+                    "Missing Authorization header",
+                ))),
+            }
+        }
+    })
+}
+
+/// Like `authenticated` but also extracts the session the access token belongs to.
+pub fn session(server: Server) -> impl Filter<Extract = (User, AccessRecord), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let server = server.clone();
+            async move {
+                match header {
+                    Some(header) => resolve(server, header, DatabaseController::find_session).await,
+                    None => Err(warp::reject::custom(Unauthorized::new(
+                        "Missing Authorization header",
+                    ))),
+                }
+            }
+        })
+        .untuple_one()
+}
+
+/// The user agent and address a request came from. The socket address is used unless it's one of
+/// `trusted_proxies`, then `X-Forwarded-For` is read from the right, past any further trusted
+/// hops, so a client can't pick the address its logins are counted against.
+pub fn client(trusted_proxies: &[String]) -> impl Filter<Extract = (SessionClient,), Error = Rejection> + Clone {
+    let trusted: Vec<IpAddr> = trusted_proxies.iter().filter_map(|ip| ip.parse().ok()).collect();
+    warp::header::optional::<String>("user-agent")
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .and(warp::addr::remote())
+        .map(move |user_agent: Option<String>, forwarded: Option<String>, remote: Option<SocketAddr>| {
+            let mut ip = remote.map(|remote| remote.ip());
+            if let (Some(peer), Some(forwarded)) = (ip, forwarded) {
+                if trusted.contains(&peer) {
+                    for hop in forwarded.rsplit(',') {
+                        match hop.trim().parse::<IpAddr>() {
+                            Ok(hop) => {
+                                ip = Some(hop);
+                                if !trusted.contains(&hop) {
+                                    break;
+                                }
+                            }
+                            Err(_e) => break,
+                        }
+                    }
+                }
+            }
+            SessionClient {
+                device: None,
+                user_agent,
+                ip: ip.map(|ip| ip.to_string()),
+            }
+        })
+}
@@ -0,0 +1,109 @@
+use super::super::database_errors::DatabaseError;
+use super::auth::Unauthorized;
+use super::requests::FieldError;
+use super::v1::APIResponse;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use warp::body::BodyDeserializeError;
+use warp::http::StatusCode;
+use warp::reject::{InvalidQuery, MethodNotAllowed, PayloadTooLarge};
+use warp::reply::{Json, Response, WithStatus};
+use warp::{Rejection, Reply};
+
+/// Codes for rejections raised by warp itself, continuing after the `DatabaseError` codes.
+pub const BAD_REQUEST: u32 = 6;
+pub const PAYLOAD_TOO_LARGE: u32 = 7;
+pub const METHOD_NOT_ALLOWED: u32 = 8;
+
+/// The `data` of every failed api response.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ErrorBody {
+    pub code: u32,
+    pub error: String,
+    pub fields: Option<Vec<FieldError>>,
+}
+
+pub fn status(e: &DatabaseError) -> StatusCode {
+    match *e {
+        DatabaseError::NotFoundError(_) => StatusCode::NOT_FOUND,
+        DatabaseError::InvalidCredentialsError(_) => StatusCode::UNAUTHORIZED,
+        DatabaseError::AlreadyExistsError(_) => StatusCode::CONFLICT,
+        DatabaseError::PermissionDeniedError(_) => StatusCode::FORBIDDEN,
+        DatabaseError::RateLimitedError(_) => StatusCode::TOO_MANY_REQUESTS,
+        DatabaseError::ValidationError(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+fn name(code: u32) -> &'static str {
+    match code {
+        1 => "invalid_credentials",
+        2 => "not_found",
+        3 => "already_exists",
+        4 => "permission_denied",
+        5 => "validation_failed",
+        BAD_REQUEST => "bad_request",
+        PAYLOAD_TOO_LARGE => "payload_too_large",
+        METHOD_NOT_ALLOWED => "method_not_allowed",
+        9 => "rate_limited",
+        _ => "internal_error",
+    }
+}
+
+fn failure(status: StatusCode, code: u32, message: String, fields: Option<Vec<FieldError>>) -> WithStatus<Json> {
+    warp::reply::with_status(
+        warp::reply::json(&APIResponse {
+            status: "fail".to_string(),
+            message: Some(message),
+            data: Some(ErrorBody {
+                code,
+                error: name(code).to_string(),
+                fields,
+            }),
+        }),
+        status,
+    )
+}
+
+/// The response for a failed request. Internal errors are logged and answered with a generic
+/// message instead of their database or io details.
+pub fn error_reply(e: &DatabaseError) -> WithStatus<Json> {
+    let message = if e.is_internal() {
+        println!("{:?}", e);
+        "Internal server error".to_string()
+    } else {
+        e.to_string()
+    };
+    let fields = match *e {
+        DatabaseError::ValidationError(ref errors) => Some(FieldError::from_errors(errors)),
+        _ => None,
+    };
+    failure(status(e), e.code(), message, fields)
+}
+
+/// Awaits a handler and turns its failure into a rejection for `handle_rejection` to answer.
+pub async fn rejected<T, F>(handler: F) -> Result<T, Rejection>
+where
+    F: Future<Output = Result<T, DatabaseError>>,
+{
+    handler.await.map_err(warp::reject::custom)
+}
+
+pub async fn handle_rejection(err: Rejection) -> Result<Response, Rejection> {
+    if let Some(e) = err.find::<DatabaseError>() {
+        Ok(error_reply(e).into_response())
+    } else if let Some(e) = err.find::<Unauthorized>() {
+        let reply = failure(StatusCode::UNAUTHORIZED, 1, e.details.clone(), None);
+        Ok(warp::reply::with_header(reply, "www-authenticate", "Bearer").into_response())
+    } else if let Some(e) = err.find::<BodyDeserializeError>() {
+        Ok(failure(StatusCode::BAD_REQUEST, BAD_REQUEST, e.to_string(), None).into_response())
+    } else if let Some(e) = err.find::<InvalidQuery>() {
+        Ok(failure(StatusCode::BAD_REQUEST, BAD_REQUEST, e.to_string(), None).into_response())
+    } else if let Some(e) = err.find::<PayloadTooLarge>() {
+        Ok(failure(StatusCode::PAYLOAD_TOO_LARGE, PAYLOAD_TOO_LARGE, e.to_string(), None).into_response())
+    } else if let Some(e) = err.find::<MethodNotAllowed>() {
+        Ok(failure(StatusCode::METHOD_NOT_ALLOWED, METHOD_NOT_ALLOWED, e.to_string(), None).into_response())
+    } else {
+        Err(err)
+    }
+}
@@ -1 +1,5 @@
+pub mod auth;
+pub mod errors;
+pub mod requests;
+pub mod rest;
 pub mod v1;
\ No newline at end of file
@@ -0,0 +1,386 @@
+use super::super::database_errors::DatabaseError;
+use super::super::database_structures::{MediaVisibility, PostStatus};
+use serde::de::{DeserializeOwned, Deserializer};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use validator::{Validate, ValidationError, ValidationErrors, ValidationErrorsKind};
+use validator_derive::Validate;
+
+/// One entry of the error list returned when a request fails validation.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct FieldError {
+    pub field: String,
+    pub code: String,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn from_errors(errors: &ValidationErrors) -> Vec<FieldError> {
+        let mut fields = Vec::new();
+        for (field, kind) in errors.errors() {
+            if let ValidationErrorsKind::Field(errors) = kind {
+                for error in errors {
+                    fields.push(FieldError {
+                        field: field.to_string(),
+                        code: error.code.to_string(),
+                        message: match error.message {
+                            Some(ref message) => message.to_string(),
+                            None => error.code.to_string(),
+                        },
+                    });
+                }
+            }
+        }
+        fields.sort_by(|a, b| a.field.cmp(&b.field));
+        fields
+    }
+}
+
+/// Validates a request that has already been deserialized, e.g. a json body.
+pub fn validated<T: Validate>(request: T) -> Result<T, DatabaseError> {
+    match request.validate() {
+        Ok(()) => Ok(request),
+        Err(e) => Err(DatabaseError::ValidationError(e)),
+    }
+}
+
+/// A validation failure on `field` for input that can only be judged past the request itself.
+pub fn invalid_field(field: &'static str, code: &'static str, message: String) -> DatabaseError {
+    let mut errors = ValidationErrors::new();
+    let mut error = ValidationError::new(code);
+    error.message = Some(message.into());
+    errors.add(field, error);
+    DatabaseError::ValidationError(errors)
+}
+
+/// Builds and validates a request from the string map sent to the action api.
+pub fn from_data<T: DeserializeOwned + Validate>(data: &HashMap<String, String>) -> Result<T, DatabaseError> {
+    let mut object = serde_json::Map::new();
+    for (key, value) in data {
+        object.insert(key.clone(), serde_json::Value::String(value.clone()));
+    }
+    match serde_json::from_value::<T>(serde_json::Value::Object(object)) {
+        Ok(request) => validated(request),
+        Err(e) => {
+            let mut errors = ValidationErrors::new();
+            let mut error = ValidationError::new("invalid");
+            error.message = Some(e.to_string().into());
+            errors.add("data", error);
+            Err(DatabaseError::ValidationError(errors))
+        }
+    }
+}
+
+/// Numbers arrive as strings from query strings and the action api but as numbers from json.
+fn lenient_number<'de, D>(deserializer: D) -> Result<Option<i64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Number {
+        Int(i64),
+        Str(String),
+    }
+    match Option::<Number>::deserialize(deserializer)? {
+        Some(Number::Int(n)) => Ok(Some(n)),
+        Some(Number::Str(s)) => s.parse::<i64>().map(Some).map_err(serde::de::Error::custom),
+        None => Ok(None),
+    }
+}
+
+fn validate_username(username: &str) -> Result<(), ValidationError> {
+    if username
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+    {
+        Ok(())
+    } else {
+        let mut error = ValidationError::new("username");
+        error.message = Some("may only contain letters, numbers, '_', '-' and '.'".into());
+        Err(error)
+    }
+}
+
+fn validate_post_status(status: &str) -> Result<(), ValidationError> {
+    match PostStatus::parse(status) {
+        Some(_) => Ok(()),
+        None => {
+            let mut error = ValidationError::new("status");
+            error.message = Some("must be draft or published".into());
+            Err(error)
+        }
+    }
+}
+
+fn validate_visibility(visibility: &str) -> Result<(), ValidationError> {
+    match MediaVisibility::parse(visibility) {
+        Some(_) => Ok(()),
+        None => {
+            let mut error = ValidationError::new("visibility");
+            error.message = Some("must be public or private".into());
+            Err(error)
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Validate, Default, Clone, Debug)]
+#[serde(default)]
+pub struct RegisterRequest {
+    #[validate(
+        length(min = 3, max = 32, message = "must be between 3 and 32 characters"),
+        custom = "validate_username"
+    )]
+    pub username: String,
+    #[validate(length(min = 1, message = "is required"))]
+    pub password: String,
+    #[validate(email(message = "must be a valid email address"))]
+    pub email: String,
+    #[validate(length(max = 64, message = "must be at most 64 characters"))]
+    pub first_name: Option<String>,
+    #[validate(length(max = 64, message = "must be at most 64 characters"))]
+    pub last_name: Option<String>,
+    #[validate(length(max = 256, message = "must be at most 256 characters"))]
+    pub address: Option<String>,
+    #[validate(length(max = 32, message = "must be at most 32 characters"))]
+    pub phone_number: Option<String>,
+}
+
+/// Fields left out are kept, empty ones are cleared.
+#[derive(Serialize, Deserialize, Validate, Default, Clone, Debug)]
+#[serde(default)]
+pub struct UpdateProfileRequest {
+    #[validate(length(max = 64, message = "must be at most 64 characters"))]
+    pub first_name: Option<String>,
+    #[validate(length(max = 64, message = "must be at most 64 characters"))]
+    pub last_name: Option<String>,
+    #[validate(length(max = 256, message = "must be at most 256 characters"))]
+    pub address: Option<String>,
+    #[validate(length(max = 32, message = "must be at most 32 characters"))]
+    pub phone_number: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Validate, Default, Clone, Debug)]
+#[serde(default)]
+pub struct ChangePasswordRequest {
+    #[validate(length(min = 1, message = "is required"))]
+    pub current_password: String,
+    #[validate(length(min = 1, message = "is required"))]
+    pub new_password: String,
+}
+
+#[derive(Serialize, Deserialize, Validate, Default, Clone, Debug)]
+#[serde(default)]
+pub struct ChangeEmailRequest {
+    #[validate(email(message = "must be a valid email address"))]
+    pub email: String,
+}
+
+#[derive(Serialize, Deserialize, Validate, Default, Clone, Debug)]
+#[serde(default)]
+pub struct ConfirmEmailRequest {
+    #[validate(length(min = 1, message = "is required"))]
+    pub verify_token: String,
+    #[validate(length(min = 1, message = "is required"))]
+    pub verify_code: String,
+}
+
+#[derive(Serialize, Deserialize, Validate, Default, Clone, Debug)]
+#[serde(default)]
+pub struct LoginRequest {
+    #[validate(length(min = 1, message = "is required"))]
+    pub username: String,
+    #[validate(length(min = 1, message = "is required"))]
+    pub password: String,
+    /// A name for the device the session is opened on, shown when listing sessions.
+    #[validate(length(max = 64, message = "must be at most 64 characters"))]
+    pub device: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Validate, Default, Clone, Debug)]
+#[serde(default)]
+pub struct VerifyRequest {
+    #[validate(length(min = 1, message = "is required"))]
+    pub username: String,
+    #[validate(length(min = 1, message = "is required"))]
+    pub verify_token: String,
+    #[validate(length(min = 1, message = "is required"))]
+    pub verify_code: String,
+}
+
+#[derive(Serialize, Deserialize, Validate, Default, Clone, Debug)]
+#[serde(default)]
+pub struct ResendVerificationRequest {
+    #[validate(length(min = 1, message = "is required"))]
+    pub username: String,
+}
+
+#[derive(Serialize, Deserialize, Validate, Default, Clone, Debug)]
+#[serde(default)]
+pub struct PasswordResetRequest {
+    #[validate(email(message = "must be a valid email address"))]
+    pub email: String,
+}
+
+#[derive(Serialize, Deserialize, Validate, Default, Clone, Debug)]
+#[serde(default)]
+pub struct ConfirmResetRequest {
+    #[validate(length(min = 1, message = "is required"))]
+    pub username: String,
+    #[validate(length(min = 1, message = "is required"))]
+    pub reset_token: String,
+    #[validate(length(min = 1, message = "is required"))]
+    pub reset_code: String,
+    #[validate(length(min = 1, message = "is required"))]
+    pub password: String,
+}
+
+#[derive(Serialize, Deserialize, Validate, Default, Clone, Debug)]
+#[serde(default)]
+pub struct MagicLinkRequest {
+    #[validate(email(message = "must be a valid email address"))]
+    pub email: String,
+}
+
+#[derive(Serialize, Deserialize, Validate, Default, Clone, Debug)]
+#[serde(default)]
+pub struct MagicLoginRequest {
+    #[validate(length(min = 1, message = "is required"))]
+    pub username: String,
+    #[validate(length(min = 1, message = "is required"))]
+    pub login_token: String,
+    #[validate(length(min = 1, message = "is required"))]
+    pub login_code: String,
+    #[validate(length(max = 64, message = "must be at most 64 characters"))]
+    pub device: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Validate, Default, Clone, Debug)]
+#[serde(default)]
+pub struct TwoFactorLoginRequest {
+    #[validate(length(min = 1, message = "is required"))]
+    pub challenge_token: String,
+    /// A TOTP code or one of the recovery codes
+    #[validate(length(min = 1, max = 32, message = "must be between 1 and 32 characters"))]
+    pub code: String,
+}
+
+#[derive(Serialize, Deserialize, Validate, Default, Clone, Debug)]
+#[serde(default)]
+pub struct TwoFactorCodeRequest {
+    #[validate(length(min = 1, message = "is required"))]
+    pub code: String,
+}
+
+#[derive(Serialize, Deserialize, Validate, Default, Clone, Debug)]
+#[serde(default)]
+pub struct PasswordConfirmRequest {
+    #[validate(length(min = 1, message = "is required"))]
+    pub password: String,
+}
+
+/// Where a sign in provider sends the user back to.
+#[derive(Serialize, Deserialize, Validate, Default, Clone, Debug)]
+#[serde(default)]
+pub struct SocialCallbackQuery {
+    pub code: String,
+    #[validate(length(min = 1, message = "is required"))]
+    pub state: String,
+    /// Set instead of `code` when the user didn't go through with it
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Validate, Default, Clone, Debug)]
+#[serde(default)]
+pub struct ExchangeRequest {
+    #[validate(length(min = 1, message = "is required"))]
+    pub refresh_token: String,
+}
+
+/// The credentials every authenticated action of the action api carries in its data.
+#[derive(Serialize, Deserialize, Validate, Default, Clone, Debug)]
+#[serde(default)]
+pub struct Credentials {
+    #[validate(length(min = 1, message = "is required"))]
+    pub username: String,
+    #[validate(length(min = 1, message = "is required"))]
+    pub access_token: String,
+}
+
+#[derive(Serialize, Deserialize, Validate, Default, Clone, Debug)]
+#[serde(default)]
+pub struct IdRequest {
+    #[validate(length(min = 1, message = "is required"))]
+    pub id: String,
+}
+
+#[derive(Serialize, Deserialize, Validate, Default, Clone, Debug)]
+#[serde(default)]
+pub struct SetAccessLevelRequest {
+    #[validate(length(min = 1, message = "is required"))]
+    pub target: String,
+    #[validate(length(min = 1, message = "is required"))]
+    pub access_level: String,
+}
+
+#[derive(Serialize, Deserialize, Validate, Default, Clone, Debug)]
+#[serde(default)]
+pub struct UnlockUserRequest {
+    #[validate(length(min = 1, message = "is required"))]
+    pub target: String,
+}
+
+#[derive(Serialize, Deserialize, Validate, Default, Clone, Debug)]
+#[serde(default)]
+pub struct CreatePostRequest {
+    #[validate(length(min = 1, max = 200, message = "must be between 1 and 200 characters"))]
+    pub title: String,
+    #[validate(length(min = 1, message = "is required"))]
+    pub body: String,
+    #[validate(length(min = 1, max = 200, message = "must be between 1 and 200 characters"))]
+    pub slug: Option<String>,
+    #[validate(custom = "validate_post_status")]
+    pub status: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Validate, Default, Clone, Debug)]
+#[serde(default)]
+pub struct UpdatePostRequest {
+    #[validate(length(min = 1, max = 200, message = "must be between 1 and 200 characters"))]
+    pub title: Option<String>,
+    #[validate(length(min = 1, message = "can't be empty"))]
+    pub body: Option<String>,
+    #[validate(length(min = 1, max = 200, message = "must be between 1 and 200 characters"))]
+    pub slug: Option<String>,
+    #[validate(custom = "validate_post_status")]
+    pub status: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Validate, Default, Clone, Debug)]
+#[serde(default)]
+pub struct PostLookup {
+    pub id: Option<String>,
+    pub slug: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Validate, Default, Clone, Debug)]
+#[serde(default)]
+pub struct ListPostsQuery {
+    pub author: Option<String>,
+    #[validate(custom = "validate_post_status")]
+    pub status: Option<String>,
+    #[serde(deserialize_with = "lenient_number")]
+    #[validate(range(min = 1, max = 100, message = "must be between 1 and 100"))]
+    pub limit: Option<i64>,
+    #[serde(deserialize_with = "lenient_number")]
+    #[validate(range(min = 0, message = "can't be negative"))]
+    pub skip: Option<i64>,
+    pub mine: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Validate, Default, Clone, Debug)]
+#[serde(default)]
+pub struct UploadFields {
+    #[validate(custom = "validate_visibility")]
+    pub visibility: Option<String>,
+}
@@ -0,0 +1,1041 @@
+use super::super::database::DatabaseController;
+use super::super::database_errors::{DatabaseError, InvalidCredentialsError, NotFoundError};
+use super::super::database_structures::{
+    AccessRecord, LoginOutcome, Principal, Profile, SessionClient, SessionInfo, User,
+};
+use super::auth;
+use super::errors;
+use super::requests::{
+    self, ChangeEmailRequest, ChangePasswordRequest, ConfirmEmailRequest, ConfirmResetRequest, CreatePostRequest, ExchangeRequest, ListPostsQuery, LoginRequest, MagicLinkRequest,
+    MagicLoginRequest, PasswordConfirmRequest, PasswordResetRequest, PostLookup, RegisterRequest, ResendVerificationRequest,
+    SetAccessLevelRequest, SocialCallbackQuery, TwoFactorCodeRequest, TwoFactorLoginRequest, UpdatePostRequest, UpdateProfileRequest,
+    VerifyRequest,
+};
+use super::v1::{APIResponse, API};
+use bson::doc;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::sync::Arc;
+use warp::filters::BoxedFilter;
+use warp::http::StatusCode;
+use warp::reply::{Json, WithStatus};
+use warp::Filter;
+
+type Route = BoxedFilter<(WithStatus<Json>,)>;
+
+fn respond<T: Serialize>(status: StatusCode, message: Option<String>, data: Option<T>) -> WithStatus<Json> {
+    let outcome = if status.is_success() { "success" } else { "fail" };
+    warp::reply::with_status(
+        warp::reply::json(&APIResponse {
+            status: outcome.to_string(),
+            message,
+            data,
+        }),
+        status,
+    )
+}
+
+fn with_api(api: API) -> impl Filter<Extract = (API,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || api.clone())
+}
+
+fn json_body<T: DeserializeOwned + Send>() -> impl Filter<Extract = (T,), Error = warp::Rejection> + Clone {
+    warp::body::content_length_limit(16 * 1024).and(warp::body::json())
+}
+
+fn register(api: API) -> Route {
+    warp::post()
+        .and(warp::path!("api" / "v1" / "users"))
+        .and(json_body())
+        .and(with_api(api))
+        .and_then(|body: RegisterRequest, api: API| {
+            errors::rejected(async move {
+                let request = requests::validated(body)?;
+                let emailer = api.mailer.lock().unwrap().clone();
+                let config = Arc::clone(&api.config);
+                let user = api
+                    .server
+                    .run(move |server| {
+                        DatabaseController::register_user(
+                            server,
+                            config.roles.default.clone(),
+                            &request,
+                            &emailer,
+                            &config,
+                        )
+                    })
+                    .await?;
+                match user {
+                    Some(user) => Ok(respond(
+                        StatusCode::CREATED,
+                        None,
+                        Some(doc! {
+                            "user_id": user.id,
+                            "verify_token": user.verify.unwrap().verify_token
+                        }),
+                    )),
+                    None => Ok(respond::<()>(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        Some("The verification email could not be sent".to_string()),
+                        None,
+                    )),
+                }
+            })
+        })
+        .boxed()
+}
+
+async fn verify_user(body: VerifyRequest, api: API) -> Result<WithStatus<Json>, warp::Rejection> {
+    errors::rejected(async move {
+        let request = requests::validated(body)?;
+        let verify_token = request.verify_token.clone();
+        let user = api
+            .server
+            .run(move |server| {
+                DatabaseController::verify_user(
+                    server,
+                    request.username,
+                    request.verify_token,
+                    request.verify_code,
+                )
+            })
+            .await?;
+        let verify = user.verify.unwrap();
+        Ok(respond(
+            StatusCode::OK,
+            Some(format!("{} is now verified", user.username)),
+            Some(doc! {
+                "verify_token": verify_token,
+                "verify_time": verify.verify_time.unwrap()
+            }),
+        ))
+    })
+    .await
+}
+
+fn verify(api: API) -> Route {
+    warp::post()
+        .and(warp::path!("api" / "v1" / "users" / "verify"))
+        .and(json_body())
+        .and(with_api(api))
+        .and_then(verify_user)
+        .boxed()
+}
+
+/// The link sent in verification emails, carrying everything `verify` would get in its body.
+fn verify_link(api: API) -> Route {
+    warp::get()
+        .and(warp::path!("api" / "v1" / "users" / "verify"))
+        .and(warp::query::<VerifyRequest>())
+        .and(with_api(api))
+        .and_then(verify_user)
+        .boxed()
+}
+
+fn resend_verification(api: API) -> Route {
+    warp::post()
+        .and(warp::path!("api" / "v1" / "users" / "verify" / "resend"))
+        .and(json_body())
+        .and(with_api(api))
+        .and_then(|body: ResendVerificationRequest, api: API| {
+            errors::rejected(async move {
+                let request = requests::validated(body)?;
+                let emailer = api.mailer.lock().unwrap().clone();
+                let config = Arc::clone(&api.config);
+                let user = api
+                    .server
+                    .run(move |server| DatabaseController::resend_verification(server, &request, &emailer, &config))
+                    .await?;
+                match user {
+                    Some(user) => Ok(respond(
+                        StatusCode::OK,
+                        Some("A new verification code has been sent".to_string()),
+                        Some(doc! {"verify_token": user.verify.unwrap().verify_token}),
+                    )),
+                    None => Ok(respond::<()>(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        Some("The verification email could not be sent".to_string()),
+                        None,
+                    )),
+                }
+            })
+        })
+        .boxed()
+}
+
+fn request_reset(api: API) -> Route {
+    warp::post()
+        .and(warp::path!("api" / "v1" / "password_resets"))
+        .and(json_body())
+        .and(with_api(api))
+        .and_then(|body: PasswordResetRequest, api: API| {
+            errors::rejected(async move {
+                let request = requests::validated(body)?;
+                let emailer = api.mailer.lock().unwrap().clone();
+                let config = Arc::clone(&api.config);
+                let reset = api
+                    .server
+                    .run(move |server| {
+                        DatabaseController::request_password_reset(server, &request, &emailer, &config)
+                    })
+                    .await?;
+                match reset {
+                    Some(reset) => Ok(respond(
+                        StatusCode::CREATED,
+                        Some("If an account uses that address, a reset code has been sent to it".to_string()),
+                        Some(doc! {"reset_token": reset.reset_token}),
+                    )),
+                    None => Ok(respond::<()>(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        Some("The reset email could not be sent".to_string()),
+                        None,
+                    )),
+                }
+            })
+        })
+        .boxed()
+}
+
+fn confirm_reset(api: API) -> Route {
+    warp::post()
+        .and(warp::path!("api" / "v1" / "password_resets" / "confirm"))
+        .and(json_body())
+        .and(with_api(api))
+        .and_then(|body: ConfirmResetRequest, api: API| {
+            errors::rejected(async move {
+                let request = requests::validated(body)?;
+                let user = api
+                    .server
+                    .run(move |server| DatabaseController::confirm_password_reset(server, &request))
+                    .await?;
+                Ok(respond::<()>(
+                    StatusCode::OK,
+                    Some(format!("The password for {} has been reset", user.username)),
+                    None,
+                ))
+            })
+        })
+        .boxed()
+}
+
+fn me(api: API) -> Route {
+    warp::get()
+        .and(warp::path!("api" / "v1" / "users" / "me"))
+        .and(auth::account(api.server))
+        .map(|user: User| respond(StatusCode::OK, None, Some(Profile::from(&user))))
+        .boxed()
+}
+
+fn update_profile(api: API) -> Route {
+    warp::patch()
+        .and(warp::path!("api" / "v1" / "users" / "me"))
+        .and(auth::account(api.server.clone()))
+        .and(json_body())
+        .and(with_api(api))
+        .and_then(|user: User, body: UpdateProfileRequest, api: API| {
+            errors::rejected(async move {
+                let request = requests::validated(body)?;
+                let user = api
+                    .server
+                    .run(move |server| DatabaseController::update_profile(server, &user, &request))
+                    .await?;
+                Ok(respond(StatusCode::OK, None, Some(Profile::from(&user))))
+            })
+        })
+        .boxed()
+}
+
+fn change_password(api: API) -> Route {
+    warp::put()
+        .and(warp::path!("api" / "v1" / "users" / "me" / "password"))
+        .and(auth::session(api.server.clone()))
+        .and(json_body())
+        .and(auth::client(&api.config.server.trusted_proxies))
+        .and(with_api(api))
+        .and_then(|user: User, session: AccessRecord, body: ChangePasswordRequest, client: SessionClient, api: API| {
+            errors::rejected(async move {
+                let request = requests::validated(body)?;
+                api.server
+                    .run(move |server| {
+                        DatabaseController::change_password(server, &user, &session.id, &request, &client)
+                    })
+                    .await?;
+                Ok(respond::<()>(
+                    StatusCode::OK,
+                    Some("The password has been changed, other sessions were logged out".to_string()),
+                    None,
+                ))
+            })
+        })
+        .boxed()
+}
+
+fn change_email(api: API) -> Route {
+    warp::post()
+        .and(warp::path!("api" / "v1" / "users" / "me" / "email"))
+        .and(auth::account(api.server.clone()))
+        .and(json_body())
+        .and(with_api(api))
+        .and_then(|user: User, body: ChangeEmailRequest, api: API| {
+            errors::rejected(async move {
+                let request = requests::validated(body)?;
+                let emailer = api.mailer.lock().unwrap().clone();
+                let config = Arc::clone(&api.config);
+                let verified = api
+                    .server
+                    .run(move |server| DatabaseController::change_email(server, &user, &request, &emailer, &config))
+                    .await?;
+                match verified {
+                    Some(verified) => Ok(respond(
+                        StatusCode::CREATED,
+                        Some("A confirmation code has been sent to the new address".to_string()),
+                        Some(doc! {"verify_token": verified.verify_token}),
+                    )),
+                    None => Ok(respond::<()>(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        Some("The confirmation email could not be sent".to_string()),
+                        None,
+                    )),
+                }
+            })
+        })
+        .boxed()
+}
+
+fn confirm_email(api: API) -> Route {
+    warp::post()
+        .and(warp::path!("api" / "v1" / "users" / "me" / "email" / "confirm"))
+        .and(auth::account(api.server.clone()))
+        .and(json_body())
+        .and(with_api(api))
+        .and_then(|user: User, body: ConfirmEmailRequest, api: API| {
+            errors::rejected(async move {
+                let request = requests::validated(body)?;
+                let user = api
+                    .server
+                    .run(move |server| DatabaseController::confirm_email_change(server, &user, &request))
+                    .await?;
+                Ok(respond(
+                    StatusCode::OK,
+                    Some(format!("{} is now your email address", user.email)),
+                    Some(Profile::from(&user)),
+                ))
+            })
+        })
+        .boxed()
+}
+
+fn delete_account(api: API) -> Route {
+    warp::delete()
+        .and(warp::path!("api" / "v1" / "users" / "me"))
+        .and(auth::account(api.server.clone()))
+        .and(json_body())
+        .and(auth::client(&api.config.server.trusted_proxies))
+        .and(with_api(api))
+        .and_then(|user: User, body: PasswordConfirmRequest, client: SessionClient, api: API| {
+            errors::rejected(async move {
+                let request = requests::validated(body)?;
+                let config = Arc::clone(&api.config);
+                let user = api
+                    .server
+                    .run(move |server| {
+                        DatabaseController::request_account_deletion(server, &user, &request.password, &client, &config)
+                    })
+                    .await?;
+                Ok(respond(
+                    StatusCode::ACCEPTED,
+                    Some(format!(
+                        "{} will be deleted, log in again before then to keep the account",
+                        user.username
+                    )),
+                    Some(doc! {"deletion_scheduled": user.deletion_scheduled.unwrap()}),
+                ))
+            })
+        })
+        .boxed()
+}
+
+fn export_data(api: API) -> Route {
+    warp::get()
+        .and(warp::path!("api" / "v1" / "users" / "me" / "export"))
+        .and(auth::account(api.server.clone()))
+        .and(with_api(api))
+        .and_then(|user: User, api: API| {
+            errors::rejected(async move {
+                let export = api
+                    .server
+                    .run(move |server| DatabaseController::export_user_data(server, &user))
+                    .await?;
+                Ok(respond(StatusCode::OK, None, Some(export)))
+            })
+        })
+        .boxed()
+}
+
+fn set_access_level(api: API) -> Route {
+    warp::put()
+        .and(warp::path!("api" / "v1" / "users" / String / "access_level"))
+        .and(auth::authenticated(api.server.clone()))
+        .and(json_body())
+        .and(with_api(api))
+        .and_then(|target: String, admin: Principal, mut body: SetAccessLevelRequest, api: API| {
+            errors::rejected(async move {
+                // The path names the user, whatever the body says
+                body.target = target;
+                let request = requests::validated(body)?;
+                let config = Arc::clone(&api.config);
+                let user = api
+                    .server
+                    .run(move |server| {
+                        DatabaseController::set_access_level(
+                            server,
+                            &admin,
+                            &request.target,
+                            &request.access_level,
+                            &config,
+                        )
+                    })
+                    .await?;
+                Ok(respond(
+                    StatusCode::OK,
+                    Some(format!("{} is now {}", user.username, user.access_level)),
+                    Some(Profile::from(&user)),
+                ))
+            })
+        })
+        .boxed()
+}
+
+fn unlock_user(api: API) -> Route {
+    warp::delete()
+        .and(warp::path!("api" / "v1" / "users" / String / "lockout"))
+        .and(auth::authenticated(api.server.clone()))
+        .and(with_api(api))
+        .and_then(|target: String, admin: Principal, api: API| {
+            errors::rejected(async move {
+                let config = Arc::clone(&api.config);
+                let user = api
+                    .server
+                    .run(move |server| DatabaseController::unlock_user(server, &admin, &target, &config))
+                    .await?;
+                Ok(respond(
+                    StatusCode::OK,
+                    Some(format!("{} can log in again", user.username)),
+                    Some(Profile::from(&user)),
+                ))
+            })
+        })
+        .boxed()
+}
+
+fn login(api: API) -> Route {
+    warp::post()
+        .and(warp::path!("api" / "v1" / "sessions"))
+        .and(json_body())
+        .and(auth::client(&api.config.server.trusted_proxies))
+        .and(with_api(api))
+        .and_then(|body: LoginRequest, mut client: SessionClient, api: API| {
+            errors::rejected(async move {
+                let request = requests::validated(body)?;
+                client.device = request.device.clone();
+                let outcome = api
+                    .server
+                    .run(move |server| DatabaseController::login_user(server, &request, &client))
+                    .await?;
+                Ok(login_response(outcome))
+            })
+        })
+        .boxed()
+}
+
+fn request_magic_link(api: API) -> Route {
+    warp::post()
+        .and(warp::path!("api" / "v1" / "sessions" / "magic_link"))
+        .and(json_body())
+        .and(with_api(api))
+        .and_then(|body: MagicLinkRequest, api: API| {
+            errors::rejected(async move {
+                let request = requests::validated(body)?;
+                let emailer = api.mailer.lock().unwrap().clone();
+                let config = Arc::clone(&api.config);
+                let magic_link = api
+                    .server
+                    .run(move |server| DatabaseController::request_magic_link(server, &request, &emailer, &config))
+                    .await?;
+                match magic_link {
+                    Some(magic_link) => Ok(respond(
+                        StatusCode::CREATED,
+                        Some("If an account uses that address, a login link has been sent to it".to_string()),
+                        Some(doc! {"login_token": magic_link.login_token}),
+                    )),
+                    None => Ok(respond::<()>(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        Some("The login email could not be sent".to_string()),
+                        None,
+                    )),
+                }
+            })
+        })
+        .boxed()
+}
+
+fn confirm_magic_link(api: API) -> Route {
+    warp::post()
+        .and(warp::path!("api" / "v1" / "sessions" / "magic_link" / "confirm"))
+        .and(json_body())
+        .and(auth::client(&api.config.server.trusted_proxies))
+        .and(with_api(api))
+        .and_then(|body: MagicLoginRequest, mut client: SessionClient, api: API| {
+            errors::rejected(async move {
+                let request = requests::validated(body)?;
+                client.device = request.device.clone();
+                let outcome = api
+                    .server
+                    .run(move |server| DatabaseController::login_with_magic_link(server, &request, &client))
+                    .await?;
+                Ok(login_response(outcome))
+            })
+        })
+        .boxed()
+}
+
+/// The link sent in login emails, carrying everything `confirm_magic_link` would get in its body.
+/// Opening it only asks for that confirmation, so mail scanners that follow links can't use it up.
+fn magic_link() -> Route {
+    warp::get()
+        .and(warp::path!("api" / "v1" / "sessions" / "magic_link" / "confirm"))
+        .and(warp::query::<MagicLoginRequest>())
+        .and_then(|query: MagicLoginRequest| {
+            errors::rejected(async move {
+                let request = requests::validated(query)?;
+                Ok(respond(
+                    StatusCode::OK,
+                    Some("Confirm this login by sending these details back with a POST".to_string()),
+                    Some(doc! {
+                        "username": request.username,
+                        "login_token": request.login_token,
+                        "login_code": request.login_code,
+                    }),
+                ))
+            })
+        })
+        .boxed()
+}
+
+fn login_response(outcome: LoginOutcome) -> WithStatus<Json> {
+    match outcome {
+        LoginOutcome::Session(record) => respond(
+            StatusCode::CREATED,
+            None,
+            Some(doc! {
+                "session_id": record.id,
+                "access_token": record.access_token,
+                "refresh_token": record.refresh_token.unwrap(),
+                "expires": record.expires
+            }),
+        ),
+        // Not logged in yet, the code goes to /api/v1/sessions/two_factor
+        LoginOutcome::TwoFactorRequired(challenge) => respond(
+            StatusCode::ACCEPTED,
+            Some("A two factor code is required".to_string()),
+            Some(doc! {
+                "two_factor_required": true,
+                "challenge_token": challenge.token,
+                "expires": challenge.expires
+            }),
+        ),
+    }
+}
+
+/// Hands back the provider page to send the user to, the client does the redirect.
+fn social_start(api: API) -> Route {
+    warp::get()
+        .and(warp::path!("auth" / String / "start"))
+        .and(with_api(api))
+        .and_then(|provider: String, api: API| {
+            errors::rejected(async move {
+                let config = Arc::clone(&api.config);
+                let url = api
+                    .server
+                    .run(move |server| DatabaseController::start_social_login(server, &config, &provider))
+                    .await?;
+                Ok(respond(StatusCode::OK, None, Some(doc! {"authorization_url": url})))
+            })
+        })
+        .boxed()
+}
+
+fn social_callback(api: API) -> Route {
+    warp::get()
+        .and(warp::path!("auth" / String / "callback"))
+        .and(warp::query::<SocialCallbackQuery>())
+        .and(auth::client(&api.config.server.trusted_proxies))
+        .and(with_api(api))
+        .and_then(|provider: String, query: SocialCallbackQuery, client: SessionClient, api: API| {
+            errors::rejected(async move {
+                let query = requests::validated(query)?;
+                let config = Arc::clone(&api.config);
+                // The code exchange and profile fetch block on the provider
+                let outcome = api
+                    .server
+                    .run(move |server| {
+                        DatabaseController::finish_social_login(server, &config, &provider, &query, &client)
+                    })
+                    .await?;
+                Ok(login_response(outcome))
+            })
+        })
+        .boxed()
+}
+
+fn complete_two_factor(api: API) -> Route {
+    warp::post()
+        .and(warp::path!("api" / "v1" / "sessions" / "two_factor"))
+        .and(json_body())
+        .and(with_api(api))
+        .and_then(|body: TwoFactorLoginRequest, api: API| {
+            errors::rejected(async move {
+                let request = requests::validated(body)?;
+                let record = api
+                    .server
+                    .run(move |server| DatabaseController::complete_two_factor(server, &request))
+                    .await?;
+                Ok(respond(
+                    StatusCode::CREATED,
+                    None,
+                    Some(doc! {
+                        "session_id": record.id,
+                        "access_token": record.access_token,
+                        "refresh_token": record.refresh_token.unwrap(),
+                        "expires": record.expires
+                    }),
+                ))
+            })
+        })
+        .boxed()
+}
+
+fn begin_two_factor(api: API) -> Route {
+    warp::post()
+        .and(warp::path!("api" / "v1" / "users" / "me" / "two_factor"))
+        .and(auth::account(api.server.clone()))
+        .and(with_api(api))
+        .and_then(|user: User, api: API| {
+            errors::rejected(async move {
+                let enrollment = api
+                    .server
+                    .run(move |server| DatabaseController::begin_two_factor(server, &user))
+                    .await?;
+                Ok(respond(
+                    StatusCode::CREATED,
+                    Some("Confirm with a code from your authenticator app".to_string()),
+                    Some(enrollment),
+                ))
+            })
+        })
+        .boxed()
+}
+
+fn confirm_two_factor(api: API) -> Route {
+    warp::post()
+        .and(warp::path!("api" / "v1" / "users" / "me" / "two_factor" / "confirm"))
+        .and(auth::account(api.server.clone()))
+        .and(json_body())
+        .and(with_api(api))
+        .and_then(|user: User, body: TwoFactorCodeRequest, api: API| {
+            errors::rejected(async move {
+                let request = requests::validated(body)?;
+                let user = api
+                    .server
+                    .run(move |server| DatabaseController::confirm_two_factor(server, &user, &request.code))
+                    .await?;
+                Ok(respond(
+                    StatusCode::OK,
+                    Some("Two factor authentication is enabled".to_string()),
+                    Some(Profile::from(&user)),
+                ))
+            })
+        })
+        .boxed()
+}
+
+fn disable_two_factor(api: API) -> Route {
+    warp::delete()
+        .and(warp::path!("api" / "v1" / "users" / "me" / "two_factor"))
+        .and(auth::account(api.server.clone()))
+        .and(json_body())
+        .and(auth::client(&api.config.server.trusted_proxies))
+        .and(with_api(api))
+        .and_then(|user: User, body: PasswordConfirmRequest, client: SessionClient, api: API| {
+            errors::rejected(async move {
+                let request = requests::validated(body)?;
+                let user = api
+                    .server
+                    .run(move |server| {
+                        DatabaseController::disable_two_factor(server, &user, &request.password, &client)
+                    })
+                    .await?;
+                Ok(respond(
+                    StatusCode::OK,
+                    Some("Two factor authentication is disabled".to_string()),
+                    Some(Profile::from(&user)),
+                ))
+            })
+        })
+        .boxed()
+}
+
+fn refresh(api: API) -> Route {
+    warp::post()
+        .and(warp::path!("api" / "v1" / "sessions" / "refresh"))
+        .and(json_body())
+        .and(auth::client(&api.config.server.trusted_proxies))
+        .and(with_api(api))
+        .and_then(|body: ExchangeRequest, client: SessionClient, api: API| {
+            errors::rejected(async move {
+                let request = requests::validated(body)?;
+                let record = api
+                    .server
+                    .run(move |server| {
+                        DatabaseController::exchange_refresh_token(server, &request.refresh_token, &client)
+                    })
+                    .await?;
+                match record {
+                    Some(record) => Ok(respond(
+                        StatusCode::OK,
+                        None,
+                        Some(doc! {
+                            "access_token": record.access_token,
+                            "refresh_token": record.refresh_token.unwrap(),
+                            "expires": record.expires
+                        }),
+                    )),
+                    None => Err(DatabaseError::NotFoundError(NotFoundError::new(
+                        "No access record was created",
+                    ))),
+                }
+            })
+        })
+        .boxed()
+}
+
+fn logout(api: API) -> Route {
+    warp::delete()
+        .and(warp::path!("api" / "v1" / "sessions"))
+        .and(auth::session(api.server.clone()))
+        .and(with_api(api))
+        .and_then(|user: User, session: AccessRecord, api: API| {
+            errors::rejected(async move {
+                let message = format!("{} is logged out", user.username);
+                api.server
+                    .run(move |server| DatabaseController::revoke_session(server, &user, &session.id))
+                    .await?;
+                Ok(respond::<()>(StatusCode::OK, Some(message), None))
+            })
+        })
+        .boxed()
+}
+
+fn list_sessions(api: API) -> Route {
+    warp::get()
+        .and(warp::path!("api" / "v1" / "sessions"))
+        .and(auth::session(api.server.clone()))
+        .and(with_api(api))
+        .and_then(|user: User, session: AccessRecord, api: API| {
+            errors::rejected(async move {
+                let sessions = api
+                    .server
+                    .run(move |server| DatabaseController::list_sessions(server, &user))
+                    .await?;
+                let sessions: Vec<SessionInfo> = sessions
+                    .iter()
+                    .map(|record| SessionInfo {
+                        current: record.id == session.id,
+                        ..SessionInfo::from(record)
+                    })
+                    .collect();
+                Ok(respond(StatusCode::OK, None, Some(sessions)))
+            })
+        })
+        .boxed()
+}
+
+fn revoke_sessions(api: API) -> Route {
+    warp::delete()
+        .and(warp::path!("api" / "v1" / "sessions" / "all"))
+        .and(auth::account(api.server.clone()))
+        .and(with_api(api))
+        .and_then(|user: User, api: API| {
+            errors::rejected(async move {
+                let message = format!("{} is logged out everywhere", user.username);
+                api.server
+                    .run(move |server| DatabaseController::revoke_sessions(server, &user))
+                    .await?;
+                Ok(respond::<()>(StatusCode::OK, Some(message), None))
+            })
+        })
+        .boxed()
+}
+
+fn revoke_session(api: API) -> Route {
+    warp::delete()
+        .and(warp::path!("api" / "v1" / "sessions" / String))
+        .and(auth::account(api.server.clone()))
+        .and(with_api(api))
+        .and_then(|id: String, user: User, api: API| {
+            errors::rejected(async move {
+                let session = api
+                    .server
+                    .run(move |server| DatabaseController::revoke_session(server, &user, &id))
+                    .await?;
+                Ok(respond(
+                    StatusCode::OK,
+                    Some("The session was revoked".to_string()),
+                    Some(doc! {"id": session.id}),
+                ))
+            })
+        })
+        .boxed()
+}
+
+fn list_posts(api: API) -> Route {
+    warp::get()
+        .and(warp::path!("api" / "v1" / "posts"))
+        .and(auth::optional(api.server.clone()))
+        .and(warp::query::<ListPostsQuery>())
+        .and(with_api(api))
+        .and_then(|user: Option<Principal>, query: ListPostsQuery, api: API| {
+            errors::rejected(async move {
+                let query = requests::validated(query)?;
+                // ?mine=true lists the caller's own posts, drafts included
+                let wants_mine = query.mine.as_deref() == Some("true");
+                let mine = if wants_mine { user } else { None };
+                if wants_mine && mine.is_none() {
+                    return Err(DatabaseError::InvalidCredentialsError(InvalidCredentialsError::new(
+                        "Listing your own posts requires a bearer token",
+                    )));
+                }
+                let posts = api
+                    .server
+                    .run(move |server| DatabaseController::list_posts(server, mine.as_ref(), &query))
+                    .await?;
+                Ok(respond(StatusCode::OK, None, Some(posts)))
+            })
+        })
+        .boxed()
+}
+
+fn create_post(api: API) -> Route {
+    warp::post()
+        .and(warp::path!("api" / "v1" / "posts"))
+        .and(auth::authenticated(api.server.clone()))
+        .and(json_body())
+        .and(with_api(api))
+        .and_then(|user: Principal, body: CreatePostRequest, api: API| {
+            errors::rejected(async move {
+                let request = requests::validated(body)?;
+                let config = Arc::clone(&api.config);
+                let post = api
+                    .server
+                    .run(move |server| DatabaseController::create_post(server, &user, &request, &config))
+                    .await?;
+                Ok(respond(StatusCode::CREATED, Some("Post created".to_string()), post))
+            })
+        })
+        .boxed()
+}
+
+fn get_post(api: API) -> Route {
+    warp::get()
+        .and(warp::path!("api" / "v1" / "posts" / String))
+        .and(auth::optional(api.server.clone()))
+        .and(with_api(api))
+        .and_then(|id: String, user: Option<Principal>, api: API| {
+            errors::rejected(async move {
+                let config = Arc::clone(&api.config);
+                let post = api
+                    .server
+                    .run(move |server| {
+                        let by_id = PostLookup {
+                            id: Some(id.clone()),
+                            slug: None,
+                        };
+                        // Posts can be addressed by slug as well as by id
+                        match DatabaseController::get_post(server, user.as_ref(), &by_id, &config) {
+                            Err(DatabaseError::NotFoundError(_)) => {
+                                let by_slug = PostLookup {
+                                    id: None,
+                                    slug: Some(id),
+                                };
+                                DatabaseController::get_post(server, user.as_ref(), &by_slug, &config)
+                            }
+                            result => result,
+                        }
+                    })
+                    .await?;
+                Ok(respond(StatusCode::OK, None, Some(post)))
+            })
+        })
+        .boxed()
+}
+
+fn update_post(api: API) -> Route {
+    warp::patch()
+        .and(warp::path!("api" / "v1" / "posts" / String))
+        .and(auth::authenticated(api.server.clone()))
+        .and(json_body())
+        .and(with_api(api))
+        .and_then(|id: String, user: Principal, body: UpdatePostRequest, api: API| {
+            errors::rejected(async move {
+                let request = requests::validated(body)?;
+                let config = Arc::clone(&api.config);
+                let post = api
+                    .server
+                    .run(move |server| {
+                        DatabaseController::update_post(server, &user, &id, &request, &config)
+                    })
+                    .await?;
+                Ok(respond(StatusCode::OK, Some("Post updated".to_string()), Some(post)))
+            })
+        })
+        .boxed()
+}
+
+fn delete_post(api: API) -> Route {
+    warp::delete()
+        .and(warp::path!("api" / "v1" / "posts" / String))
+        .and(auth::authenticated(api.server.clone()))
+        .and(with_api(api))
+        .and_then(|id: String, user: Principal, api: API| {
+            errors::rejected(async move {
+                let config = Arc::clone(&api.config);
+                let post = api
+                    .server
+                    .run(move |server| DatabaseController::delete_post(server, &user, &id, &config))
+                    .await?;
+                Ok(respond(
+                    StatusCode::OK,
+                    Some(format!("{} was deleted", post.slug)),
+                    Some(doc! {"id": post.id}),
+                ))
+            })
+        })
+        .boxed()
+}
+
+fn list_media(api: API) -> Route {
+    warp::get()
+        .and(warp::path!("api" / "v1" / "media"))
+        .and(auth::authenticated(api.server.clone()))
+        .and(with_api(api))
+        .and_then(|user: Principal, api: API| {
+            errors::rejected(async move {
+                let media = api
+                    .server
+                    .run(move |server| DatabaseController::list_media(server, &user))
+                    .await?;
+                Ok(respond(StatusCode::OK, None, Some(media)))
+            })
+        })
+        .boxed()
+}
+
+fn delete_media(api: API) -> Route {
+    warp::delete()
+        .and(warp::path!("api" / "v1" / "media" / String))
+        .and(auth::authenticated(api.server.clone()))
+        .and(with_api(api))
+        .and_then(|id: String, user: Principal, api: API| {
+            errors::rejected(async move {
+                let config = Arc::clone(&api.config);
+                let media = api
+                    .server
+                    .run(move |server| DatabaseController::delete_media(server, &user, &id, &config))
+                    .await?;
+                Ok(respond(
+                    StatusCode::OK,
+                    Some(format!("{} was deleted", media.original_filename)),
+                    Some(doc! {"id": media.id}),
+                ))
+            })
+        })
+        .boxed()
+}
+
+/// Resource routes under `/api/v1/`. Media uploads are multipart and live in `Uploader`.
+pub fn routes(api: API) -> Route {
+    register(api.clone())
+        .or(verify(api.clone()))
+        .unify()
+        .or(verify_link(api.clone()))
+        .unify()
+        .or(resend_verification(api.clone()))
+        .unify()
+        .or(request_reset(api.clone()))
+        .unify()
+        .or(confirm_reset(api.clone()))
+        .unify()
+        .or(me(api.clone()))
+        .unify()
+        .or(update_profile(api.clone()))
+        .unify()
+        .or(change_password(api.clone()))
+        .unify()
+        .or(change_email(api.clone()))
+        .unify()
+        .or(confirm_email(api.clone()))
+        .unify()
+        .or(delete_account(api.clone()))
+        .unify()
+        .or(export_data(api.clone()))
+        .unify()
+        .or(set_access_level(api.clone()))
+        .unify()
+        .or(unlock_user(api.clone()))
+        .unify()
+        .or(begin_two_factor(api.clone()))
+        .unify()
+        .or(confirm_two_factor(api.clone()))
+        .unify()
+        .or(disable_two_factor(api.clone()))
+        .unify()
+        .or(login(api.clone()))
+        .unify()
+        .or(complete_two_factor(api.clone()))
+        .unify()
+        .or(request_magic_link(api.clone()))
+        .unify()
+        .or(confirm_magic_link(api.clone()))
+        .unify()
+        .or(magic_link())
+        .unify()
+        .or(social_start(api.clone()))
+        .unify()
+        .or(social_callback(api.clone()))
+        .unify()
+        .or(refresh(api.clone()))
+        .unify()
+        .or(logout(api.clone()))
+        .unify()
+        .or(list_sessions(api.clone()))
+        .unify()
+        .or(revoke_sessions(api.clone()))
+        .unify()
+        .or(revoke_session(api.clone()))
+        .unify()
+        .or(list_posts(api.clone()))
+        .unify()
+        .or(create_post(api.clone()))
+        .unify()
+        .or(get_post(api.clone()))
+        .unify()
+        .or(update_post(api.clone()))
+        .unify()
+        .or(delete_post(api.clone()))
+        .unify()
+        .or(list_media(api.clone()))
+        .unify()
+        .or(delete_media(api))
+        .unify()
+        .boxed()
+}
@@ -3,6 +3,17 @@ use super::super::database_errors::DatabaseError;
 use super::super::emailer::Emailer;
 use super::super::server::Server;
 use super::super::configuration::Configuration;
+use super::super::database_structures::{LoginOutcome, Principal, Profile, SessionClient, SessionInfo, User};
+use super::requests::{
+    self, ChangeEmailRequest, ChangePasswordRequest, ConfirmEmailRequest, ConfirmResetRequest, CreatePostRequest,
+    Credentials, ExchangeRequest, IdRequest, ListPostsQuery, LoginRequest, MagicLinkRequest, MagicLoginRequest,
+    PasswordResetRequest, PostLookup, RegisterRequest, PasswordConfirmRequest, ResendVerificationRequest,
+    SetAccessLevelRequest, TwoFactorCodeRequest,
+    TwoFactorLoginRequest, UnlockUserRequest, UpdatePostRequest, UpdateProfileRequest, VerifyRequest,
+};
+use super::auth;
+use super::errors;
+use super::rest;
 use bson::doc;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
@@ -24,7 +35,9 @@ pub struct APIResponse<T> {
 
 #[derive(Clone)]
 pub struct API {
+    pub mailer: Arc<Mutex<Emailer>>,
     pub server: Server,
+    pub config: Arc<Configuration>,
 }
 
 impl API {
@@ -35,13 +48,60 @@ impl API {
         warp::post().and(warp::path!("api" / u8 / String).and(warp::body::json()))
     }
 
-    pub fn setup(mailer: Arc<Mutex<Emailer>>, server: Arc<Mutex<Server>>, config: Arc<Mutex<Configuration>>) -> impl Filter<Extract = (warp::reply::Json,), Error = Rejection> + Clone{
+    /// The `/api/v1/` resource routes followed by the original `POST /api/<version>/<action>`
+    /// dispatcher, which is kept so existing clients keep working.
+    pub fn setup(mailer: Arc<Mutex<Emailer>>, server: Server, config: Arc<Configuration>) -> impl Filter<Extract = (impl warp::Reply,), Error = Rejection> + Clone{
+        let api = API {
+            mailer,
+            server,
+            config,
+        };
         let routes = API::init_routes();
-        routes.map(
-            move |_version: u8, action: String, map: HashMap<String, HashMap<String, String>>| {
-                API::map_actions(_version, &mailer.lock().unwrap(), &server.lock().unwrap(), action, map, &config.lock().unwrap()).unwrap()
+        let legacy = api.clone();
+        let actions = routes.and(auth::client(&api.config.server.trusted_proxies)).and_then(
+            move |_version: u8, action: String, map: HashMap<String, HashMap<String, String>>, client: SessionClient| {
+                let api = legacy.clone();
+                errors::rejected(async move {
+                    // Emailer clones share one thread pool, so the lock is only held for the clone
+                    let emailer = api.mailer.lock().unwrap().clone();
+                    let config = Arc::clone(&api.config);
+                    api.server
+                        .run(move |server| API::map_actions(_version, &emailer, server, action, map, &config, client))
+                        .await
+                })
             }
-        )
+        );
+        rest::routes(api).or(actions)
+    }
+
+    fn authenticate(server: &Server, data: &HashMap<String, String>) -> Result<User, DatabaseError> {
+        let credentials = requests::from_data::<Credentials>(data)?;
+        DatabaseController::authenticate(server, &credentials.username, &credentials.access_token)
+    }
+
+    /// A session, or the challenge to finish with `two_factor_login` when TOTP is enabled.
+    fn login_reply(outcome: LoginOutcome) -> warp::reply::Json {
+        match outcome {
+            LoginOutcome::Session(record) => warp::reply::json(&APIResponse {
+                status: "success".to_string(),
+                message: Some("Success".to_string()),
+                data: Some(doc! {
+                    "session_id": record.id.clone(),
+                    "access_token": record.clone().access_token,
+                    "refresh_token": record.clone().refresh_token.unwrap(),
+                    "expires": record.expires
+                }),
+            }),
+            LoginOutcome::TwoFactorRequired(challenge) => warp::reply::json(&APIResponse {
+                status: "success".to_string(),
+                message: Some("A two factor code is required".to_string()),
+                data: Some(doc! {
+                    "two_factor_required": true,
+                    "challenge_token": challenge.token,
+                    "expires": challenge.expires
+                }),
+            }),
+        }
     }
 
     pub fn map_actions(
@@ -50,133 +110,543 @@ impl API {
         server: &Server,
         action: String,
         map: HashMap<String, HashMap<String, String>>,
-        config: &Configuration
+        config: &Configuration,
+        mut client: SessionClient,
     ) -> Result<warp::reply::Json, DatabaseError> {
-        let data = map.get("data");
+        let data = map.get("data").cloned().unwrap_or_default();
+        let data = &data;
         if action.eq("register") {
-            match DatabaseController::register_user(
-                server,
-                "subscriber".to_string(),
-                data.clone().unwrap(),
-                emailer,
-                config
-            ) {
-                Ok(user) => {
+            match requests::from_data::<RegisterRequest>(data).and_then(|request| {
+                DatabaseController::register_user(
+                    server,
+                    config.roles.default.clone(),
+                    &request,
+                    emailer,
+                    config
+                )
+            }) {
+                Ok(Some(user)) => {
                     Ok(warp::reply::json(&APIResponse {
                         status: "success".to_string(),
                         message: None,
                         data: Some(doc! {
-                            "user_id": user.clone().unwrap().id,
-                            "verify_token": user.clone().unwrap().verify.unwrap().verify_token
+                            "user_id": user.clone().id,
+                            "verify_token": user.verify.unwrap().verify_token
                         }),
                     }))
                 }
-                Err(e) => {
+                Ok(None) => {
                     Ok(warp::reply::json(&APIResponse {
                         status: "fail".to_string(),
                         message: Some("Registration failed!".to_string()),
-                        data: Some(format!("{:?}", e)),
+                        data: Some("The verification email could not be sent"),
                     }))
                 }
+                Err(e) => Err(e),
             }
         } else if action.eq("login") {
-            match DatabaseController::login_user(server, data.clone().unwrap()) {
+            match requests::from_data::<LoginRequest>(data).and_then(|request| {
+                client.device = request.device.clone();
+                DatabaseController::login_user(server, &request, &client)
+            }) {
+                Ok(outcome) => Ok(API::login_reply(outcome)),
+                Err(e) => Err(e),
+            }
+        } else if action.eq("request_magic_link") {
+            match requests::from_data::<MagicLinkRequest>(data).and_then(|request| {
+                DatabaseController::request_magic_link(server, &request, emailer, config)
+            }) {
+                Ok(Some(magic_link)) => {
+                    Ok(warp::reply::json(&APIResponse {
+                        status: "success".to_string(),
+                        message: Some("If an account uses that address, a login link has been sent to it".to_string()),
+                        data: Some(doc! {
+                            "login_token": magic_link.login_token
+                        }),
+                    }))
+                }
+                Ok(None) => {
+                    Ok(warp::reply::json(&APIResponse {
+                        status: "fail".to_string(),
+                        message: Some("Login link failed!".to_string()),
+                        data: Some("The login email could not be sent"),
+                    }))
+                }
+                Err(e) => Err(e),
+            }
+        } else if action.eq("magic_login") {
+            match requests::from_data::<MagicLoginRequest>(data).and_then(|request| {
+                client.device = request.device.clone();
+                DatabaseController::login_with_magic_link(server, &request, &client)
+            }) {
+                Ok(outcome) => Ok(API::login_reply(outcome)),
+                Err(e) => Err(e),
+            }
+        } else if action.eq("two_factor_login") {
+            match requests::from_data::<TwoFactorLoginRequest>(data)
+                .and_then(|request| DatabaseController::complete_two_factor(server, &request))
+            {
                 Ok(record) => {
                     Ok(warp::reply::json(&APIResponse {
                         status: "success".to_string(),
                         message: Some("Success".to_string()),
                         data: Some(doc! {
-                            "access_token": record.clone().unwrap().access_token,
-                            "refresh_token": record.clone().unwrap().refresh_token.unwrap(),
-                            "expires": record.clone().unwrap().expires
+                            "session_id": record.id.clone(),
+                            "access_token": record.clone().access_token,
+                            "refresh_token": record.clone().refresh_token.unwrap(),
+                            "expires": record.expires
                         }),
                     }))
                 }
-                Err(e) => {
+                Err(e) => Err(e),
+            }
+        } else if action.eq("enable_two_factor") {
+            match API::authenticate(server, data).and_then(|user| DatabaseController::begin_two_factor(server, &user)) {
+                Ok(enrollment) => {
                     Ok(warp::reply::json(&APIResponse {
-                        status: "fail".to_string(),
-                        message: Some("Login Failed".to_string()),
-                        data: Some(format!("{:?}", e)),
+                        status: "success".to_string(),
+                        message: Some("Confirm with a code from your authenticator app".to_string()),
+                        data: Some(enrollment),
+                    }))
+                }
+                Err(e) => Err(e),
+            }
+        } else if action.eq("confirm_two_factor") {
+            match API::authenticate(server, data).and_then(|user| {
+                let request = requests::from_data::<TwoFactorCodeRequest>(data)?;
+                DatabaseController::confirm_two_factor(server, &user, &request.code)
+            }) {
+                Ok(user) => {
+                    Ok(warp::reply::json(&APIResponse {
+                        status: "success".to_string(),
+                        message: Some("Two factor authentication is enabled".to_string()),
+                        data: Some(Profile::from(&user)),
+                    }))
+                }
+                Err(e) => Err(e),
+            }
+        } else if action.eq("disable_two_factor") {
+            match API::authenticate(server, data).and_then(|user| {
+                let request = requests::from_data::<PasswordConfirmRequest>(data)?;
+                DatabaseController::disable_two_factor(server, &user, &request.password, &client)
+            }) {
+                Ok(user) => {
+                    Ok(warp::reply::json(&APIResponse {
+                        status: "success".to_string(),
+                        message: Some("Two factor authentication is disabled".to_string()),
+                        data: Some(Profile::from(&user)),
                     }))
                 }
+                Err(e) => Err(e),
             }
         } else if action.eq("exchange") {
-            let username = data.clone().unwrap().get("username").unwrap();
-            let refresh_token = data.clone().unwrap().get("refresh_token").unwrap();
-            let access_token = data.clone().unwrap().get("access_token").unwrap();
-            match DatabaseController::exchange_refresh_token(
-                server,
-                access_token.to_string(),
-                refresh_token.to_string(),
-                username.to_string(),
-            ) {
-                Ok(record) => {
+            match requests::from_data::<ExchangeRequest>(data).and_then(|request| {
+                DatabaseController::exchange_refresh_token(server, &request.refresh_token, &client)
+            }) {
+                Ok(Some(record)) => {
                     Ok(warp::reply::json(&APIResponse {
                         status: "success".to_string(),
                         message: Some("Success".to_string()),
                         data: Some(doc! {
-                            "access_token": record.clone().unwrap().access_token,
-                            "refresh_token": record.clone().unwrap().refresh_token.unwrap(),
-                            "expires": record.clone().unwrap().expires
+                            "access_token": record.clone().access_token,
+                            "refresh_token": record.clone().refresh_token.unwrap(),
+                            "expires": record.expires
                         }),
                     }))
                 }
-                Err(e) => {
+                Ok(None) => {
                     Ok(warp::reply::json(&APIResponse {
                         status: "fail".to_string(),
                         message: Some("Login Failed".to_string()),
-                        data: Some(format!("{:?}", e)),
+                        data: Some("No access record was created"),
                     }))
                 }
+                Err(e) => Err(e),
             }
         } else if action.eq("verify") {
-            let username = data.clone().unwrap().get("username").unwrap();
-            let verify_token = data.clone().unwrap().get("verify_token").unwrap();
-            let verify_code = data.clone().unwrap().get("verify_code").unwrap();
-            match DatabaseController::verify_user(
-                server,
-                username.to_string(),
-                verify_token.to_string(),
-                verify_code.to_string(),
-            ) {
-                Ok(user) => {
-                    let vtoken = user.verify.clone().unwrap().verify_token;
+            match requests::from_data::<VerifyRequest>(data).and_then(|request| {
+                DatabaseController::verify_user(
+                    server,
+                    request.username.clone(),
+                    request.verify_token.clone(),
+                    request.verify_code.clone(),
+                )
+                .map(|user| (user, request.verify_token))
+            }) {
+                Ok((user, vtoken)) => {
                     let vtime = user.verify.clone().unwrap().verify_time.unwrap();
                     Ok(warp::reply::json(&APIResponse {
                         status: "success".to_string(),
-                        message: Some(format!("{} is now verified", username)),
+                        message: Some(format!("{} is now verified", user.username)),
                         data: Some(doc! {
                             "verify_token": vtoken,
                             "verify_time" : vtime
                         }),
                     }))
                 }
-                Err(e) => {
+                Err(e) => Err(e),
+            }
+        } else if action.eq("resend_verify") {
+            match requests::from_data::<ResendVerificationRequest>(data).and_then(|request| {
+                DatabaseController::resend_verification(server, &request, emailer, config)
+            }) {
+                Ok(Some(user)) => {
+                    Ok(warp::reply::json(&APIResponse {
+                        status: "success".to_string(),
+                        message: Some("A new verification code has been sent".to_string()),
+                        data: Some(doc! {
+                            "verify_token": user.verify.unwrap().verify_token
+                        }),
+                    }))
+                }
+                Ok(None) => {
                     Ok(warp::reply::json(&APIResponse {
                         status: "fail".to_string(),
-                        message: Some("Verify Failed".to_string()),
-                        data: Some(format!("{:?}", e)),
+                        message: Some("Resend failed!".to_string()),
+                        data: Some("The verification email could not be sent"),
                     }))
                 }
+                Err(e) => Err(e),
+            }
+        } else if action.eq("request_reset") {
+            match requests::from_data::<PasswordResetRequest>(data).and_then(|request| {
+                DatabaseController::request_password_reset(server, &request, emailer, config)
+            }) {
+                Ok(Some(reset)) => {
+                    Ok(warp::reply::json(&APIResponse {
+                        status: "success".to_string(),
+                        message: Some("If an account uses that address, a reset code has been sent to it".to_string()),
+                        data: Some(doc! {
+                            "reset_token": reset.reset_token
+                        }),
+                    }))
+                }
+                Ok(None) => {
+                    Ok(warp::reply::json(&APIResponse {
+                        status: "fail".to_string(),
+                        message: Some("Password reset failed!".to_string()),
+                        data: Some("The reset email could not be sent"),
+                    }))
+                }
+                Err(e) => Err(e),
+            }
+        } else if action.eq("confirm_reset") {
+            match requests::from_data::<ConfirmResetRequest>(data)
+                .and_then(|request| DatabaseController::confirm_password_reset(server, &request))
+            {
+                Ok(user) => {
+                    Ok(warp::reply::json(&APIResponse {
+                        status: "success".to_string(),
+                        message: Some(format!("The password for {} has been reset", user.username)),
+                        data: Some("Password Reset!"),
+                    }))
+                }
+                Err(e) => Err(e),
             }
         } else if action.eq("logout") {
-            let username = data.clone().unwrap().get("username").unwrap();
-            let access_token = data.clone().unwrap().get("access_token").unwrap();
-            match DatabaseController::logout(server, username.to_string(), access_token.to_string()) {
-                Ok(_res) => {
+            match requests::from_data::<Credentials>(data).and_then(|credentials| {
+                let username = credentials.username;
+                DatabaseController::logout(server, username.clone(), credentials.access_token)
+                    .map(|_res| username)
+            }) {
+                Ok(username) => {
                     Ok(warp::reply::json(&APIResponse {
                         status: "success".to_string(),
                         message: Some(format!("{} is logged out", username)),
                         data: Some("Logged Out!"),
                     }))
                 }
-                Err(e) => {
+                Err(e) => Err(e),
+            }
+        } else if action.eq("list_sessions") {
+            let credentials = requests::from_data::<Credentials>(data)?;
+            match DatabaseController::authenticate_session(server, &credentials.username, &credentials.access_token)
+                .and_then(|(user, session)| {
+                    DatabaseController::list_sessions(server, &user).map(|sessions| (sessions, session))
+                }) {
+                Ok((sessions, current)) => {
+                    let sessions: Vec<SessionInfo> = sessions
+                        .iter()
+                        .map(|record| SessionInfo {
+                            current: record.id == current.id,
+                            ..SessionInfo::from(record)
+                        })
+                        .collect();
+                    Ok(warp::reply::json(&APIResponse {
+                        status: "success".to_string(),
+                        message: None,
+                        data: Some(sessions),
+                    }))
+                }
+                Err(e) => Err(e),
+            }
+        } else if action.eq("revoke_session") {
+            match API::authenticate(server, data).and_then(|user| {
+                let id = requests::from_data::<IdRequest>(data)?.id;
+                DatabaseController::revoke_session(server, &user, &id)
+            }) {
+                Ok(session) => {
+                    Ok(warp::reply::json(&APIResponse {
+                        status: "success".to_string(),
+                        message: Some("The session was revoked".to_string()),
+                        data: Some(doc! {"id": session.id}),
+                    }))
+                }
+                Err(e) => Err(e),
+            }
+        } else if action.eq("revoke_sessions") {
+            match API::authenticate(server, data).and_then(|user| {
+                DatabaseController::revoke_sessions(server, &user).map(|_res| user.username)
+            }) {
+                Ok(username) => {
+                    Ok(warp::reply::json(&APIResponse {
+                        status: "success".to_string(),
+                        message: Some(format!("{} is logged out everywhere", username)),
+                        data: Some("Logged Out!"),
+                    }))
+                }
+                Err(e) => Err(e),
+            }
+        } else if action.eq("create_post") {
+            match API::authenticate(server, data).and_then(|user| {
+                let request = requests::from_data::<CreatePostRequest>(data)?;
+                DatabaseController::create_post(server, &Principal::from(&user), &request, config)
+            }) {
+                Ok(post) => {
+                    Ok(warp::reply::json(&APIResponse {
+                        status: "success".to_string(),
+                        message: Some("Post created".to_string()),
+                        data: post,
+                    }))
+                }
+                Err(e) => Err(e),
+            }
+        } else if action.eq("get_post") {
+            let viewer = API::authenticate(server, data).ok().map(|user| Principal::from(&user));
+            match requests::from_data::<PostLookup>(data).and_then(|lookup| {
+                DatabaseController::get_post(server, viewer.as_ref(), &lookup, config)
+            }) {
+                Ok(post) => {
+                    Ok(warp::reply::json(&APIResponse {
+                        status: "success".to_string(),
+                        message: None,
+                        data: Some(post),
+                    }))
+                }
+                Err(e) => Err(e),
+            }
+        } else if action.eq("update_post") {
+            match API::authenticate(server, data).and_then(|user| {
+                let id = requests::from_data::<IdRequest>(data)?.id;
+                let request = requests::from_data::<UpdatePostRequest>(data)?;
+                DatabaseController::update_post(server, &Principal::from(&user), &id, &request, config)
+            }) {
+                Ok(post) => {
+                    Ok(warp::reply::json(&APIResponse {
+                        status: "success".to_string(),
+                        message: Some("Post updated".to_string()),
+                        data: Some(post),
+                    }))
+                }
+                Err(e) => Err(e),
+            }
+        } else if action.eq("delete_post") {
+            match API::authenticate(server, data).and_then(|user| {
+                let id = requests::from_data::<IdRequest>(data)?.id;
+                DatabaseController::delete_post(server, &Principal::from(&user), &id, config)
+            }) {
+                Ok(post) => {
+                    Ok(warp::reply::json(&APIResponse {
+                        status: "success".to_string(),
+                        message: Some(format!("{} was deleted", post.slug)),
+                        data: Some(doc! {"id": post.id}),
+                    }))
+                }
+                Err(e) => Err(e),
+            }
+        } else if action.eq("list_posts") {
+            // Sending credentials lists your own posts, drafts included
+            let listing = requests::from_data::<ListPostsQuery>(data).and_then(|query| {
+                if data.contains_key("access_token") {
+                    let user = API::authenticate(server, data)?;
+                    DatabaseController::list_posts(server, Some(&Principal::from(&user)), &query)
+                } else {
+                    DatabaseController::list_posts(server, None, &query)
+                }
+            });
+            match listing {
+                Ok(posts) => {
+                    Ok(warp::reply::json(&APIResponse {
+                        status: "success".to_string(),
+                        message: None,
+                        data: Some(posts),
+                    }))
+                }
+                Err(e) => Err(e),
+            }
+        } else if action.eq("delete_media") {
+            match API::authenticate(server, data).and_then(|user| {
+                let id = requests::from_data::<IdRequest>(data)?.id;
+                DatabaseController::delete_media(server, &Principal::from(&user), &id, config)
+            }) {
+                Ok(media) => {
+                    Ok(warp::reply::json(&APIResponse {
+                        status: "success".to_string(),
+                        message: Some(format!("{} was deleted", media.original_filename)),
+                        data: Some(doc! {"id": media.id}),
+                    }))
+                }
+                Err(e) => Err(e),
+            }
+        } else if action.eq("list_media") {
+            match API::authenticate(server, data)
+                .and_then(|user| DatabaseController::list_media(server, &Principal::from(&user)))
+            {
+                Ok(media) => {
+                    Ok(warp::reply::json(&APIResponse {
+                        status: "success".to_string(),
+                        message: None,
+                        data: Some(media),
+                    }))
+                }
+                Err(e) => Err(e),
+            }
+        } else if action.eq("get_profile") {
+            match API::authenticate(server, data) {
+                Ok(user) => {
+                    Ok(warp::reply::json(&APIResponse {
+                        status: "success".to_string(),
+                        message: None,
+                        data: Some(Profile::from(&user)),
+                    }))
+                }
+                Err(e) => Err(e),
+            }
+        } else if action.eq("update_profile") {
+            match API::authenticate(server, data).and_then(|user| {
+                let request = requests::from_data::<UpdateProfileRequest>(data)?;
+                DatabaseController::update_profile(server, &user, &request)
+            }) {
+                Ok(user) => {
+                    Ok(warp::reply::json(&APIResponse {
+                        status: "success".to_string(),
+                        message: None,
+                        data: Some(Profile::from(&user)),
+                    }))
+                }
+                Err(e) => Err(e),
+            }
+        } else if action.eq("change_password") {
+            let credentials = requests::from_data::<Credentials>(data)?;
+            match DatabaseController::authenticate_session(server, &credentials.username, &credentials.access_token)
+                .and_then(|(user, session)| {
+                    let request = requests::from_data::<ChangePasswordRequest>(data)?;
+                    DatabaseController::change_password(server, &user, &session.id, &request, &client)
+                }) {
+                Ok(_user) => {
+                    Ok(warp::reply::json(&APIResponse {
+                        status: "success".to_string(),
+                        message: Some("The password has been changed, other sessions were logged out".to_string()),
+                        data: Some("Password Changed!"),
+                    }))
+                }
+                Err(e) => Err(e),
+            }
+        } else if action.eq("change_email") {
+            match API::authenticate(server, data).and_then(|user| {
+                let request = requests::from_data::<ChangeEmailRequest>(data)?;
+                DatabaseController::change_email(server, &user, &request, emailer, config)
+            }) {
+                Ok(Some(verified)) => {
+                    Ok(warp::reply::json(&APIResponse {
+                        status: "success".to_string(),
+                        message: Some("A confirmation code has been sent to the new address".to_string()),
+                        data: Some(doc! {
+                            "verify_token": verified.verify_token
+                        }),
+                    }))
+                }
+                Ok(None) => {
                     Ok(warp::reply::json(&APIResponse {
                         status: "fail".to_string(),
-                        message: Some("Logout Failed".to_string()),
-                        data: Some(format!("{:?}", e)),
+                        message: Some("Email change failed!".to_string()),
+                        data: Some("The confirmation email could not be sent"),
+                    }))
+                }
+                Err(e) => Err(e),
+            }
+        } else if action.eq("confirm_email") {
+            match API::authenticate(server, data).and_then(|user| {
+                let request = requests::from_data::<ConfirmEmailRequest>(data)?;
+                DatabaseController::confirm_email_change(server, &user, &request)
+            }) {
+                Ok(user) => {
+                    Ok(warp::reply::json(&APIResponse {
+                        status: "success".to_string(),
+                        message: Some(format!("{} is now your email address", user.email)),
+                        data: Some(Profile::from(&user)),
+                    }))
+                }
+                Err(e) => Err(e),
+            }
+        } else if action.eq("delete_account") {
+            match API::authenticate(server, data).and_then(|user| {
+                let request = requests::from_data::<PasswordConfirmRequest>(data)?;
+                DatabaseController::request_account_deletion(server, &user, &request.password, &client, config)
+            }) {
+                Ok(user) => {
+                    Ok(warp::reply::json(&APIResponse {
+                        status: "success".to_string(),
+                        message: Some(format!(
+                            "{} will be deleted, log in again before then to keep the account",
+                            user.username
+                        )),
+                        data: Some(doc! {
+                            "deletion_scheduled": user.deletion_scheduled.unwrap()
+                        }),
+                    }))
+                }
+                Err(e) => Err(e),
+            }
+        } else if action.eq("export_data") {
+            match API::authenticate(server, data).and_then(|user| DatabaseController::export_user_data(server, &user)) {
+                Ok(export) => {
+                    Ok(warp::reply::json(&APIResponse {
+                        status: "success".to_string(),
+                        message: None,
+                        data: Some(export),
+                    }))
+                }
+                Err(e) => Err(e),
+            }
+        } else if action.eq("set_access_level") {
+            match API::authenticate(server, data).and_then(|admin| {
+                let request = requests::from_data::<SetAccessLevelRequest>(data)?;
+                let admin = Principal::from(&admin);
+                DatabaseController::set_access_level(server, &admin, &request.target, &request.access_level, config)
+            }) {
+                Ok(user) => {
+                    Ok(warp::reply::json(&APIResponse {
+                        status: "success".to_string(),
+                        message: Some(format!("{} is now {}", user.username, user.access_level)),
+                        data: Some(Profile::from(&user)),
+                    }))
+                }
+                Err(e) => Err(e),
+            }
+        } else if action.eq("unlock_user") {
+            match API::authenticate(server, data).and_then(|admin| {
+                let request = requests::from_data::<UnlockUserRequest>(data)?;
+                DatabaseController::unlock_user(server, &Principal::from(&admin), &request.target, config)
+            }) {
+                Ok(user) => {
+                    Ok(warp::reply::json(&APIResponse {
+                        status: "success".to_string(),
+                        message: Some(format!("{} can log in again", user.username)),
+                        data: Some(Profile::from(&user)),
                     }))
                 }
+                Err(e) => Err(e),
             }
         } else {
             Ok(warp::reply::json(&APIResponse {
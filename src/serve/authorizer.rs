@@ -1,3 +1,5 @@
+use super::api::errors;
+use super::database_errors::{DatabaseError, NotFoundError};
 use super::oauth::Oauth;
 use std::sync::{Arc, Mutex};
 use warp;
@@ -33,10 +35,21 @@ impl Authorizer{
     }
 
     pub fn route(self) -> impl Filter<Extract = (warp::reply::Json,), Error = Rejection> + Clone{
-        warp::get().and(warp::path!("oauth-validate" / String)).and(warp::query::<HashMap<String, String>>()).map(move | provider: String,query: HashMap<String, String>| {
-            let auth = self.auths.lock().unwrap();
-            let authr = auth.get(&provider).unwrap();
-            authr.clone().handle_response(query)
+        warp::get().and(warp::path!("oauth-validate" / String)).and(warp::query::<HashMap<String, String>>()).and_then(move | provider: String,query: HashMap<String, String>| {
+            let authr = self.auths.lock().unwrap().get(&provider).cloned();
+            // The token exchange and the database write both block
+            errors::rejected(async move {
+                match authr {
+                    Some(authr) => {
+                        let server = authr.server.clone();
+                        server.run(move |_server| Ok(authr.handle_response(query))).await
+                    }
+                    None => Err(DatabaseError::NotFoundError(NotFoundError::new(&format!(
+                        "{} is not waiting for authorization",
+                        provider
+                    )))),
+                }
+            })
         })
     }
 }
\ No newline at end of file
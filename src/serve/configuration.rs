@@ -1,6 +1,8 @@
 use serde::{Serialize, Deserialize};
 use mongodb::{options::CreateCollectionOptions};
-use super::{database_errors::DatabaseError};
+use super::{database_errors::{DatabaseError, PermissionDeniedError}};
+use super::database_structures::Principal;
+use std::collections::HashMap;
 use config::{Config, Environment, File};
 
 pub struct NewCollection {
@@ -26,11 +28,20 @@ impl NewCollectionOptions {
     }
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    Mongodb,
+    Memory,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct DB{
     pub uri: String,
     pub name: String,
-    pub collections: Option<Vec<NewCollectionOptions>>
+    pub collections: Option<Vec<NewCollectionOptions>>,
+    /// Defaults to mongodb, `memory` keeps everything in process and needs no database server
+    pub backend: Option<StorageBackend>
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -40,7 +51,10 @@ pub struct ServerConfig{
     pub access_log: String,
     pub tls_key: String,
     pub tls_cert: String,
-    pub hostname: String
+    pub hostname: String,
+    /// Proxies whose `X-Forwarded-For` is believed, anyone else is known by their socket address
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -57,12 +71,301 @@ pub struct OauthConfig{
     pub id: Option<String>
 }
 
+/// A provider users can sign in with. Unlike `[oauth]`, which authorizes the server itself,
+/// these are used once per login.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SocialProvider{
+    pub name: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub auth_url: String,
+    pub token_url: String,
+    /// Returns the signed in user's profile as json
+    pub userinfo_url: String,
+    pub scope: Vec<String>,
+    /// Profile field with the provider's id for the user
+    #[serde(default = "SocialProvider::default_id_field")]
+    pub id_field: String,
+    #[serde(default = "SocialProvider::default_email_field")]
+    pub email_field: String,
+    /// Profile field saying the email was verified
+    #[serde(default = "SocialProvider::default_email_verified_field")]
+    pub email_verified_field: String,
+    /// Takes every address as verified without looking at `email_verified_field`. Only for
+    /// providers that never return an address they haven't verified.
+    #[serde(default)]
+    pub trust_provider_email: bool
+}
+
+impl SocialProvider {
+    fn default_id_field() -> String {
+        "sub".to_string()
+    }
+
+    fn default_email_field() -> String {
+        "email".to_string()
+    }
+
+    fn default_email_verified_field() -> String {
+        "email_verified".to_string()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct SocialConfig{
+    pub providers: Vec<SocialProvider>
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct EmailConfig{
     pub from_address: String,
     pub provider: String
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MediaConfig{
+    pub storage_dir: String,
+    pub max_size: u64,
+    pub allowed_types: Vec<String>
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RolesConfig{
+    pub levels: Vec<String>,
+    pub default: String,
+    pub permissions: HashMap<String, String>
+}
+
+impl RolesConfig {
+    /// Position of an access level in the hierarchy, higher levels inherit everything below them.
+    pub fn rank(&self, access_level: &str) -> Option<usize> {
+        self.levels.iter().position(|level| level == access_level)
+    }
+
+    /// Permissions missing from `[roles.permissions]` are restricted to the highest level.
+    pub fn permits(&self, access_level: &str, permission: &str) -> bool {
+        let required = match self.permissions.get(permission) {
+            Some(level) => self.rank(level),
+            None => self.levels.len().checked_sub(1),
+        };
+        match (self.rank(access_level), required) {
+            (Some(have), Some(need)) => have >= need,
+            _ => false,
+        }
+    }
+
+    pub fn require(&self, principal: &Principal, permission: &str) -> Result<(), DatabaseError> {
+        if self.permits(&principal.access_level, permission) {
+            Ok(())
+        } else {
+            Err(DatabaseError::PermissionDeniedError(PermissionDeniedError::new(&format!(
+                "The {} access level does not have the {} permission",
+                principal.access_level, permission
+            ))))
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessTokenMode {
+    /// Random tokens looked up in the sessions collection on every request
+    Opaque,
+    /// HS256 JWTs checked against their signature, refresh tokens stay in the sessions collection
+    Signed,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PasswordHashAlgorithm {
+    Argon2id,
+    /// Only worth choosing to match other systems, existing bcrypt hashes verify either way
+    Bcrypt,
+}
+
+/// What `[auth.password_policy]` asks of new passwords. Anything left out is the default.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct PasswordPolicy{
+    pub min_length: usize,
+    pub max_length: usize,
+    pub require_lowercase: bool,
+    pub require_uppercase: bool,
+    pub require_digit: bool,
+    /// Anything that isn't a letter or digit counts as a symbol
+    pub require_symbol: bool,
+    /// Refuse passwords that are the username, the email address or its local part
+    pub reject_personal: bool,
+    /// A file of known breached passwords, one per line
+    pub breached_passwords: Option<String>
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        PasswordPolicy {
+            min_length: 8,
+            max_length: 128,
+            require_lowercase: false,
+            require_uppercase: false,
+            require_digit: false,
+            require_symbol: false,
+            reject_personal: true,
+            breached_passwords: None,
+        }
+    }
+}
+
+/// How `[auth.lockout]` slows down password guessing. Once a counter reaches its number of
+/// attempts every further failure locks it out for `base_delay` seconds, doubling each time up to
+/// `max_delay`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct LockoutPolicy{
+    /// Failures allowed against one account
+    pub account_attempts: u32,
+    /// Failures allowed from one address, across every account it tries
+    pub ip_attempts: u32,
+    /// Wrong codes a password reset takes before it has to be requested again
+    pub reset_attempts: u32,
+    pub base_delay: u64,
+    pub max_delay: u64,
+    /// Seconds after the last failure that a counter starts over
+    pub reset_after: u64
+}
+
+impl Default for LockoutPolicy {
+    fn default() -> Self {
+        LockoutPolicy {
+            account_attempts: 5,
+            ip_attempts: 20,
+            reset_attempts: 5,
+            base_delay: 30,
+            max_delay: 3600,
+            reset_after: 86400,
+        }
+    }
+}
+
+/// Lifetimes are in seconds. Everything but `token_key` can be left out.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AuthConfig{
+    /// Secret the stored token hashes are keyed with. Changing it invalidates every outstanding
+    /// session, verification and reset token.
+    pub token_key: String,
+    /// Defaults to opaque
+    pub access_tokens: Option<AccessTokenMode>,
+    /// Key signed access tokens are issued with, `token_key` when unset
+    pub signing_key: Option<String>,
+    #[serde(default = "AuthConfig::default_access_token_ttl")]
+    pub access_token_ttl: u64,
+    #[serde(default = "AuthConfig::default_refresh_token_ttl")]
+    pub refresh_token_ttl: u64,
+    #[serde(default = "AuthConfig::default_verification_ttl")]
+    pub verification_ttl: u64,
+    #[serde(default = "AuthConfig::default_password_reset_ttl")]
+    pub password_reset_ttl: u64,
+    /// Seconds between asking for an account to be deleted and it going for good
+    #[serde(default = "AuthConfig::default_deletion_grace_period")]
+    pub deletion_grace_period: u64,
+    /// Seconds an emailed login link stays usable
+    #[serde(default = "AuthConfig::default_magic_link_ttl")]
+    pub magic_link_ttl: u64,
+    /// Seconds a user has to get through a sign in provider's pages
+    #[serde(default = "AuthConfig::default_social_login_ttl")]
+    pub social_login_ttl: u64,
+    /// Length of the codes emailed for verification, password resets and login links
+    #[serde(default = "AuthConfig::default_code_length")]
+    pub code_length: usize,
+    /// Characters those codes are drawn from
+    #[serde(default = "AuthConfig::default_code_alphabet")]
+    pub code_alphabet: String,
+    /// Defaults to argon2id. Passwords hashed any other way are rehashed on their next login.
+    pub password_hash: Option<PasswordHashAlgorithm>,
+    /// bcrypt cost passwords are hashed with
+    #[serde(default = "AuthConfig::default_hash_cost")]
+    pub hash_cost: u32,
+    /// Argon2id memory in KiB
+    #[serde(default = "AuthConfig::default_argon2_memory_cost")]
+    pub argon2_memory_cost: u32,
+    /// Argon2id passes over that memory
+    #[serde(default = "AuthConfig::default_argon2_time_cost")]
+    pub argon2_time_cost: u32,
+    #[serde(default = "AuthConfig::default_argon2_parallelism")]
+    pub argon2_parallelism: u32,
+    /// How long a login has to enter its second factor once the password checked out
+    #[serde(default = "AuthConfig::default_two_factor_challenge_ttl")]
+    pub two_factor_challenge_ttl: u64,
+    /// Name authenticator apps show next to the account
+    #[serde(default = "AuthConfig::default_totp_issuer")]
+    pub totp_issuer: String,
+    #[serde(default)]
+    pub password_policy: PasswordPolicy,
+    #[serde(default)]
+    pub lockout: LockoutPolicy
+}
+
+impl AuthConfig {
+    fn default_access_token_ttl() -> u64 {
+        1200
+    }
+
+    fn default_refresh_token_ttl() -> u64 {
+        2592000
+    }
+
+    fn default_verification_ttl() -> u64 {
+        86400
+    }
+
+    fn default_password_reset_ttl() -> u64 {
+        3600
+    }
+
+    fn default_magic_link_ttl() -> u64 {
+        600
+    }
+
+    fn default_social_login_ttl() -> u64 {
+        600
+    }
+
+    fn default_deletion_grace_period() -> u64 {
+        604800
+    }
+
+    fn default_code_length() -> usize {
+        6
+    }
+
+    fn default_code_alphabet() -> String {
+        "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789".to_string()
+    }
+
+    fn default_hash_cost() -> u32 {
+        12
+    }
+
+    fn default_argon2_memory_cost() -> u32 {
+        19456
+    }
+
+    fn default_argon2_time_cost() -> u32 {
+        2
+    }
+
+    fn default_argon2_parallelism() -> u32 {
+        1
+    }
+
+    fn default_two_factor_challenge_ttl() -> u64 {
+        300
+    }
+
+    fn default_totp_issuer() -> String {
+        "qamaits".to_string()
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct OauthWrapper{
     pub auths: Vec<OauthConfig>
@@ -74,6 +377,11 @@ pub struct Configuration {
     pub server: ServerConfig,
     pub oauth: OauthWrapper,
     pub email: EmailConfig,
+    pub media: MediaConfig,
+    pub roles: RolesConfig,
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub social: SocialConfig,
 }
 
 #[derive(Clone, Debug)]
@@ -1,20 +1,61 @@
-use super::configuration::{Configuration, NewCollection, OauthConfig};
+use super::configuration::{
+    AccessTokenMode, AuthConfig, Configuration, NewCollection, OauthConfig, SocialProvider, StorageBackend,
+};
 use super::database_errors::{
     AlreadyExistsError, DatabaseError, InvalidCredentialsError, NotFoundError,
+    PermissionDeniedError, RateLimitedError,
+};
+use super::database_structures::{
+    AccessRecord, EmailChange, Profile, SessionInfo, UserExport, LinkedAccount, LoginAttempts, LoginChallenge, LoginOutcome, MagicLink, Media, OauthState, Object, PasswordReset, Post, Principal, PostStatus, SecurityEvent, SessionClient, User,
+    TwoFactor, TwoFactorEnrollment, Verified,
 };
-use serde::{de::DeserializeOwned};
-use super::database_structures::{AccessRecord, Object, User, Verified};
 use super::server::Server;
+use super::api::requests::{
+    self, ChangeEmailRequest, ChangePasswordRequest, ConfirmEmailRequest, ConfirmResetRequest, CreatePostRequest,
+    ListPostsQuery, LoginRequest, MagicLinkRequest, MagicLoginRequest, PasswordResetRequest, PostLookup,
+    RegisterRequest, ResendVerificationRequest, SocialCallbackQuery, TwoFactorLoginRequest, UpdatePostRequest,
+    UpdateProfileRequest,
+};
 use super::emailer::Emailer;
-use bson::doc;
-use mongodb::{options::FindOneOptions, Client, Collection, Database};
-use std::collections::HashMap;
+use super::passwords::{self, PasswordChecker};
+use super::storage::memory::MemoryStorage;
+use super::storage::mongo::MongoStorage;
+use super::storage::{PostFilter, Storage};
+use super::social::{self, SocialProfile};
+use super::tokens::{Claims, TokenHasher, TokenSigner};
+use super::totp;
+use config::ConfigError;
+use mongodb::{Client, Database};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
 use std::time::SystemTime;
 
+/// Shortest `[auth] signing_key` accepted for signed access tokens.
+const MIN_SIGNING_KEY_LENGTH: usize = 32;
+
+/// How many recovery codes come with a TOTP enrollment.
+const RECOVERY_CODES: usize = 10;
+
+/// Recovery codes are shown lowercase but people type them however they like.
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .collect::<String>()
+        .to_lowercase()
+}
+
 #[derive(Clone)]
 pub struct DatabaseController {
     pub uri: String,
-    pub database: Database,
+    pub storage: Arc<dyn Storage>,
+    pub tokens: TokenHasher,
+    /// Set when access tokens are signed rather than looked up.
+    pub signer: Option<TokenSigner>,
+    pub passwords: PasswordChecker,
+    pub auth: AuthConfig,
 }
 
 impl DatabaseController {
@@ -48,9 +89,37 @@ impl DatabaseController {
         return Ok(database);
     }
 
+    /// Refuses to sign with a missing, short or shipped placeholder key, since anyone knowing the
+    /// key can issue themselves a token at any access level.
+    fn signer_from_config(config: &Configuration) -> Result<Option<TokenSigner>, DatabaseError> {
+        match config.auth.access_tokens {
+            Some(AccessTokenMode::Signed) => {
+                let key = config.auth.signing_key.as_ref().unwrap_or(&config.auth.token_key);
+                if key.len() < MIN_SIGNING_KEY_LENGTH || key == "SIGNING_KEY" || key == "TOKEN_KEY" {
+                    return Err(DatabaseError::ConfigError(ConfigError::Message(format!(
+                        "Signed access tokens need a secret [auth] signing_key of at least {} characters",
+                        MIN_SIGNING_KEY_LENGTH
+                    ))));
+                }
+                Ok(Some(TokenSigner::new(key)))
+            }
+            _ => Ok(None),
+        }
+    }
+
     pub fn create_database_from_config(
         config: &Configuration,
     ) -> Result<DatabaseController, DatabaseError> {
+        if config.database.backend == Some(StorageBackend::Memory) {
+            return Ok(DatabaseController {
+                uri: config.clone().database.uri,
+                storage: Arc::new(MemoryStorage::new()),
+                tokens: TokenHasher::new(&config.auth.token_key),
+                signer: DatabaseController::signer_from_config(config)?,
+                passwords: PasswordChecker::from_config(&config.auth)?,
+                auth: config.auth.clone(),
+            });
+        }
         let collections = config.clone().database.collections.unwrap();
         let mut coll_vec = vec![];
         for i in 0..collections.len() {
@@ -67,7 +136,11 @@ impl DatabaseController {
             Ok(database) => {
                 return Ok(DatabaseController {
                     uri: config.clone().database.uri,
-                    database: database,
+                    storage: Arc::new(MongoStorage::new(database)),
+                    tokens: TokenHasher::new(&config.auth.token_key),
+                    signer: DatabaseController::signer_from_config(config)?,
+                    passwords: PasswordChecker::from_config(&config.auth)?,
+                    auth: config.auth.clone(),
                 });
             }
             Err(error) => {
@@ -77,184 +150,95 @@ impl DatabaseController {
     }
 
     pub fn find_user(
-        collection: &Collection,
+        server: &Server,
         username: &str,
         email: Option<String>,
     ) -> Result<User, DatabaseError> {
-        let filter;
-        if email.is_some() {
-            filter = doc! { "$or": [{"username" : username }, {"email": email.unwrap()}]};
-        } else {
-            filter = doc! {"username" : username };
-        }
-        let options = FindOneOptions::builder().build();
-        match collection.find_one(filter, options) {
-            Ok(doc) => {
-                if doc.is_some() {
-                    let d = doc.unwrap();
-                    let new_doc = bson::Bson::Document(d);
-                    let user: User = bson::from_bson::<User>(new_doc).unwrap();
-                    return Ok(user);
-                } else {
-                    return Err(DatabaseError::NotFoundError(NotFoundError::new(&format!(
-                        "{} was not found",
-                        username
-                    ))));
-                }
-            }
-            Err(e) => {
-                return Err(DatabaseError::Error(e));
-            }
+        match server.database.storage.find_user(username, email.as_deref()) {
+            Ok(Some(user)) => Ok(user),
+            Ok(None) => Err(DatabaseError::NotFoundError(NotFoundError::new(&format!(
+                "{} was not found",
+                username
+            )))),
+            Err(e) => Err(e),
         }
     }
 
     pub fn register_user(
         server: &Server,
         access_level: String,
-        data: &HashMap<String, String>,
+        request: &RegisterRequest,
         emailer: &Emailer,
         config: &Configuration
     ) -> Result<Option<User>, DatabaseError> {
-        let password : String;
-        let username : String;
-        let email : String;
-        let first_name: Option<String>;
-        let last_name : Option<String>;
-        let phone_number : Option<String>;
-        let address : Option<String>;
-        
-        let mut field = "password";
-        if data.get(field).is_some() {
-            password = data.get(field).unwrap().to_string();
-        }else{
-            return Err(DatabaseError::InvalidCredentialsError(InvalidCredentialsError::new(&format!("Missing {} field", field))));
-        }
-
-        field = "username";
-        if data.get(field).is_some() {
-            username = data.get(field).unwrap().to_string();
-        }else{
-            return Err(DatabaseError::InvalidCredentialsError(InvalidCredentialsError::new(&format!("Missing {} field", field))));
-        }
-
-        field = "email";
-        if data.get(field).is_some() {
-            email = data.get(field).unwrap().to_string();
-        }else{
-            return Err(DatabaseError::InvalidCredentialsError(InvalidCredentialsError::new(&format!("Missing {} field", field))));
-        }
-        
-        field = "first_name";
-        if data.get(field).is_some() {
-            first_name = Some(data.get(field).unwrap().to_string());
-        }else{
-            first_name = None;
-        }
-
-        field = "last_name";
-        if data.get(field).is_some() {
-            last_name = Some(data.get(field).unwrap().to_string());
-        }else{
-            last_name = None;
-        }
-
-        field = "address";
-        if data.get(field).is_some() {
-            address = Some(data.get(field).unwrap().to_string());
-        }else{
-            address = None;
-        }
-
-        field = "phone_number";
-        if data.get(field).is_some() {
-            phone_number = Some(data.get(field).unwrap().to_string());
-        }else{
-            phone_number = None;
-        }
-
-        if validator::validate_email(email.clone()) {
-            match DatabaseController::user_exists(server, &username, &email) {
-                Err(_e) => match DatabaseController::add_object(server, "user") {
-                    Ok(id) => {
-                        if id.is_some() {
-                            match DatabaseController::add_user(
-                                server,
-                                id.unwrap(),
-                                username,
-                                password,
-                                email,
-                                access_level,
-                                Verified::new(),
-                                first_name,
-                                last_name,
-                                address,
-                                phone_number
-                            ) {
-                                Ok(user) => {
-                                    if user.is_some() {
-                                        let email = emailer.build_email(config.clone().email.from_address, (
-                                            user.clone().unwrap().email,
-                                            user.clone().unwrap().username,
-                                        ), "Login Verification Code".to_string(),
-                                        format!(
-                                            "<h2><u>Your verification code is</u>:<b> {}</b></h2>",
-                                            user.clone().unwrap().verify.unwrap().verify_code
-                                        ),
-                                        format!(
-                                            "Your verification code is: {}",
-                                            user.clone().unwrap().verify.unwrap().verify_code
-                                        ));
-                                        match DatabaseController::find::<OauthConfig>(server, doc!{"name": config.clone().email.provider}, "oauth"){
-                                            Ok(auth) => {
-                                                if auth.is_some() {
-                                                    emailer.send_email(email.clone(), auth.unwrap());
-                                                }else{
-                                                    return Ok(None);
-                                                }
-                                            }
-                                            Err(e) => {
-                                                return Err(e);
-                                            }
-                                        }
-                                        return Ok(user);
-                                    } else {
+        server.database.passwords.check(&request.password, &request.username, &request.email)?;
+        let username = request.username.clone();
+        let email = request.email.clone();
+        let verified = Verified::new(&server.database.auth);
+        match DatabaseController::user_exists(server, &username, &email) {
+            Err(_e) => match DatabaseController::add_object(server, "user") {
+                Ok(id) => {
+                    if id.is_some() {
+                        match DatabaseController::add_user(
+                            server,
+                            id.unwrap(),
+                            username,
+                            request.password.clone(),
+                            email,
+                            access_level,
+                            verified.hashed(&server.database.tokens),
+                            request.first_name.clone(),
+                            request.last_name.clone(),
+                            request.address.clone(),
+                            request.phone_number.clone()
+                        ) {
+                            Ok(user) => {
+                                if let Some(mut user) = user {
+                                    // Only the hashes were stored, the plaintext is emailed and
+                                    // handed back once
+                                    user.verify = Some(verified);
+                                    let sent = DatabaseController::send_verification(
+                                        server,
+                                        emailer,
+                                        config,
+                                        &user,
+                                    )?;
+                                    if !sent {
                                         return Ok(None);
                                     }
-                                }
-                                Err(e) => {
-                                    return Err(e);
+                                    return Ok(Some(user));
+                                } else {
+                                    return Ok(None);
                                 }
                             }
-                        } else {
-                            return Ok(None);
+                            Err(e) => {
+                                return Err(e);
+                            }
                         }
-                    }
-                    Err(e) => {
-                        return Err(e);
-                    }
-                },
-                Ok(user) => {
-                    if user.username == username {
-                        return Err(DatabaseError::AlreadyExistsError(AlreadyExistsError::new(
-                            "There is already someone wth that username",
-                        )));
                     } else {
-                        return Err(DatabaseError::AlreadyExistsError(AlreadyExistsError::new(
-                            "There is already an account with that email address",
-                        )));
+                        return Ok(None);
                     }
                 }
+                Err(e) => {
+                    return Err(e);
+                }
+            },
+            Ok(user) => {
+                if user.username == username {
+                    return Err(DatabaseError::AlreadyExistsError(AlreadyExistsError::new(
+                        "There is already someone wth that username",
+                    )));
+                } else {
+                    return Err(DatabaseError::AlreadyExistsError(AlreadyExistsError::new(
+                        "There is already an account with that email address",
+                    )));
+                }
             }
-        } else {
-            return Err(DatabaseError::InvalidCredentialsError(
-                InvalidCredentialsError::new("This is an invalid email address"),
-            ));
         }
     }
 
     pub fn user_exists(server: &Server, username: &str, email: &str) -> Result<User, DatabaseError> {
-        let users_collection = server.database.database.collection("users");
-        match DatabaseController::find_user(&users_collection, username, Some(email.to_string())) {
+        match DatabaseController::find_user(server, username, Some(email.to_string())) {
             Ok(user) => return Ok(user),
             Err(e) => return Err(e),
         }
@@ -266,11 +250,10 @@ impl DatabaseController {
         verify_token: String,
         verify_code: String,
     ) -> Result<User, DatabaseError> {
-        let users_collection = server.database.database.collection("users");
-        match DatabaseController::find_user(&users_collection, &username, None) {
+        match DatabaseController::find_user(server, &username, None) {
             Ok(mut user) => {
-                if Verified::verify(&user, verify_token, verify_code) {
-                    let mut ver = user.clone().verify.unwrap();
+                if Verified::verify(&user, verify_token, verify_code, &server.database.tokens) {
+                    let mut ver = user.clone().verify.unwrap().hashed(&server.database.tokens);
                     ver.verify_time = Some(
                         SystemTime::now()
                             .duration_since(SystemTime::UNIX_EPOCH)
@@ -281,7 +264,7 @@ impl DatabaseController {
                     ver.verified = true;
                     user.verify = Some(ver);
                     match DatabaseController::update_user(
-                        &users_collection,
+                        server,
                         user.clone().username,
                         user.clone(),
                     ) {
@@ -305,41 +288,433 @@ impl DatabaseController {
     }
 
     pub fn logout(server: &Server, username: String, access_token: String) -> Result<bool, DatabaseError> {
-        let users_collection = server.database.database.collection("users");
-        match DatabaseController::find_user(&users_collection, &username, None) {
-            Ok(user) => {
-                if user.clone().access_record.is_some() {
-                    if user.clone().access_record.unwrap().access_token == access_token {
-                        match DatabaseController::update_access_record(
-                            &users_collection,
-                            user,
-                            None,
-                        ) {
-                            Ok(_acc) => {
-                                return Ok(true);
-                            }
-                            Err(e) => {
-                                return Err(e);
-                            }
-                        }
-                    } else {
-                        return Err(DatabaseError::NotFoundError(NotFoundError::new(&format!(
-                            "{} has no access record please login",
-                            user.clone().username
-                        ))));
-                    }
-                } else {
-                    return Err(DatabaseError::InvalidCredentialsError(
-                        InvalidCredentialsError::new("Invalid username or access token"),
-                    ));
-                }
+        let (_user, session) = DatabaseController::authenticate_session(server, &username, &access_token)?;
+        match server.database.storage.delete_session(&session.id) {
+            Ok(()) => Ok(true),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Every session `user` has open, newest first.
+    pub fn list_sessions(server: &Server, user: &User) -> Result<Vec<AccessRecord>, DatabaseError> {
+        server.database.storage.list_sessions(&user.id)
+    }
+
+    /// Ends one of `user`'s sessions. Other users' sessions are reported as not found.
+    pub fn revoke_session(server: &Server, user: &User, id: &str) -> Result<AccessRecord, DatabaseError> {
+        let sessions = server.database.storage.list_sessions(&user.id)?;
+        match sessions.into_iter().find(|session| session.id == id) {
+            Some(session) => match server.database.storage.delete_session(&session.id) {
+                Ok(()) => Ok(session),
+                Err(e) => Err(e),
+            },
+            None => Err(DatabaseError::NotFoundError(NotFoundError::new(&format!(
+                "There is no session with the id {}",
+                id
+            )))),
+        }
+    }
+
+    /// Ends every session `user` has, including the one making the request.
+    pub fn revoke_sessions(server: &Server, user: &User) -> Result<(), DatabaseError> {
+        server.database.storage.delete_sessions(&user.id)
+    }
+
+    /// Emails `user` through the configured provider, `Ok(false)` when the provider has no stored
+    /// credentials yet and nothing could be sent.
+    pub fn email_user(
+        server: &Server,
+        emailer: &Emailer,
+        config: &Configuration,
+        user: &User,
+        subject: &str,
+        html: String,
+        text: String,
+    ) -> Result<bool, DatabaseError> {
+        match server.database.storage.find_oauth_record(&config.email.provider) {
+            Ok(Some(auth)) => {
+                let email = emailer.build_email(
+                    config.email.from_address.clone(),
+                    (user.email.clone(), user.username.clone()),
+                    subject.to_string(),
+                    html,
+                    text,
+                );
+                emailer.send_email(email, auth);
+                Ok(true)
             }
-            Err(e) => {
-                return Err(e);
+            Ok(None) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Whether `email_user` has a mail account to send with.
+    pub fn can_email(server: &Server, config: &Configuration) -> Result<bool, DatabaseError> {
+        Ok(server.database.storage.find_oauth_record(&config.email.provider)?.is_some())
+    }
+
+    /// Emails the user's current verification code along with a link that verifies the account
+    /// without having to type the code in.
+    pub fn send_verification(
+        server: &Server,
+        emailer: &Emailer,
+        config: &Configuration,
+        user: &User,
+    ) -> Result<bool, DatabaseError> {
+        let verify = match &user.verify {
+            Some(verify) => verify,
+            None => return Ok(false),
+        };
+        let link = format!(
+            "https://{}/api/v1/users/verify?username={}&verify_token={}&verify_code={}",
+            server.hostname, user.username, verify.verify_token, verify.verify_code
+        );
+        DatabaseController::email_user(
+            server,
+            emailer,
+            config,
+            user,
+            "Login Verification Code",
+            format!(
+                "<h2><u>Your verification code is</u>:<b> {}</b></h2><p><a href=\"{}\">Verify your account</a></p>",
+                verify.verify_code, link
+            ),
+            format!("Your verification code is: {}\nOr verify your account at: {}", verify.verify_code, link),
+        )
+    }
+
+    /// Replaces an unverified user's token and code and emails the new code. Resends are limited to
+    /// one every `RESEND_INTERVAL`, `Ok(None)` if the email couldn't be sent.
+    pub fn resend_verification(
+        server: &Server,
+        request: &ResendVerificationRequest,
+        emailer: &Emailer,
+        config: &Configuration,
+    ) -> Result<Option<User>, DatabaseError> {
+        let mut user = DatabaseController::find_user(server, &request.username, None)?;
+        if let Some(verify) = &user.verify {
+            if verify.verified {
+                return Err(DatabaseError::AlreadyExistsError(AlreadyExistsError::new(&format!(
+                    "{} is already verified",
+                    user.username
+                ))));
+            }
+            if !verify.can_resend() {
+                return Err(DatabaseError::RateLimitedError(RateLimitedError::new(
+                    "A verification code was sent recently, please wait before asking for another",
+                )));
+            }
+        }
+        let verified = Verified::new(&server.database.auth);
+        user.verify = Some(verified.hashed(&server.database.tokens));
+        DatabaseController::update_user(server, user.username.clone(), user.clone())?;
+        user.verify = Some(verified);
+        if DatabaseController::send_verification(server, emailer, config, &user)? {
+            Ok(Some(user))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Starts a password reset for the account behind `request.email`. The code is emailed and the
+    /// returned reset carries the token for the caller, `Ok(None)` if the email couldn't be sent.
+    /// Unknown addresses, and accounts sent a reset within `RESEND_INTERVAL`, get a reset that
+    /// was never stored and no email, so neither flooding an inbox nor probing for accounts works.
+    pub fn request_password_reset(
+        server: &Server,
+        request: &PasswordResetRequest,
+        emailer: &Emailer,
+        config: &Configuration,
+    ) -> Result<Option<PasswordReset>, DatabaseError> {
+        if !DatabaseController::can_email(server, config)? {
+            return Ok(None);
+        }
+        let reset = PasswordReset::generate(&server.database.auth);
+        let mut user = match server.database.storage.find_user_by_email(&request.email)? {
+            Some(user) if user.password_reset.as_ref().is_none_or(|reset| reset.can_resend()) => user,
+            // Answered like a sent reset so replies don't tell which addresses have accounts
+            _ => return Ok(Some(reset)),
+        };
+        user.password_reset = Some(reset.hashed(&server.database.tokens));
+        DatabaseController::update_user(server, user.username.clone(), user.clone())?;
+        let sent = DatabaseController::email_user(
+            server,
+            emailer,
+            config,
+            &user,
+            "Password Reset Code",
+            format!("<h2><u>Your password reset code is</u>:<b> {}</b></h2>", reset.reset_code),
+            format!("Your password reset code is: {}", reset.reset_code),
+        )?;
+        if sent {
+            Ok(Some(reset))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Sets a new password once the reset token and emailed code check out. The reset is single use,
+    /// gets thrown away after `reset_attempts` wrong codes, and any open session is dropped along
+    /// with the old password.
+    pub fn confirm_password_reset(server: &Server, request: &ConfirmResetRequest) -> Result<User, DatabaseError> {
+        let mut user = DatabaseController::find_user(server, &request.username, None)?;
+        let invalid = || {
+            DatabaseError::InvalidCredentialsError(InvalidCredentialsError::new("Invalid or expired reset code"))
+        };
+        let (reset_token, verified) = match &user.password_reset {
+            Some(reset) => (
+                reset.reset_token.clone(),
+                reset.verify(&request.reset_token, &request.reset_code, &server.database.tokens),
+            ),
+            None => return Err(invalid()),
+        };
+        let storage = &server.database.storage;
+        let misses_key = LoginAttempts::reset_key(&reset_token);
+        if !verified {
+            let now = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_millis();
+            let misses = storage.add_login_failure(&misses_key, &now.to_string(), "0")?;
+            if misses.failures >= server.database.auth.lockout.reset_attempts {
+                storage.take_password_reset(&user.id, &reset_token)?;
+                storage.delete_login_attempts(&misses_key)?;
+            }
+            return Err(invalid());
+        }
+        server.database.passwords.check(&request.password, &user.username, &user.email)?;
+        if !storage.take_password_reset(&user.id, &reset_token)? {
+            return Err(invalid());
+        }
+        storage.delete_login_attempts(&misses_key)?;
+        user.password = User::hash_pw(request.password.clone(), &server.database.auth)?;
+        user.password_reset = None;
+        DatabaseController::update_user(server, user.username.clone(), user.clone())?;
+        DatabaseController::revoke_sessions(server, &user)?;
+        Ok(user)
+    }
+
+    /// Sets the optional profile fields `request` has, empty values clear them.
+    pub fn update_profile(server: &Server, user: &User, request: &UpdateProfileRequest) -> Result<User, DatabaseError> {
+        let mut user = user.clone();
+        let fields = [
+            (&mut user.first_name, &request.first_name),
+            (&mut user.last_name, &request.last_name),
+            (&mut user.address, &request.address),
+            (&mut user.phone_number, &request.phone_number),
+        ];
+        for (field, value) in fields {
+            if let Some(value) = value {
+                *field = Some(value.clone()).filter(|value| !value.is_empty());
+            }
+        }
+        DatabaseController::update_user(server, user.username.clone(), user.clone())?;
+        Ok(user)
+    }
+
+    /// Replaces the password of a logged in user who knows the current one. Every other session
+    /// is ended, the one making the change stays open.
+    pub fn change_password(
+        server: &Server,
+        user: &User,
+        session_id: &str,
+        request: &ChangePasswordRequest,
+        client: &SessionClient,
+    ) -> Result<User, DatabaseError> {
+        DatabaseController::check_current_password(server, user, &request.current_password, client)?;
+        server.database.passwords.check(&request.new_password, &user.username, &user.email)?;
+        let mut user = user.clone();
+        user.password = User::hash_pw(request.new_password.clone(), &server.database.auth)?;
+        DatabaseController::update_user(server, user.username.clone(), user.clone())?;
+        for session in server.database.storage.list_sessions(&user.id)? {
+            if session.id != session_id {
+                server.database.storage.delete_session(&session.id)?;
+            }
+        }
+        let mut event = SecurityEvent::new(&user.id, "password_changed", "The password was changed", client);
+        event.session_id = Some(session_id.to_string());
+        server.database.storage.insert_security_event(&event)?;
+        Ok(user)
+    }
+
+    /// Emails a code to the address `user` wants to move to. Until it's confirmed the current
+    /// address keeps working, and asking again replaces the pending change. The returned code
+    /// carries the token for the caller, `Ok(None)` if the email couldn't be sent.
+    pub fn change_email(
+        server: &Server,
+        user: &User,
+        request: &ChangeEmailRequest,
+        emailer: &Emailer,
+        config: &Configuration,
+    ) -> Result<Option<Verified>, DatabaseError> {
+        if request.email.eq_ignore_ascii_case(&user.email) {
+            return Err(DatabaseError::AlreadyExistsError(AlreadyExistsError::new(
+                "That is already your email address",
+            )));
+        }
+        DatabaseController::email_available(server, &request.email)?;
+        let verified = Verified::new(&server.database.auth);
+        let mut user = user.clone();
+        user.email_change = Some(EmailChange {
+            email: request.email.clone(),
+            verify: verified.hashed(&server.database.tokens),
+        });
+        DatabaseController::update_user(server, user.username.clone(), user.clone())?;
+        let recipient = User {
+            email: request.email.clone(),
+            ..user
+        };
+        let sent = DatabaseController::email_user(
+            server,
+            emailer,
+            config,
+            &recipient,
+            "Confirm Your New Email Address",
+            format!("<h2><u>Your confirmation code is</u>:<b> {}</b></h2>", verified.verify_code),
+            format!("Your confirmation code is: {}", verified.verify_code),
+        )?;
+        if sent {
+            Ok(Some(verified))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Switches `user` over to their pending email address once its code checks out.
+    pub fn confirm_email_change(
+        server: &Server,
+        user: &User,
+        request: &ConfirmEmailRequest,
+    ) -> Result<User, DatabaseError> {
+        let tokens = &server.database.tokens;
+        let change = match &user.email_change {
+            Some(change) if change.verify.check(&request.verify_token, &request.verify_code, tokens) => change.clone(),
+            _ => {
+                return Err(DatabaseError::InvalidCredentialsError(
+                    InvalidCredentialsError::new("Invalid verify credentials"),
+                ));
             }
+        };
+        // Someone may have registered with it in the meantime
+        DatabaseController::email_available(server, &change.email)?;
+        let mut user = user.clone();
+        let details = format!("The email address was changed from {} to {}", user.email, change.email);
+        user.email = change.email;
+        user.email_change = None;
+        DatabaseController::update_user(server, user.username.clone(), user.clone())?;
+        let event = SecurityEvent::new(&user.id, "email_changed", &details, &SessionClient::default());
+        server.database.storage.insert_security_event(&event)?;
+        Ok(user)
+    }
+
+    fn email_available(server: &Server, email: &str) -> Result<(), DatabaseError> {
+        match server.database.storage.find_user_by_email(email)? {
+            Some(_user) => Err(DatabaseError::AlreadyExistsError(AlreadyExistsError::new(
+                "There is already an account with that email address",
+            ))),
+            None => Ok(()),
         }
     }
 
+    /// Schedules `user`'s account for deletion once the grace period is over and logs them out
+    /// everywhere. Logging in again before then keeps the account.
+    pub fn request_account_deletion(
+        server: &Server,
+        user: &User,
+        password: &str,
+        client: &SessionClient,
+        config: &Configuration,
+    ) -> Result<User, DatabaseError> {
+        DatabaseController::check_current_password(server, user, password, client)?;
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        let mut user = user.clone();
+        let deletion_time = now + server.database.auth.deletion_grace_period as u128 * 1000;
+        user.deletion_scheduled = Some(deletion_time.to_string());
+        DatabaseController::update_user(server, user.username.clone(), user.clone())?;
+        DatabaseController::revoke_sessions(server, &user)?;
+        let details = format!("The account will be deleted at {}", deletion_time);
+        let event = SecurityEvent::new(&user.id, "deletion_requested", &details, client);
+        server.database.storage.insert_security_event(&event)?;
+        if server.database.auth.deletion_grace_period == 0 {
+            DatabaseController::purge_user(server, &user, config)?;
+        }
+        Ok(user)
+    }
+
+    /// Deletes every account whose grace period is over, returning how many went.
+    pub fn purge_deleted_users(server: &Server, config: &Configuration) -> Result<usize, DatabaseError> {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        let mut purged = 0;
+        for user in server.database.storage.list_users_pending_deletion()? {
+            let due = user
+                .deletion_scheduled
+                .as_ref()
+                .and_then(|time| time.parse::<u128>().ok())
+                .is_some_and(|time| time <= now);
+            if due {
+                DatabaseController::purge_user(server, &user, config)?;
+                purged += 1;
+            }
+        }
+        Ok(purged)
+    }
+
+    /// Removes `user` along with everything they own, their uploads included.
+    fn purge_user(server: &Server, user: &User, config: &Configuration) -> Result<(), DatabaseError> {
+        let storage = &server.database.storage;
+        let filter = PostFilter {
+            author_id: Some(user.id.clone()),
+            status: None,
+        };
+        for post in storage.list_posts(&filter, 0, i64::MAX)? {
+            storage.delete_post(&post.id)?;
+            DatabaseController::remove_object(server, &post.id)?;
+        }
+        for media in storage.list_media(&user.id)? {
+            storage.delete_media(&media.id)?;
+            DatabaseController::remove_object(server, &media.id)?;
+            let _ = fs::remove_file(Path::new(&config.media.storage_dir).join(&media.id));
+        }
+        storage.delete_sessions(&user.id)?;
+        storage.delete_security_events(&user.id)?;
+        storage.delete_login_challenges(&user.id)?;
+        storage.delete_login_attempts(&LoginAttempts::account_key(&user.id))?;
+        if let Some(reset) = &user.password_reset {
+            storage.delete_login_attempts(&LoginAttempts::reset_key(&reset.reset_token))?;
+        }
+        storage.delete_user(&user.id)?;
+        DatabaseController::remove_object(server, &user.id)
+    }
+
+    /// Everything stored about `user`, see `UserExport`.
+    pub fn export_user_data(server: &Server, user: &User) -> Result<UserExport, DatabaseError> {
+        let storage = &server.database.storage;
+        let filter = PostFilter {
+            author_id: Some(user.id.clone()),
+            status: None,
+        };
+        Ok(UserExport {
+            export_time: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_millis()
+                .to_string(),
+            profile: Profile::from(user),
+            verified: user.verify.as_ref().is_some_and(|verify| verify.verified),
+            linked_accounts: user.linked_accounts.clone(),
+            sessions: storage.list_sessions(&user.id)?.iter().map(SessionInfo::from).collect(),
+            security_events: storage.list_security_events(&user.id)?,
+            posts: storage.list_posts(&filter, 0, i64::MAX)?,
+            media: storage.list_media(&user.id)?,
+        })
+    }
+
     pub fn add_user(
         server: &Server,
         id: String,
@@ -353,31 +728,30 @@ impl DatabaseController {
         address: Option<String>,
         phone_number: Option<String>,
     ) -> Result<Option<User>, DatabaseError> {
-        match User::new(id, username, password, email, access_level, Some(verified), first_name, last_name, address, phone_number) {
-            Ok(user) => {
-                let users_collection = server.database.database.collection("users");
-                let new_user = bson::to_bson(&user).unwrap();
-                if let bson::Bson::Document(document) = new_user {
-                    match users_collection.insert_one(document, None) {
-                        Ok(_result) => {
-                            return Ok(Some(user));
-                        }
-                        Err(e) => {
-                            return Err(DatabaseError::Error(e));
-                        }
-                    }
-                } else {
-                    return Ok(None);
-                }
-            }
+        match User::new(
+            id,
+            username,
+            password,
+            email,
+            access_level,
+            Some(verified),
+            first_name,
+            last_name,
+            address,
+            phone_number,
+            &server.database.auth,
+        ) {
+            Ok(user) => match server.database.storage.insert_user(&user) {
+                Ok(()) => Ok(Some(user)),
+                Err(e) => Err(e),
+            },
             Err(e) => {
-                return Err(DatabaseError::BcryptError(e));
+                return Err(e);
             }
         }
     }
 
     pub fn add_object(server: &Server, the_type: &str) -> Result<Option<String>, DatabaseError> {
-        let objects = server.database.database.collection("objects");
         match DatabaseController::generate_object_id() {
             Ok(oid) => match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
                 Ok(n) => {
@@ -386,24 +760,9 @@ impl DatabaseController {
                         creation_time: n.as_millis().to_string(),
                         id: oid,
                     };
-                    match bson::to_bson(&object_raw) {
-                        Ok(bson_object) => {
-                            if let bson::Bson::Document(document) = bson_object {
-                                match objects.insert_one(document, None) {
-                                    Ok(_result) => {
-                                        return Ok(Some(object_raw.id));
-                                    }
-                                    Err(e) => {
-                                        return Err(DatabaseError::Error(e));
-                                    }
-                                }
-                            } else {
-                                return Ok(None);
-                            }
-                        }
-                        Err(e) => {
-                            return Err(DatabaseError::EncoderError(e));
-                        }
+                    match server.database.storage.insert_object(&object_raw) {
+                        Ok(()) => Ok(Some(object_raw.id)),
+                        Err(e) => Err(e),
                     }
                 }
                 Err(e) => {
@@ -420,25 +779,9 @@ impl DatabaseController {
         match DatabaseController::add_object(server, "oauth") {
             Ok(id) => {
                 oauth.id = Some(id.unwrap());
-                let oauth_collection = server.database.database.collection("oauth");
-                match bson::to_bson(&oauth) {
-                    Ok(bson_object) => {
-                        if let bson::Bson::Document(document) = bson_object {
-                            match oauth_collection.insert_one(document, None) {
-                                Ok(_result) => {
-                                    return Ok(Some(oauth.id.unwrap()));
-                                }
-                                Err(e) => {
-                                    return Err(DatabaseError::Error(e));
-                                }
-                            }
-                        } else {
-                            return Ok(None);
-                        }
-                    }
-                    Err(e) => {
-                        return Err(DatabaseError::EncoderError(e));
-                    }
+                match server.database.storage.insert_oauth_record(&oauth) {
+                    Ok(()) => Ok(oauth.id),
+                    Err(e) => Err(e),
                 }
             }
             Err(e) =>{
@@ -447,28 +790,8 @@ impl DatabaseController {
         }
     }
 
-    pub fn find<T>(server: &Server, query: bson::Document, collection: &str)  -> Result<Option<T>, DatabaseError>
-    where T: DeserializeOwned {
-        let collect = server.database.database.collection(&collection);
-        let options = FindOneOptions::builder().build();
-        match collect.find_one(query, options) {
-            Ok(doc) => {
-                if doc.is_some() {
-                    let d = doc.unwrap();
-                    let new_doc = bson::Bson::Document(d);
-                    return Ok(Some(bson::from_bson::<T>(new_doc).unwrap()));
-                } else {
-                    Ok(None)
-                }
-            }
-            Err(e) => {
-                return Err(DatabaseError::Error(e));
-            }
-        }
-    }
-
     pub fn get_oauth_record(server: &Server, name: String) -> Result<OauthConfig, DatabaseError>{
-        match DatabaseController::find::<OauthConfig>(server, doc!{"name" : name.clone()}, "oauth"){
+        match server.database.storage.find_oauth_record(&name) {
             Ok(config) => {
                 if config.is_some(){
                     Ok(config.unwrap())
@@ -480,188 +803,1072 @@ impl DatabaseController {
         }
     }
 
-    pub fn get_acccess_record(server: &Server, username: String) -> Result<Option<AccessRecord>, DatabaseError> {
-        let users_collection = server.database.database.collection("users");
-        match DatabaseController::find_user(&users_collection, &username, None) {
-            Ok(user) => {
-                if user.access_record.is_some() {
-                    return Ok(user.access_record);
-                } else {
-                    return Err(DatabaseError::NotFoundError(NotFoundError::new(
-                        "User access record doesn't exist was not found!",
-                    )));
-                }
+    /// Swaps a refresh token for a new pair of tokens on the same session. Refresh tokens are
+    /// single use, presenting a spent one revokes its session and records a security event.
+    pub fn exchange_refresh_token(
+        server: &Server,
+        refresh_token: &str,
+        client: &SessionClient,
+    ) -> Result<Option<AccessRecord>, DatabaseError> {
+        let tokens = &server.database.tokens;
+        let found = DatabaseController::find_hashed_session(server, refresh_token, |storage, token| {
+            storage.find_session_by_refresh_token(token)
+        })?;
+        let mut session = match found {
+            Some(session) => session,
+            None => {
+                DatabaseController::check_refresh_token_reuse(server, refresh_token, client)?;
+                return Err(DatabaseError::NotFoundError(NotFoundError::new(
+                    "The specified refresh token was not found!",
+                )));
             }
-            Err(e) => {
-                println!("{:?}", e);
-                return Err(e);
+        };
+        let user = match server.database.storage.find_user_by_id(&session.user_id)? {
+            Some(user) => user,
+            None => {
+                return Err(DatabaseError::NotFoundError(NotFoundError::new(
+                    "The specified refresh token was not found!",
+                )));
             }
+        };
+        if session.is_refresh_expired() {
+            server.database.storage.delete_session(&session.id)?;
+            return Err(DatabaseError::InvalidCredentialsError(InvalidCredentialsError::new(
+                "The refresh token has expired, please login",
+            )));
+        }
+        let spent = session.refresh_token.clone().unwrap_or_default();
+        session.refresh(&server.database.auth);
+        DatabaseController::sign_access_token(server, &user, &mut session);
+        if server.database.storage.swap_session(&session.hashed(tokens), &spent)? {
+            return Ok(Some(session));
         }
+        // A concurrent exchange spent the same token first, which is reuse all the same
+        DatabaseController::check_refresh_token_reuse(server, refresh_token, client)?;
+        Err(DatabaseError::NotFoundError(NotFoundError::new(
+            "The specified refresh token was not found!",
+        )))
     }
 
-    pub fn add_access_record(
-        collection: &Collection,
-        mut user: User,
-        data: Option<AccessRecord>,
-    ) -> Result<Option<AccessRecord>, DatabaseError> {
-        user.access_record = data;
-        match DatabaseController::update_user(&collection, user.clone().username, user.clone()) {
-            Ok(_res) => {
-                return Ok(Some(user.clone().access_record.unwrap()));
+    /// Fails if `refresh_token` was already swapped by some session, after revoking that session.
+    /// Either the client or an attacker holds a stale copy and there's no telling which.
+    fn check_refresh_token_reuse(
+        server: &Server,
+        refresh_token: &str,
+        client: &SessionClient,
+    ) -> Result<(), DatabaseError> {
+        let hashed = server.database.tokens.hash(refresh_token);
+        let session = match server.database.storage.find_session_by_used_refresh_token(&hashed)? {
+            Some(session) => session,
+            None => return Ok(()),
+        };
+        server.database.storage.delete_session(&session.id)?;
+        let event = SecurityEvent {
+            session_id: Some(session.id.clone()),
+            ..SecurityEvent::new(
+                &session.user_id,
+                "refresh_token_reuse",
+                "A refresh token was used twice so its session was revoked",
+                client,
+            )
+        };
+        server.database.storage.insert_security_event(&event)?;
+        Err(DatabaseError::InvalidCredentialsError(InvalidCredentialsError::new(
+            "This refresh token has already been used, the session has been revoked",
+        )))
+    }
+
+    pub fn update_user(
+        server: &Server,
+        username: String,
+        user: User,
+    ) -> Result<Option<User>, DatabaseError> {
+        match server.database.storage.update_user(&username, &user) {
+            Ok(()) => Ok(Some(user)),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn generate_object_id() -> Result<String, DatabaseError> {
+        match bson::oid::ObjectId::new() {
+            Ok(oid) => {
+                return Ok(oid.to_hex());
             }
             Err(e) => {
-                return Err(e);
+                return Err(DatabaseError::OIDError(e));
             }
         }
     }
 
-    pub fn exchange_refresh_token(
+    /// Checks the password and opens a new session for `client`. Every login gets its own
+    /// session so signing in on one device leaves the others alone. A password hashed with an
+    /// older algorithm or cost is rehashed while we have it in hand.
+    ///
+    /// Failures are counted per account and per address, see `[auth.lockout]`, and either counter
+    /// being locked turns every attempt away until it runs out. Accounts with TOTP enabled get a
+    /// challenge to finish with `complete_two_factor` instead of a session.
+    pub fn login_user(
         server: &Server,
-        access_token: String,
-        refresh_token: String,
-        username: String,
-    ) -> Result<Option<AccessRecord>, DatabaseError> {
-        let users_collection = server.database.database.collection("users");
-        match DatabaseController::find_user(&users_collection, &username, None) {
-            Ok(user) => {
-                if user.clone().access_record.unwrap().refresh_token.unwrap() == refresh_token
-                    && user.clone().access_record.unwrap().access_token == access_token
-                {
-                    return DatabaseController::update_access_record(
-                        &users_collection,
-                        user.clone(),
-                        Some(AccessRecord::new(user.clone().id)),
-                    );
-                } else {
-                    return Err(DatabaseError::NotFoundError(NotFoundError::new(
-                        "The specified access token was not found!",
-                    )));
-                }
+        request: &LoginRequest,
+        client: &SessionClient,
+    ) -> Result<LoginOutcome, DatabaseError> {
+        let ip_key = client.ip.as_ref().map(|ip| LoginAttempts::ip_key(ip));
+        let user = DatabaseController::login_candidate(server, &request.username, &ip_key)?;
+        if !User::verify_pw(request.password.clone(), user.password.clone())? {
+            return DatabaseController::failed_login(server, &user, &ip_key, client, "Invalid Passoword");
+        }
+        if !user.verify.as_ref().is_some_and(|verify| verify.verified) {
+            return Err(DatabaseError::InvalidCredentialsError(
+                InvalidCredentialsError::new("Account has not yet been verified"),
+            ));
+        }
+        if passwords::needs_rehash(&user.password, &server.database.auth) {
+            let mut user = user.clone();
+            user.password = User::hash_pw(request.password.clone(), &server.database.auth)?;
+            DatabaseController::update_user(server, user.username.clone(), user)?;
+        }
+        DatabaseController::start_session(server, &user, client)
+    }
+
+    /// Emails `request.email` a link that logs in without a password, along with its code for
+    /// typing in instead. The returned link carries the token for the caller like a password reset
+    /// does, `Ok(None)` if the email couldn't be sent. Unknown addresses and repeats are answered
+    /// the same way as in `request_password_reset`.
+    pub fn request_magic_link(
+        server: &Server,
+        request: &MagicLinkRequest,
+        emailer: &Emailer,
+        config: &Configuration,
+    ) -> Result<Option<MagicLink>, DatabaseError> {
+        if !DatabaseController::can_email(server, config)? {
+            return Ok(None);
+        }
+        let magic_link = MagicLink::generate(&server.database.auth);
+        let mut user = match server.database.storage.find_user_by_email(&request.email)? {
+            Some(user) if user.magic_link.as_ref().is_none_or(|link| link.can_resend()) => user,
+            // Answered like a sent link, see `request_password_reset`
+            _ => return Ok(Some(magic_link)),
+        };
+        user.magic_link = Some(magic_link.hashed(&server.database.tokens));
+        DatabaseController::update_user(server, user.username.clone(), user.clone())?;
+        let link = format!(
+            "https://{}/api/v1/sessions/magic_link/confirm?username={}&login_token={}&login_code={}",
+            server.hostname, user.username, magic_link.login_token, magic_link.login_code
+        );
+        let sent = DatabaseController::email_user(
+            server,
+            emailer,
+            config,
+            &user,
+            "Login Link",
+            format!(
+                "<h2><u>Your login code is</u>:<b> {}</b></h2><p><a href=\"{}\">Log in</a></p>",
+                magic_link.login_code, link
+            ),
+            format!("Your login code is: {}\nOr log in at: {}", magic_link.login_code, link),
+        )?;
+        if sent {
+            Ok(Some(magic_link))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Logs in with an emailed link instead of a password. Links are single use, even when the same
+    /// one is sent twice at once, and the lockout and verification rules are the same as for
+    /// `login_user`.
+    pub fn login_with_magic_link(
+        server: &Server,
+        request: &MagicLoginRequest,
+        client: &SessionClient,
+    ) -> Result<LoginOutcome, DatabaseError> {
+        let ip_key = client.ip.as_ref().map(|ip| LoginAttempts::ip_key(ip));
+        let user = DatabaseController::login_candidate(server, &request.username, &ip_key)?;
+        let login_token = match &user.magic_link {
+            Some(link) if link.verify(&request.login_token, &request.login_code, &server.database.tokens) => {
+                link.login_token.clone()
+            }
+            _ => {
+                return DatabaseController::failed_login(server, &user, &ip_key, client, "Invalid or expired login code");
             }
+        };
+        if !server.database.storage.take_magic_link(&user.id, &login_token)? {
+            return Err(DatabaseError::InvalidCredentialsError(
+                InvalidCredentialsError::new("Invalid or expired login code"),
+            ));
+        }
+        if !user.verify.as_ref().is_some_and(|verify| verify.verified) {
+            return Err(DatabaseError::InvalidCredentialsError(
+                InvalidCredentialsError::new("Account has not yet been verified"),
+            ));
+        }
+        DatabaseController::start_session(server, &user, client)
+    }
+
+    /// The account `username` names, as long as neither it nor the address trying it is locked
+    /// out. Unknown usernames count against the address.
+    fn login_candidate(server: &Server, username: &str, ip_key: &Option<String>) -> Result<User, DatabaseError> {
+        if let Some(key) = ip_key {
+            DatabaseController::check_lockout(server, key)?;
+        }
+        let user = match DatabaseController::find_user(server, username, None) {
+            Ok(user) => user,
             Err(e) => {
+                if let Some(key) = ip_key {
+                    DatabaseController::record_login_failure(server, key, server.database.auth.lockout.ip_attempts)?;
+                }
                 return Err(e);
             }
+        };
+        DatabaseController::check_lockout(server, &LoginAttempts::account_key(&user.id))?;
+        Ok(user)
+    }
+
+    /// Counts a wrong password or code against the account and address and fails with `message`,
+    /// noting it in the security log when that locks the account.
+    fn failed_login<T>(
+        server: &Server,
+        user: &User,
+        ip_key: &Option<String>,
+        client: &SessionClient,
+        message: &str,
+    ) -> Result<T, DatabaseError> {
+        let lockout = &server.database.auth.lockout;
+        if let Some(key) = ip_key {
+            DatabaseController::record_login_failure(server, key, lockout.ip_attempts)?;
         }
+        let account_key = LoginAttempts::account_key(&user.id);
+        if let Some(delay) = DatabaseController::record_login_failure(server, &account_key, lockout.account_attempts)? {
+            let details = format!("Too many failed logins, locked for {} seconds", delay);
+            let event = SecurityEvent::new(&user.id, "account_locked", &details, client);
+            server.database.storage.insert_security_event(&event)?;
+            return Err(DatabaseError::InvalidCredentialsError(InvalidCredentialsError::new(&format!(
+                "{}, the account is locked for {} seconds",
+                message, delay
+            ))));
+        }
+        Err(DatabaseError::InvalidCredentialsError(InvalidCredentialsError::new(message)))
     }
 
-    pub fn update_access_record(
-        collection: &Collection,
-        mut user: User,
-        record: Option<AccessRecord>,
-    ) -> Result<Option<AccessRecord>, DatabaseError> {
-        user.access_record = record;
-        match DatabaseController::update_user(collection, user.clone().username, user) {
-            Ok(us) => {
-                if us.is_some() {
-                    return Ok(us.unwrap().access_record);
-                } else {
-                    return Err(DatabaseError::NotFoundError(NotFoundError::new(&format!(
-                        "{} was not found",
-                        us.unwrap().username
-                    ))));
-                }
+    /// Opens a session once the first factor checked out, or a challenge when a second is needed.
+    fn start_session(server: &Server, user: &User, client: &SessionClient) -> Result<LoginOutcome, DatabaseError> {
+        if user.two_factor_enabled() {
+            let challenge = LoginChallenge::new(&user.id, client, &server.database.auth);
+            server.database.storage.insert_login_challenge(&challenge.hashed(&server.database.tokens))?;
+            return Ok(LoginOutcome::TwoFactorRequired(challenge));
+        }
+        DatabaseController::open_session(server, user, client).map(LoginOutcome::Session)
+    }
+
+    /// Issues a session for `user`, which also ends any failed login count against the account.
+    fn open_session(server: &Server, user: &User, client: &SessionClient) -> Result<AccessRecord, DatabaseError> {
+        if user.deletion_scheduled.is_some() {
+            let mut user = user.clone();
+            user.deletion_scheduled = None;
+            DatabaseController::update_user(server, user.username.clone(), user.clone())?;
+            let event = SecurityEvent::new(&user.id, "deletion_cancelled", "Logging in kept the account", client);
+            server.database.storage.insert_security_event(&event)?;
+        }
+        let mut session = AccessRecord::new(user.id.clone(), client, &server.database.auth);
+        DatabaseController::sign_access_token(server, user, &mut session);
+        server.database.storage.insert_session(&session.hashed(&server.database.tokens))?;
+        // Only a finished login clears the failures, a right password alone would let wrong
+        // second factor codes be tried without ever locking
+        server.database.storage.delete_login_attempts(&LoginAttempts::account_key(&user.id))?;
+        Ok(session)
+    }
+
+    /// Second step of a TOTP login. Wrong codes count towards the account lockout like wrong
+    /// passwords do, and the challenge goes away once the account locks.
+    pub fn complete_two_factor(server: &Server, request: &TwoFactorLoginRequest) -> Result<AccessRecord, DatabaseError> {
+        let storage = &server.database.storage;
+        let challenge = match storage.find_login_challenge(&server.database.tokens.hash(&request.challenge_token))? {
+            Some(challenge) if !challenge.is_expired() => challenge,
+            Some(challenge) => {
+                storage.delete_login_challenge(&challenge.id)?;
+                return Err(DatabaseError::InvalidCredentialsError(InvalidCredentialsError::new(
+                    "The login challenge has expired, please login again",
+                )));
             }
-            Err(e) => {
-                return Err(e);
+            None => {
+                return Err(DatabaseError::InvalidCredentialsError(
+                    InvalidCredentialsError::new("Invalid login challenge"),
+                ));
+            }
+        };
+        let mut user = match storage.find_user_by_id(&challenge.user_id)? {
+            Some(user) if user.two_factor.is_some() => user,
+            _ => {
+                return Err(DatabaseError::InvalidCredentialsError(
+                    InvalidCredentialsError::new("Invalid login challenge"),
+                ));
             }
+        };
+        let account_key = LoginAttempts::account_key(&user.id);
+        DatabaseController::check_lockout(server, &account_key)?;
+        let previous = user.two_factor.clone().unwrap();
+        if !DatabaseController::check_second_factor(server, &mut user, &request.code) {
+            let attempts = server.database.auth.lockout.account_attempts;
+            if DatabaseController::record_login_failure(server, &account_key, attempts)?.is_some() {
+                storage.delete_login_challenge(&challenge.id)?;
+            }
+            return Err(DatabaseError::InvalidCredentialsError(
+                InvalidCredentialsError::new("Invalid two factor code"),
+            ));
         }
+        // Remembers the step used or drops the recovery code, unless another login got there first
+        if !storage.swap_two_factor(&user.id, &previous, user.two_factor.as_ref().unwrap())? {
+            return Err(DatabaseError::InvalidCredentialsError(
+                InvalidCredentialsError::new("Invalid two factor code"),
+            ));
+        }
+        storage.delete_login_challenge(&challenge.id)?;
+        DatabaseController::open_session(server, &user, &challenge.client)
     }
 
-    pub fn update_user(
-        collection: &Collection,
-        username: String,
-        user: User,
-    ) -> Result<Option<User>, DatabaseError> {
-        let query = doc! {"username": username};
-        match bson::to_bson(&user) {
-            Ok(bson_object) => {
-                if let bson::Bson::Document(document) = bson_object {
-                    match collection.update_one(query, document, None) {
-                        Ok(_result) => {
-                            return Ok(Some(user));
-                        }
-                        Err(e) => {
-                            return Err(DatabaseError::Error(e));
-                        }
-                    }
+    /// Accepts a current TOTP code or an unused recovery code, updating `user` to match.
+    fn check_second_factor(server: &Server, user: &mut User, code: &str) -> bool {
+        let two_factor = match user.two_factor.as_mut() {
+            Some(two_factor) => two_factor,
+            None => return false,
+        };
+        if let Some(step) = totp::verify(&two_factor.secret, code.trim(), two_factor.last_step) {
+            two_factor.last_step = step;
+            return true;
+        }
+        let code = normalize_recovery_code(code);
+        let tokens = &server.database.tokens;
+        match two_factor.recovery_codes.iter().position(|stored| tokens.check(&code, stored, true)) {
+            Some(used) => {
+                two_factor.recovery_codes.remove(used);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Starts TOTP enrollment with a new secret and recovery codes. Nothing changes at login until
+    /// `confirm_two_factor` sees a first code.
+    pub fn begin_two_factor(server: &Server, user: &User) -> Result<TwoFactorEnrollment, DatabaseError> {
+        if user.two_factor_enabled() {
+            return Err(DatabaseError::AlreadyExistsError(AlreadyExistsError::new(
+                "Two factor authentication is already enabled",
+            )));
+        }
+        let secret = totp::generate_secret();
+        let recovery_codes: Vec<String> = (0..RECOVERY_CODES)
+            .map(|_| {
+                thread_rng()
+                    .sample_iter(&Alphanumeric)
+                    .take(10)
+                    .collect::<String>()
+                    .to_lowercase()
+            })
+            .collect();
+        let mut user = user.clone();
+        user.two_factor = Some(TwoFactor {
+            secret: secret.clone(),
+            enabled: false,
+            recovery_codes: recovery_codes.iter().map(|code| server.database.tokens.hash(code)).collect(),
+            last_step: 0,
+        });
+        DatabaseController::update_user(server, user.username.clone(), user.clone())?;
+        Ok(TwoFactorEnrollment {
+            provisioning_uri: totp::provisioning_uri(&server.database.auth.totp_issuer, &user.username, &secret),
+            secret,
+            recovery_codes,
+        })
+    }
+
+    pub fn confirm_two_factor(server: &Server, user: &User, code: &str) -> Result<User, DatabaseError> {
+        let mut user = user.clone();
+        let two_factor = match user.two_factor.as_mut() {
+            Some(two_factor) if !two_factor.enabled => two_factor,
+            Some(_two_factor) => {
+                return Err(DatabaseError::AlreadyExistsError(AlreadyExistsError::new(
+                    "Two factor authentication is already enabled",
+                )));
+            }
+            None => {
+                return Err(DatabaseError::NotFoundError(NotFoundError::new(
+                    "Two factor authentication has not been set up",
+                )));
+            }
+        };
+        match totp::verify(&two_factor.secret, code.trim(), two_factor.last_step) {
+            Some(step) => {
+                two_factor.enabled = true;
+                two_factor.last_step = step;
+            }
+            None => {
+                return Err(DatabaseError::InvalidCredentialsError(
+                    InvalidCredentialsError::new("Invalid two factor code"),
+                ));
+            }
+        }
+        DatabaseController::update_user(server, user.username.clone(), user.clone())?;
+        let event = SecurityEvent::new(&user.id, "two_factor_enabled", "TOTP was enabled", &SessionClient::default());
+        server.database.storage.insert_security_event(&event)?;
+        Ok(user)
+    }
+
+    /// Turns TOTP off, or abandons an enrollment, once the password is confirmed.
+    pub fn disable_two_factor(
+        server: &Server,
+        user: &User,
+        password: &str,
+        client: &SessionClient,
+    ) -> Result<User, DatabaseError> {
+        DatabaseController::check_current_password(server, user, password, client)?;
+        let mut user = user.clone();
+        user.two_factor = None;
+        DatabaseController::update_user(server, user.username.clone(), user.clone())?;
+        let event = SecurityEvent::new(&user.id, "two_factor_disabled", "TOTP was disabled", client);
+        server.database.storage.insert_security_event(&event)?;
+        Ok(user)
+    }
+
+    /// Checks the password a logged in user confirms a sensitive change with. Wrong ones count
+    /// towards the same lockout as failed logins.
+    fn check_current_password(
+        server: &Server,
+        user: &User,
+        password: &str,
+        client: &SessionClient,
+    ) -> Result<(), DatabaseError> {
+        let ip_key = client.ip.as_ref().map(|ip| LoginAttempts::ip_key(ip));
+        if let Some(key) = &ip_key {
+            DatabaseController::check_lockout(server, key)?;
+        }
+        DatabaseController::check_lockout(server, &LoginAttempts::account_key(&user.id))?;
+        if !User::verify_pw(password.to_string(), user.password.clone())? {
+            return DatabaseController::failed_login(server, user, &ip_key, client, "Invalid Passoword");
+        }
+        Ok(())
+    }
+
+    /// Turns the attempt away while the counter under `key` is locked.
+    fn check_lockout(server: &Server, key: &str) -> Result<(), DatabaseError> {
+        match server.database.storage.find_login_attempts(key)?.and_then(|attempts| attempts.locked_for()) {
+            Some(seconds) => Err(DatabaseError::RateLimitedError(RateLimitedError::new(&format!(
+                "Too many failed logins, try again in {} seconds",
+                seconds
+            )))),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns how long the failure locked the counter for, if it did.
+    fn record_login_failure(server: &Server, key: &str, attempts: u32) -> Result<Option<u64>, DatabaseError> {
+        let storage = &server.database.storage;
+        let policy = &server.database.auth.lockout;
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        let stale_before = now.saturating_sub(policy.reset_after as u128 * 1000);
+        let record = storage.add_login_failure(key, &now.to_string(), &stale_before.to_string())?;
+        match record.lock_delay(attempts, policy) {
+            Some(delay) => {
+                storage.lock_login_attempts(key, &(now + delay as u128 * 1000).to_string())?;
+                Ok(Some(delay))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn social_provider<'a>(config: &'a Configuration, name: &str) -> Result<&'a SocialProvider, DatabaseError> {
+        match config.social.providers.iter().find(|provider| provider.name == name) {
+            Some(provider) => Ok(provider),
+            None => Err(DatabaseError::NotFoundError(NotFoundError::new(&format!(
+                "{} is not a sign in provider",
+                name
+            )))),
+        }
+    }
+
+    /// Starts a social login, returning the provider page to send the user to. Every start gets
+    /// its own state and PKCE verifier.
+    pub fn start_social_login(server: &Server, config: &Configuration, provider: &str) -> Result<String, DatabaseError> {
+        let provider = DatabaseController::social_provider(config, provider)?;
+        let (url, state, pkce_verifier) = social::authorization_url(provider, &config.server.hostname)?;
+        let tokens = &server.database.tokens;
+        let pending = OauthState::new(&state, &provider.name, &pkce_verifier, &server.database.auth, tokens);
+        server.database.storage.insert_oauth_state(&pending)?;
+        Ok(url.into_string())
+    }
+
+    /// Finishes a social login when the provider sends the user back. The state is single use and
+    /// only good for the provider it was issued for.
+    pub fn finish_social_login(
+        server: &Server,
+        config: &Configuration,
+        provider: &str,
+        request: &SocialCallbackQuery,
+        client: &SessionClient,
+    ) -> Result<LoginOutcome, DatabaseError> {
+        let provider = DatabaseController::social_provider(config, provider)?;
+        if let Some(error) = &request.error {
+            return Err(DatabaseError::InvalidCredentialsError(InvalidCredentialsError::new(&format!(
+                "{} sign in was not completed: {}",
+                provider.name, error
+            ))));
+        }
+        let storage = &server.database.storage;
+        let pending = match storage.find_oauth_state(&server.database.tokens.hash(&request.state))? {
+            Some(pending) if pending.provider == provider.name => pending,
+            _ => return Err(DatabaseController::invalid_oauth_state()),
+        };
+        storage.delete_oauth_state(&pending.id)?;
+        if pending.is_expired() {
+            return Err(DatabaseController::invalid_oauth_state());
+        }
+        let access_token = social::exchange_code(provider, &config.server.hostname, &request.code, &pending.pkce_verifier)?;
+        let profile = social::fetch_profile(provider, &access_token)?;
+        let user = DatabaseController::social_user(server, config, provider, &profile, client)?;
+        DatabaseController::check_lockout(server, &LoginAttempts::account_key(&user.id))?;
+        DatabaseController::start_session(server, &user, client)
+    }
+
+    fn invalid_oauth_state() -> DatabaseError {
+        DatabaseError::InvalidCredentialsError(InvalidCredentialsError::new(
+            "Invalid or expired sign in state, please start again",
+        ))
+    }
+
+    /// The user a social profile signs in as: whoever it's already linked to, else the account
+    /// with the same email, else a new account. Emails the provider hasn't verified are never
+    /// trusted for either of the last two.
+    fn social_user(
+        server: &Server,
+        config: &Configuration,
+        provider: &SocialProvider,
+        profile: &SocialProfile,
+        client: &SessionClient,
+    ) -> Result<User, DatabaseError> {
+        let storage = &server.database.storage;
+        if let Some(user) = storage.find_user_by_linked_account(&provider.name, &profile.subject)? {
+            return Ok(user);
+        }
+        if !profile.email_verified {
+            return Err(DatabaseError::InvalidCredentialsError(InvalidCredentialsError::new(&format!(
+                "{} has not verified {}",
+                provider.name, profile.email
+            ))));
+        }
+        let link = LinkedAccount {
+            provider: provider.name.clone(),
+            subject: profile.subject.clone(),
+        };
+        if let Some(mut user) = storage.find_user_by_email(&profile.email)? {
+            let mut details = format!("Linked to a {} account", provider.name);
+            if !user.verify.as_ref().is_some_and(|verify| verify.verified) {
+                // Whoever registered the address never proved they own it, while the provider just
+                // did. Their password and sessions go so a squatter can't share the account.
+                user.password = User::hash_pw(DatabaseController::random_password(), &server.database.auth)?;
+                user.two_factor = None;
+                user.password_reset = None;
+                user.magic_link = None;
+                user.email_change = None;
+                user.linked_accounts.clear();
+                let mut verified = match user.verify.take() {
+                    Some(verified) => verified,
+                    None => Verified::new(&server.database.auth).hashed(&server.database.tokens),
+                };
+                verified.confirm();
+                user.verify = Some(verified);
+                DatabaseController::revoke_sessions(server, &user)?;
+                details = format!("{}, the unverified password was removed", details);
+            }
+            user.linked_accounts.push(link);
+            DatabaseController::update_user(server, user.username.clone(), user.clone())?;
+            let event = SecurityEvent::new(&user.id, "account_linked", &details, client);
+            storage.insert_security_event(&event)?;
+            return Ok(user);
+        }
+        let id = match DatabaseController::add_object(server, "user")? {
+            Some(id) => id,
+            None => {
+                return Err(DatabaseError::NotFoundError(NotFoundError::new(
+                    "No object was created for the user",
+                )));
+            }
+        };
+        let mut verified = Verified::new(&server.database.auth).hashed(&server.database.tokens);
+        verified.confirm();
+        // Nobody knows this password, a password reset is the way to set one
+        let mut user = User::new(
+            id,
+            DatabaseController::available_username(server, &profile.email)?,
+            DatabaseController::random_password(),
+            profile.email.clone(),
+            config.roles.default.clone(),
+            Some(verified),
+            None,
+            None,
+            None,
+            None,
+            &server.database.auth,
+        )?;
+        user.linked_accounts.push(link);
+        storage.insert_user(&user)?;
+        Ok(user)
+    }
+
+    /// A password nobody knows, for accounts that only sign in through a provider.
+    fn random_password() -> String {
+        thread_rng().sample_iter(&Alphanumeric).take(64).collect()
+    }
+
+    /// A free username based on the local part of `email`.
+    fn available_username(server: &Server, email: &str) -> Result<String, DatabaseError> {
+        let mut base: String = email
+            .split('@')
+            .next()
+            .unwrap_or("")
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '-' || *c == '.')
+            .take(24)
+            .collect();
+        if base.len() < 3 {
+            base = "user".to_string();
+        }
+        let mut username = base.clone();
+        while server.database.storage.find_user(&username, None)?.is_some() {
+            username = format!("{}{}", base, thread_rng().gen_range(1000, 10000));
+        }
+        Ok(username)
+    }
+
+    /// Clears the failed login counter of `target` so they can try again straight away.
+    pub fn unlock_user(
+        server: &Server,
+        admin: &Principal,
+        target: &str,
+        config: &Configuration,
+    ) -> Result<User, DatabaseError> {
+        config.roles.require(admin, "manage_users")?;
+        let user = DatabaseController::find_user(server, target, None)?;
+        server.database.storage.delete_login_attempts(&LoginAttempts::account_key(&user.id))?;
+        let details = format!("Unlocked by the administrator {}", admin.id);
+        let event = SecurityEvent::new(&user.id, "account_unlocked", &details, &SessionClient::default());
+        server.database.storage.insert_security_event(&event)?;
+        Ok(user)
+    }
+
+    pub fn authenticate(
+        server: &Server,
+        username: &str,
+        access_token: &str,
+    ) -> Result<User, DatabaseError> {
+        DatabaseController::authenticate_session(server, username, access_token).map(|(user, _session)| user)
+    }
+
+    /// Like `find_session` but the token also has to belong to `username`.
+    pub fn authenticate_session(
+        server: &Server,
+        username: &str,
+        access_token: &str,
+    ) -> Result<(User, AccessRecord), DatabaseError> {
+        match DatabaseController::find_session(server, access_token) {
+            Ok((user, session)) if user.username == username => Ok((user, session)),
+            Ok(_) => Err(DatabaseError::InvalidCredentialsError(
+                InvalidCredentialsError::new("Invalid username or access token"),
+            )),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Swaps the session's random access token for a signed one when signing is enabled. The
+    /// session still keeps its hash so refreshing checks it the same way either way.
+    fn sign_access_token(server: &Server, user: &User, session: &mut AccessRecord) {
+        if let Some(signer) = &server.database.signer {
+            let expires = session.expires.parse::<u128>().unwrap_or(0) / 1000;
+            let now = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            session.access_token = signer.sign(&Claims {
+                sub: user.id.clone(),
+                sid: session.id.clone(),
+                access_level: user.access_level.clone(),
+                iat: now,
+                exp: expires as u64,
+            });
+        }
+    }
+
+    /// Who `access_token` speaks for. Signed tokens are checked against their signature alone and
+    /// answered from their claims without a lookup, so they keep their access level and stay
+    /// valid until they expire even if the user or their session changes sooner.
+    pub fn find_principal(server: &Server, access_token: &str) -> Result<Principal, DatabaseError> {
+        match &server.database.signer {
+            Some(signer) => signer.verify(access_token).map(|claims| Principal {
+                id: claims.sub,
+                access_level: claims.access_level,
+            }),
+            None => {
+                DatabaseController::find_session(server, access_token).map(|(user, _session)| Principal::from(&user))
+            }
+        }
+    }
+
+    /// The user behind `access_token`, for requests that read or change the account itself. Unlike
+    /// `find_principal` a signed token also needs its session to still be open, so logging out or
+    /// revoking sessions takes these routes away from a stolen token straight away.
+    pub fn find_user_by_access_token(server: &Server, access_token: &str) -> Result<User, DatabaseError> {
+        match &server.database.signer {
+            Some(signer) => {
+                let claims = signer.verify(access_token)?;
+                let sessions = server.database.storage.list_sessions(&claims.sub)?;
+                let user = if sessions.iter().any(|session| session.id == claims.sid) {
+                    server.database.storage.find_user_by_id(&claims.sub)?
                 } else {
-                    return Ok(None);
+                    None
+                };
+                match user {
+                    Some(user) => Ok(user),
+                    None => Err(DatabaseError::InvalidCredentialsError(
+                        InvalidCredentialsError::new("Invalid access token"),
+                    )),
                 }
             }
-            Err(e) => {
-                return Err(DatabaseError::EncoderError(e));
+            None => DatabaseController::find_session(server, access_token).map(|(user, _session)| user),
+        }
+    }
+
+    /// Looks a session up by one of its tokens through `find`. Sessions stored before tokens were
+    /// hashed are matched on the plaintext and rewritten hashed on the way through.
+    fn find_hashed_session<F>(server: &Server, token: &str, find: F) -> Result<Option<AccessRecord>, DatabaseError>
+    where
+        F: Fn(&dyn Storage, &str) -> Result<Option<AccessRecord>, DatabaseError>,
+    {
+        let tokens = &server.database.tokens;
+        let storage = server.database.storage.as_ref();
+        if let Some(session) = find(storage, &tokens.hash(token))? {
+            if session.hashed {
+                return Ok(Some(session));
+            }
+        }
+        match find(storage, token)? {
+            // A hashed record is never matched on its stored value, or the hash would work as a token
+            Some(session) if !session.hashed => {
+                let session = session.hashed(tokens);
+                storage.replace_session(&session)?;
+                Ok(Some(session))
             }
+            _ => Ok(None),
         }
     }
 
-    pub fn generate_object_id() -> Result<String, DatabaseError> {
-        match bson::oid::ObjectId::new() {
-            Ok(oid) => {
-                return Ok(oid.to_hex());
+    /// The live session `access_token` belongs to, along with its user.
+    pub fn find_session(server: &Server, access_token: &str) -> Result<(User, AccessRecord), DatabaseError> {
+        let found = DatabaseController::find_hashed_session(server, access_token, |storage, token| {
+            storage.find_session_by_access_token(token)
+        });
+        let session = match found {
+            Ok(Some(session)) if !session.is_expired() => session,
+            Ok(Some(_session)) => {
+                return Err(DatabaseError::InvalidCredentialsError(
+                    InvalidCredentialsError::new("The access token has expired"),
+                ));
             }
-            Err(e) => {
-                return Err(DatabaseError::OIDError(e));
+            Ok(None) => {
+                return Err(DatabaseError::InvalidCredentialsError(
+                    InvalidCredentialsError::new("Invalid access token"),
+                ));
             }
+            Err(e) => return Err(e),
+        };
+        match server.database.storage.find_user_by_id(&session.user_id) {
+            Ok(Some(user)) => Ok((user, session)),
+            Ok(None) => Err(DatabaseError::InvalidCredentialsError(
+                InvalidCredentialsError::new("Invalid access token"),
+            )),
+            Err(e) => Err(e),
         }
     }
 
-    pub fn login_user(
+    fn parse_post_status(status: &str) -> Result<PostStatus, DatabaseError> {
+        match PostStatus::parse(status) {
+            Some(status) => Ok(status),
+            None => Err(requests::invalid_field(
+                "status",
+                "status",
+                format!("{} is not a valid post status", status),
+            )),
+        }
+    }
+
+    pub fn find_post(server: &Server, id: Option<&str>, slug: Option<&str>) -> Result<Post, DatabaseError> {
+        match server.database.storage.find_post(id, slug) {
+            Ok(Some(post)) => Ok(post),
+            Ok(None) => Err(DatabaseError::NotFoundError(NotFoundError::new(
+                "The requested post was not found",
+            ))),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn save_post(server: &Server, post: &Post, insert: bool) -> Result<Post, DatabaseError> {
+        let result = if insert {
+            server.database.storage.insert_post(post)
+        } else {
+            server.database.storage.replace_post(post)
+        };
+        match result {
+            Ok(()) => Ok(post.clone()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn slug_available(server: &Server, slug: &str) -> Result<(), DatabaseError> {
+        match server.database.storage.find_post(None, Some(slug)) {
+            Ok(None) => Ok(()),
+            Ok(Some(_)) => Err(DatabaseError::AlreadyExistsError(AlreadyExistsError::new(
+                &format!("There is already a post with the slug {}", slug),
+            ))),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn create_post(
         server: &Server,
-        data: &HashMap<String, String>,
-    ) -> Result<Option<AccessRecord>, DatabaseError> {
-        let username = data.get("username").unwrap().to_string();
-        let password = data.get("password").unwrap().to_string();
-        let users_collection = server.database.database.collection("users");
-        match DatabaseController::find_user(&users_collection, &username, None) {
-            Ok(user) => match User::verify_pw(password, user.clone().password) {
-                Ok(res) => {
-                    if user.clone().verify.unwrap().verified {
-                        if res {
-                            match DatabaseController::get_acccess_record(server, username) {
-                                Ok(acc) => {
-                                    if SystemTime::now()
-                                        .duration_since(SystemTime::UNIX_EPOCH)
-                                        .unwrap()
-                                        .as_millis()
-                                        < acc.clone().unwrap().expires.parse::<u128>().unwrap()
-                                    {
-                                        return Ok(Some(acc.unwrap()));
-                                    } else {
-                                        let access = AccessRecord::new(user.clone().id);
-                                        return DatabaseController::add_access_record(
-                                            &users_collection,
-                                            user,
-                                            Some(access),
-                                        );
-                                    }
-                                }
-                                Err(_e) => {
-                                    let access = AccessRecord::new(user.clone().id);
-                                    return DatabaseController::add_access_record(
-                                        &users_collection,
-                                        user,
-                                        Some(access),
-                                    );
-                                }
-                            }
-                        } else {
-                            return Err(DatabaseError::InvalidCredentialsError(
-                                InvalidCredentialsError::new("Invalid Passoword"),
-                            ));
-                        }
-                    } else {
-                        return Err(DatabaseError::InvalidCredentialsError(
-                            InvalidCredentialsError::new("Account has not yet been verified"),
-                        ));
-                    }
+        user: &Principal,
+        request: &CreatePostRequest,
+        config: &Configuration,
+    ) -> Result<Option<Post>, DatabaseError> {
+        let title = request.title.clone();
+        let body = request.body.clone();
+        let slug = match request.slug {
+            Some(ref slug) => Post::slugify(slug),
+            None => Post::slugify(&title),
+        };
+        if slug.is_empty() {
+            return Err(requests::invalid_field(
+                "slug",
+                "slug",
+                "A post needs a title or slug with letters or numbers".to_string(),
+            ));
+        }
+        let status = match request.status {
+            Some(ref status) => DatabaseController::parse_post_status(status)?,
+            None => PostStatus::Draft,
+        };
+
+        config.roles.require(user, "create_post")?;
+        DatabaseController::slug_available(server, &slug)?;
+        match DatabaseController::add_object(server, "post") {
+            Ok(Some(id)) => {
+                let post = Post::new(id, user.id.clone(), title, slug, body, status);
+                DatabaseController::save_post(server, &post, true).map(Some)
+            }
+            Ok(None) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Looks a post up by `id` or `slug`. Drafts are only visible to their author and editors,
+    /// everyone else is told it doesn't exist.
+    pub fn get_post(
+        server: &Server,
+        viewer: Option<&Principal>,
+        lookup: &PostLookup,
+        config: &Configuration,
+    ) -> Result<Post, DatabaseError> {
+        if lookup.id.is_none() && lookup.slug.is_none() {
+            return Err(requests::invalid_field("id", "required", "Missing id or slug field".to_string()));
+        }
+        let post = DatabaseController::find_post(server, lookup.id.as_deref(), lookup.slug.as_deref())?;
+        if post.status == PostStatus::Published {
+            return Ok(post);
+        }
+        if let Some(user) = viewer {
+            if user.id == post.author_id || config.roles.permits(&user.access_level, "edit_any_post") {
+                return Ok(post);
+            }
+        }
+        Err(DatabaseError::NotFoundError(NotFoundError::new(
+            "The requested post was not found",
+        )))
+    }
+
+    fn owned_post(
+        server: &Server,
+        user: &Principal,
+        id: &str,
+        config: &Configuration,
+    ) -> Result<Post, DatabaseError> {
+        let post = DatabaseController::find_post(server, Some(id), None)?;
+        if post.author_id == user.id {
+            Ok(post)
+        } else {
+            config.roles.require(user, "edit_any_post").map(|_| post)
+        }
+    }
+
+    pub fn update_post(
+        server: &Server,
+        user: &Principal,
+        id: &str,
+        request: &UpdatePostRequest,
+        config: &Configuration,
+    ) -> Result<Post, DatabaseError> {
+        let mut post = DatabaseController::owned_post(server, user, id, config)?;
+        if let Some(ref title) = request.title {
+            post.title = title.to_string();
+        }
+        if let Some(ref body) = request.body {
+            post.body = body.to_string();
+        }
+        if let Some(ref slug) = request.slug {
+            let slug = Post::slugify(slug);
+            if slug.is_empty() {
+                return Err(requests::invalid_field(
+                    "slug",
+                    "slug",
+                    "A slug needs letters or numbers".to_string(),
+                ));
+            }
+            if slug != post.slug {
+                DatabaseController::slug_available(server, &slug)?;
+                post.slug = slug;
+            }
+        }
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis()
+            .to_string();
+        if let Some(ref status) = request.status {
+            let status = DatabaseController::parse_post_status(status)?;
+            if status == PostStatus::Published && post.publish_time.is_none() {
+                post.publish_time = Some(now.clone());
+            }
+            post.status = status;
+        }
+        post.update_time = now;
+        DatabaseController::save_post(server, &post, false)
+    }
+
+    pub fn delete_post(
+        server: &Server,
+        user: &Principal,
+        id: &str,
+        config: &Configuration,
+    ) -> Result<Post, DatabaseError> {
+        let post = DatabaseController::owned_post(server, user, id, config)?;
+        server.database.storage.delete_post(&post.id)?;
+        DatabaseController::remove_object(server, &post.id)?;
+        Ok(post)
+    }
+
+    /// Lists published posts, optionally filtered by `author`. Passing `mine` lists that user's
+    /// own posts instead, including drafts, optionally filtered by `status`.
+    pub fn list_posts(
+        server: &Server,
+        mine: Option<&Principal>,
+        query: &ListPostsQuery,
+    ) -> Result<Vec<Post>, DatabaseError> {
+        let mut filter = PostFilter::default();
+        match mine {
+            Some(user) => {
+                filter.author_id = Some(user.id.clone());
+                if let Some(ref status) = query.status {
+                    filter.status = Some(DatabaseController::parse_post_status(status)?);
                 }
-                Err(e) => {
-                    return Err(DatabaseError::BcryptError(e));
+            }
+            None => {
+                filter.status = Some(PostStatus::Published);
+                if let Some(ref author) = query.author {
+                    let user = DatabaseController::find_user(server, author, None)?;
+                    filter.author_id = Some(user.id);
                 }
-            },
-            Err(e) => {
-                return Err(e);
             }
         }
+        let limit = match query.limit {
+            Some(limit) if limit > 0 => limit.min(100),
+            _ => 20,
+        };
+        let skip = match query.skip {
+            Some(skip) if skip > 0 => skip,
+            _ => 0,
+        };
+        server.database.storage.list_posts(&filter, skip, limit)
+    }
+
+    pub fn add_media(server: &Server, media: &Media) -> Result<Media, DatabaseError> {
+        match server.database.storage.insert_media(media) {
+            Ok(()) => Ok(media.clone()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn find_media(server: &Server, id: &str) -> Result<Media, DatabaseError> {
+        match server.database.storage.find_media(id) {
+            Ok(Some(media)) => Ok(media),
+            Ok(None) => Err(DatabaseError::NotFoundError(NotFoundError::new(
+                "The requested media was not found",
+            ))),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn remove_object(server: &Server, id: &str) -> Result<(), DatabaseError> {
+        server.database.storage.remove_object(id)
+    }
+
+    pub fn delete_media(
+        server: &Server,
+        user: &Principal,
+        id: &str,
+        config: &Configuration,
+    ) -> Result<Media, DatabaseError> {
+        let media = DatabaseController::find_media(server, id)?;
+        if media.owner_id != user.id {
+            config.roles.require(user, "manage_media")?;
+        }
+        server.database.storage.delete_media(&media.id)?;
+        DatabaseController::remove_object(server, &media.id)?;
+        // The record is gone either way, a missing file shouldn't fail the request
+        let _ = fs::remove_file(Path::new(&config.media.storage_dir).join(&media.id));
+        Ok(media)
+    }
+
+    pub fn list_media(server: &Server, user: &Principal) -> Result<Vec<Media>, DatabaseError> {
+        server.database.storage.list_media(&user.id)
+    }
+
+    pub fn set_access_level(
+        server: &Server,
+        admin: &Principal,
+        target: &str,
+        access_level: &str,
+        config: &Configuration,
+    ) -> Result<User, DatabaseError> {
+        config.roles.require(admin, "manage_users")?;
+        if config.roles.rank(access_level).is_none() {
+            return Err(requests::invalid_field(
+                "access_level",
+                "access_level",
+                format!("{} is not a valid access level", access_level),
+            ));
+        }
+        let mut user = DatabaseController::find_user(server, target, None)?;
+        // Stops the last admin from accidentally locking everyone out
+        if admin.id == user.id {
+            return Err(DatabaseError::PermissionDeniedError(PermissionDeniedError::new(
+                "You can't change your own access level",
+            )));
+        }
+        user.access_level = access_level.to_string();
+        match DatabaseController::update_user(server, user.clone().username, user) {
+            Ok(Some(user)) => Ok(user),
+            Ok(None) => Err(DatabaseError::NotFoundError(NotFoundError::new(&format!(
+                "{} was not found",
+                target
+            )))),
+            Err(e) => Err(e),
+        }
     }
 }
@@ -1,11 +1,15 @@
 use std::error;
 use std::fmt;
+use std::io;
 use bcrypt::{BcryptError};
 use std::time::{SystemTimeError};
 use config::{ConfigError};
 use mongodb::{error::Error};
-use bson::{EncoderError, oid};
+use bson::{DecoderError, EncoderError, oid};
 use serde::{Deserialize, Serialize};
+use tokio::task::JoinError;
+use validator::ValidationErrors;
+use warp::reject::Reject;
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct InvalidCredentialsError{
@@ -81,17 +85,72 @@ impl AlreadyExistsError{
     }
 }
 
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct PermissionDeniedError {
+    pub details: String,
+    pub code: u32,
+}
+
+impl fmt::Display for PermissionDeniedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.details)
+    }
+}
+
+impl error::Error for PermissionDeniedError  {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        None
+    }
+}
+
+impl PermissionDeniedError{
+    pub fn new(msg: &str) -> PermissionDeniedError {
+        PermissionDeniedError {details: msg.to_string(), code: 4}
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RateLimitedError {
+    pub details: String,
+    pub code: u32,
+}
+
+impl fmt::Display for RateLimitedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.details)
+    }
+}
+
+impl error::Error for RateLimitedError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        None
+    }
+}
+
+impl RateLimitedError {
+    pub fn new(msg: &str) -> RateLimitedError {
+        RateLimitedError {details: msg.to_string(), code: 9}
+    }
+}
+
 #[derive(Debug)]
 pub enum DatabaseError {
     Error(Error),
     ConfigError(ConfigError),
     BcryptError(BcryptError),
+    Argon2Error(argon2::Error),
     SystemTimeError(SystemTimeError),
     EncoderError(EncoderError),
+    DecoderError(DecoderError),
     OIDError(oid::Error),
     InvalidCredentialsError(InvalidCredentialsError),
     NotFoundError(NotFoundError),
-    AlreadyExistsError(AlreadyExistsError)
+    AlreadyExistsError(AlreadyExistsError),
+    PermissionDeniedError(PermissionDeniedError),
+    RateLimitedError(RateLimitedError),
+    ValidationError(ValidationErrors),
+    IOError(io::Error),
+    TaskError(JoinError)
 }
 
 impl fmt::Display for DatabaseError {
@@ -100,12 +159,19 @@ impl fmt::Display for DatabaseError {
             DatabaseError::Error(ref e) => e.fmt(f),
             DatabaseError::ConfigError(ref e) => e.fmt(f),
             DatabaseError::BcryptError(ref e) => e.fmt(f),
+            DatabaseError::Argon2Error(ref e) => e.fmt(f),
             DatabaseError::SystemTimeError(ref e) => e.fmt(f),
             DatabaseError::EncoderError(ref e) => e.fmt(f),
+            DatabaseError::DecoderError(ref e) => e.fmt(f),
             DatabaseError::OIDError(ref e) => e.fmt(f),
             DatabaseError::InvalidCredentialsError(ref e) => e.fmt(f),
             DatabaseError::NotFoundError(ref e) => e.fmt(f),
             DatabaseError::AlreadyExistsError(ref e) => e.fmt(f),
+            DatabaseError::PermissionDeniedError(ref e) => e.fmt(f),
+            DatabaseError::RateLimitedError(ref e) => e.fmt(f),
+            DatabaseError::ValidationError(ref e) => e.fmt(f),
+            DatabaseError::IOError(ref e) => e.fmt(f),
+            DatabaseError::TaskError(ref e) => e.fmt(f),
         }
     }
 }
@@ -116,12 +182,41 @@ impl error::Error for DatabaseError {
             DatabaseError::Error(ref e) => Some(e),
             DatabaseError::ConfigError(ref e) => Some(e),
             DatabaseError::BcryptError(ref e) => Some(e),
+            DatabaseError::Argon2Error(ref e) => Some(e),
             DatabaseError::SystemTimeError(ref e) => Some(e),
             DatabaseError::EncoderError(ref e) => Some(e),
+            DatabaseError::DecoderError(ref e) => Some(e),
             DatabaseError::OIDError(ref e) => Some(e),
             DatabaseError::InvalidCredentialsError(ref e) => Some(e),
             DatabaseError::NotFoundError(ref e) => Some(e),
             DatabaseError::AlreadyExistsError(ref e) => Some(e),
+            DatabaseError::PermissionDeniedError(ref e) => Some(e),
+            DatabaseError::RateLimitedError(ref e) => Some(e),
+            DatabaseError::ValidationError(ref e) => Some(e),
+            DatabaseError::IOError(ref e) => Some(e),
+            DatabaseError::TaskError(ref e) => Some(e),
         }
     }
 }
+
+impl DatabaseError {
+    /// Machine readable code sent to clients. Variants without a `code` field of their own are
+    /// internal failures and all share `0` so nothing about them leaks out.
+    pub fn code(&self) -> u32 {
+        match *self {
+            DatabaseError::InvalidCredentialsError(ref e) => e.code,
+            DatabaseError::NotFoundError(ref e) => e.code,
+            DatabaseError::AlreadyExistsError(ref e) => e.code,
+            DatabaseError::PermissionDeniedError(ref e) => e.code,
+            DatabaseError::RateLimitedError(ref e) => e.code,
+            DatabaseError::ValidationError(_) => 5,
+            _ => 0,
+        }
+    }
+
+    pub fn is_internal(&self) -> bool {
+        self.code() == 0
+    }
+}
+
+impl Reject for DatabaseError {}
@@ -1,8 +1,11 @@
-use bcrypt::{hash, verify, BcryptError};
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
 use serde::{Deserialize, Serialize};
 use std::time::SystemTime;
+use super::configuration::{AuthConfig, LockoutPolicy};
+use super::database_errors::DatabaseError;
+use super::passwords;
+use super::tokens::TokenHasher;
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
 pub struct Object {
@@ -18,43 +21,239 @@ pub struct Verified {
     pub verify_code: String,
     pub verify_time: Option<String>,
     pub expiration_time: String,
+    pub sent_time: Option<String>,
+    /// Whether the token and code are stored as `TokenHasher` hashes. Only records written
+    /// before hashing was introduced hold them in plaintext.
+    #[serde(default)]
+    pub hashed: bool,
+}
+
+/// A code to email, drawn from `code_alphabet`. An empty alphabet falls back to alphanumerics.
+fn generate_code(auth: &AuthConfig) -> String {
+    let alphabet: Vec<char> = auth.code_alphabet.chars().collect();
+    if alphabet.is_empty() {
+        return thread_rng().sample_iter(&Alphanumeric).take(auth.code_length).collect();
+    }
+    let mut rng = thread_rng();
+    (0..auth.code_length)
+        .map(|_| alphabet[rng.gen_range(0, alphabet.len())])
+        .collect()
+}
+
+/// How long a user has to wait between emailed codes of one kind.
+pub const RESEND_INTERVAL: u128 = 60000;
+
+/// Whether `RESEND_INTERVAL` has passed since `sent_time`, or nothing was sent yet.
+fn resend_due(sent_time: Option<&String>) -> bool {
+    let current_time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+    match sent_time.and_then(|sent_time| sent_time.parse::<u128>().ok()) {
+        Some(sent_time) => sent_time + RESEND_INTERVAL <= current_time,
+        None => true,
+    }
 }
 
 impl Verified {
-    pub fn new() -> Self {
+    pub fn new(auth: &AuthConfig) -> Self {
         let creation_time = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
             .unwrap()
             .as_millis();
-        let expiration = creation_time + 86400000;
+        let expiration = creation_time + auth.verification_ttl as u128 * 1000;
         return Verified {
             verified: false,
             verify_token: thread_rng().sample_iter(&Alphanumeric).take(256).collect(),
-            verify_code: thread_rng().sample_iter(&Alphanumeric).take(6).collect(),
+            verify_code: generate_code(auth),
             verify_time: None,
             expiration_time: expiration.to_string(),
+            sent_time: Some(creation_time.to_string()),
+            hashed: false,
         };
     }
 
-    pub fn verify(verifier: &User, verify_token: String, verify_code: String) -> bool {
+    /// The copy that gets stored, with the token and code replaced by their hashes.
+    pub fn hashed(&self, tokens: &TokenHasher) -> Self {
+        if self.hashed {
+            return self.clone();
+        }
+        Verified {
+            verify_token: tokens.hash(&self.verify_token),
+            verify_code: tokens.hash(&self.verify_code),
+            hashed: true,
+            ..self.clone()
+        }
+    }
+
+    /// Marks the email verified as of now, for when something other than the code proved it.
+    pub fn confirm(&mut self) {
+        self.verified = true;
+        self.verify_time = Some(
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_millis()
+                .to_string(),
+        );
+    }
+
+    /// Whether enough time has passed since the last code went out to send another one.
+    pub fn can_resend(&self) -> bool {
+        resend_due(self.sent_time.as_ref())
+    }
+
+    pub fn verify(verifier: &User, verify_token: String, verify_code: String, tokens: &TokenHasher) -> bool {
+        verifier.verify.as_ref().unwrap().check(&verify_token, &verify_code, tokens)
+    }
+
+    /// Whether `verify_token` and `verify_code` match and haven't expired yet.
+    pub fn check(&self, verify_token: &str, verify_code: &str, tokens: &TokenHasher) -> bool {
         let current_time = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
             .unwrap()
             .as_millis();
-        if verifier.clone().verify.unwrap().expiration_time.parse::<u128>().unwrap() > current_time {
-            if verifier.clone().verify.unwrap().verify_token == verify_token
-                && verifier.clone().verify.unwrap().verify_code == verify_code
-            {
-                return true;
-            } else {
-                return false;
-            }
+        if self.expiration_time.parse::<u128>().unwrap() > current_time {
+            // Both are checked so a wrong token takes as long as a wrong code
+            let token_matches = tokens.check(verify_token, &self.verify_token, self.hashed);
+            let code_matches = tokens.check(verify_code, &self.verify_code, self.hashed);
+            return token_matches & code_matches;
         } else {
             return false;
         }
     }
 }
 
+/// A new email address waiting to be confirmed. The current one stays in use until then.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct EmailChange {
+    pub email: String,
+    pub verify: Verified,
+}
+
+/// A pending password reset. The token is handed to whoever asked for the reset and the code is
+/// only emailed to the account, setting a new password takes both.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct PasswordReset {
+    pub reset_token: String,
+    pub reset_code: String,
+    pub expiration_time: String,
+    #[serde(default)]
+    pub sent_time: Option<String>,
+    #[serde(default)]
+    pub hashed: bool,
+}
+
+impl PasswordReset {
+    pub fn generate(auth: &AuthConfig) -> Self {
+        let creation_time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        PasswordReset {
+            reset_token: thread_rng().sample_iter(&Alphanumeric).take(256).collect(),
+            reset_code: generate_code(auth),
+            expiration_time: (creation_time + auth.password_reset_ttl as u128 * 1000).to_string(),
+            sent_time: Some(creation_time.to_string()),
+            hashed: false,
+        }
+    }
+
+    /// Whether enough time has passed since this reset went out to send another one.
+    pub fn can_resend(&self) -> bool {
+        resend_due(self.sent_time.as_ref())
+    }
+
+    /// The copy that gets stored, with the token and code replaced by their hashes.
+    pub fn hashed(&self, tokens: &TokenHasher) -> Self {
+        if self.hashed {
+            return self.clone();
+        }
+        PasswordReset {
+            reset_token: tokens.hash(&self.reset_token),
+            reset_code: tokens.hash(&self.reset_code),
+            hashed: true,
+            ..self.clone()
+        }
+    }
+
+    pub fn verify(&self, reset_token: &str, reset_code: &str, tokens: &TokenHasher) -> bool {
+        let current_time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        match self.expiration_time.parse::<u128>() {
+            Ok(expiration_time) if expiration_time > current_time => {
+                let token_matches = tokens.check(reset_token, &self.reset_token, self.hashed);
+                let code_matches = tokens.check(reset_code, &self.reset_code, self.hashed);
+                token_matches & code_matches
+            }
+            _ => false,
+        }
+    }
+}
+
+/// A single use login link, emailed to users who'd rather not type a password.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct MagicLink {
+    pub login_token: String,
+    pub login_code: String,
+    pub expiration_time: String,
+    #[serde(default)]
+    pub sent_time: Option<String>,
+    #[serde(default)]
+    pub hashed: bool,
+}
+
+impl MagicLink {
+    pub fn generate(auth: &AuthConfig) -> Self {
+        let creation_time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        MagicLink {
+            login_token: thread_rng().sample_iter(&Alphanumeric).take(256).collect(),
+            login_code: generate_code(auth),
+            expiration_time: (creation_time + auth.magic_link_ttl as u128 * 1000).to_string(),
+            sent_time: Some(creation_time.to_string()),
+            hashed: false,
+        }
+    }
+
+    /// Whether enough time has passed since this link went out to send another one.
+    pub fn can_resend(&self) -> bool {
+        resend_due(self.sent_time.as_ref())
+    }
+
+    /// The copy that gets stored, with the token and code replaced by their hashes.
+    pub fn hashed(&self, tokens: &TokenHasher) -> Self {
+        if self.hashed {
+            return self.clone();
+        }
+        MagicLink {
+            login_token: tokens.hash(&self.login_token),
+            login_code: tokens.hash(&self.login_code),
+            hashed: true,
+            ..self.clone()
+        }
+    }
+
+    pub fn verify(&self, login_token: &str, login_code: &str, tokens: &TokenHasher) -> bool {
+        let current_time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        match self.expiration_time.parse::<u128>() {
+            Ok(expiration_time) if expiration_time > current_time => {
+                let token_matches = tokens.check(login_token, &self.login_token, self.hashed);
+                let code_matches = tokens.check(login_code, &self.login_code, self.hashed);
+                token_matches & code_matches
+            }
+            _ => false,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
 pub struct User {
     pub id: String,
@@ -63,14 +262,324 @@ pub struct User {
     pub email: String,
     pub access_level: String,
     pub verify: Option<Verified>,
-    pub access_record: Option<AccessRecord>,
     pub first_name: Option<String>,
     pub last_name: Option<String>,
     pub address: Option<String>,
     pub phone_number: Option<String>,
+    pub password_reset: Option<PasswordReset>,
+    #[serde(default)]
+    pub magic_link: Option<MagicLink>,
+    #[serde(default)]
+    pub email_change: Option<EmailChange>,
+    /// When the account goes for good, unless the user logs in before then
+    #[serde(default)]
+    pub deletion_scheduled: Option<String>,
+    #[serde(default)]
+    pub two_factor: Option<TwoFactor>,
+    /// Social logins that sign in as this user
+    #[serde(default)]
+    pub linked_accounts: Vec<LinkedAccount>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct LinkedAccount {
+    pub provider: String,
+    /// The provider's id for the user, which unlike the email never changes
+    pub subject: String,
+}
+
+/// A social login that has been sent off to its provider. The state is stored hashed and
+/// checked once on the way back, the PKCE verifier goes along with the code exchange.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct OauthState {
+    pub id: String,
+    pub state: String,
+    pub provider: String,
+    pub pkce_verifier: String,
+    pub expires: String,
+}
+
+impl OauthState {
+    pub fn new(state: &str, provider: &str, pkce_verifier: &str, auth: &AuthConfig, tokens: &TokenHasher) -> Self {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        OauthState {
+            id: bson::oid::ObjectId::new().unwrap().to_hex(),
+            state: tokens.hash(state),
+            provider: provider.to_string(),
+            pkce_verifier: pkce_verifier.to_string(),
+            expires: (now + auth.social_login_ttl as u128 * 1000).to_string(),
+        }
+    }
 
+    pub fn is_expired(&self) -> bool {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        self.expires.parse::<u128>().map_or(true, |expires| now >= expires)
+    }
 }
 
+/// TOTP enrollment. The secret has to stay readable to compute codes, recovery codes are stored
+/// hashed and each one works once.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct TwoFactor {
+    pub secret: String,
+    /// False until a first code confirms the app was set up
+    pub enabled: bool,
+    pub recovery_codes: Vec<String>,
+    /// The last time step a code was accepted for, older codes are refused
+    pub last_step: u64,
+}
+
+/// Handed back once when TOTP is set up.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct TwoFactorEnrollment {
+    pub secret: String,
+    pub provisioning_uri: String,
+    pub recovery_codes: Vec<String>,
+}
+
+/// Given out instead of a session when the password checks out but a second factor is still
+/// needed. Only the hash of its token is stored.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct LoginChallenge {
+    pub id: String,
+    pub user_id: String,
+    pub token: String,
+    pub expires: String,
+    pub client: SessionClient,
+}
+
+impl LoginChallenge {
+    pub fn new(user_id: &str, client: &SessionClient, auth: &AuthConfig) -> Self {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        LoginChallenge {
+            id: bson::oid::ObjectId::new().unwrap().to_hex(),
+            user_id: user_id.to_string(),
+            token: AccessRecord::generate_random(),
+            expires: (now + auth.two_factor_challenge_ttl as u128 * 1000).to_string(),
+            client: client.clone(),
+        }
+    }
+
+    pub fn hashed(&self, tokens: &TokenHasher) -> Self {
+        LoginChallenge {
+            token: tokens.hash(&self.token),
+            ..self.clone()
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        self.expires.parse::<u128>().map_or(true, |expires| now >= expires)
+    }
+}
+
+/// What a correct password gets you.
+pub enum LoginOutcome {
+    Session(AccessRecord),
+    TwoFactorRequired(LoginChallenge),
+}
+
+/// The parts of a `User` that are safe to hand back to clients.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Profile {
+    pub id: String,
+    pub username: String,
+    pub email: String,
+    pub access_level: String,
+    pub first_name: Option<String>,
+    pub last_name: Option<String>,
+    pub address: Option<String>,
+    pub phone_number: Option<String>,
+    pub two_factor: bool,
+    /// The address waiting to replace `email` once confirmed
+    pub pending_email: Option<String>,
+    pub deletion_scheduled: Option<String>,
+}
+
+impl From<&User> for Profile {
+    fn from(user: &User) -> Self {
+        Profile {
+            id: user.id.clone(),
+            username: user.username.clone(),
+            email: user.email.clone(),
+            access_level: user.access_level.clone(),
+            first_name: user.first_name.clone(),
+            last_name: user.last_name.clone(),
+            address: user.address.clone(),
+            phone_number: user.phone_number.clone(),
+            two_factor: user.two_factor_enabled(),
+            pending_email: user.email_change.as_ref().map(|change| change.email.clone()),
+            deletion_scheduled: user.deletion_scheduled.clone(),
+        }
+    }
+}
+
+/// Who an authenticated request comes from, which is all that permission checks and ownership
+/// need. Signed access tokens carry it in their claims, so it's had without loading the user.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Principal {
+    pub id: String,
+    pub access_level: String,
+}
+
+impl From<&User> for Principal {
+    fn from(user: &User) -> Self {
+        Principal {
+            id: user.id.clone(),
+            access_level: user.access_level.clone(),
+        }
+    }
+}
+
+/// Everything stored about a user, for them to take away. Secrets like password hashes and
+/// tokens are left out.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UserExport {
+    pub export_time: String,
+    pub profile: Profile,
+    pub verified: bool,
+    pub linked_accounts: Vec<LinkedAccount>,
+    pub sessions: Vec<SessionInfo>,
+    pub security_events: Vec<SecurityEvent>,
+    pub posts: Vec<Post>,
+    pub media: Vec<Media>,
+}
+
+/// What a user gets to see about their sessions, everything but the tokens.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SessionInfo {
+    pub id: String,
+    pub device: Option<String>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub creation_time: String,
+    pub expires: String,
+    pub current: bool,
+}
+
+impl From<&AccessRecord> for SessionInfo {
+    fn from(record: &AccessRecord) -> Self {
+        SessionInfo {
+            id: record.id.clone(),
+            device: record.device.clone(),
+            user_agent: record.user_agent.clone(),
+            ip: record.ip.clone(),
+            creation_time: record.creation_time.clone(),
+            expires: record.expires.clone(),
+            current: false,
+        }
+    }
+}
+
+/// Something suspicious that happened to an account, kept for later review.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct SecurityEvent {
+    pub id: String,
+    pub user_id: String,
+    pub kind: String,
+    pub details: String,
+    pub session_id: Option<String>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub creation_time: String,
+}
+
+impl SecurityEvent {
+    pub fn new(user_id: &str, kind: &str, details: &str, client: &SessionClient) -> Self {
+        SecurityEvent {
+            id: bson::oid::ObjectId::new().unwrap().to_hex(),
+            user_id: user_id.to_string(),
+            kind: kind.to_string(),
+            details: details.to_string(),
+            session_id: None,
+            user_agent: client.user_agent.clone(),
+            ip: client.ip.clone(),
+            creation_time: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_millis()
+                .to_string(),
+        }
+    }
+}
+
+/// Failed logins counted against an account or an address, see `[auth.lockout]`.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct LoginAttempts {
+    pub key: String,
+    pub failures: u32,
+    pub last_failure: String,
+    pub locked_until: Option<String>,
+}
+
+impl LoginAttempts {
+    pub fn new(key: &str) -> Self {
+        LoginAttempts {
+            key: key.to_string(),
+            failures: 0,
+            last_failure: "0".to_string(),
+            locked_until: None,
+        }
+    }
+
+    pub fn account_key(user_id: &str) -> String {
+        format!("account:{}", user_id)
+    }
+
+    pub fn ip_key(ip: &str) -> String {
+        format!("ip:{}", ip)
+    }
+
+    /// Counts wrong codes against one password reset, keyed by its stored token.
+    pub fn reset_key(reset_token: &str) -> String {
+        format!("reset:{}", reset_token)
+    }
+
+    /// Seconds left before another attempt is allowed, if any.
+    pub fn locked_for(&self) -> Option<u64> {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        match self.locked_until.as_ref().map(|until| until.parse::<u128>()) {
+            Some(Ok(until)) if until > now => Some((until - now).div_ceil(1000) as u64),
+            _ => None,
+        }
+    }
+
+    /// How many seconds the counter is locked for after its latest failure, if it is. The delay
+    /// doubles with every failure past `attempts`.
+    pub fn lock_delay(&self, attempts: u32, policy: &LockoutPolicy) -> Option<u64> {
+        if self.failures < attempts {
+            return None;
+        }
+        let doublings = (self.failures - attempts).min(32);
+        Some(policy.base_delay.saturating_mul(1 << doublings).min(policy.max_delay))
+    }
+}
+
+/// Where a session was opened from, as far as the login request could tell.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+pub struct SessionClient {
+    pub device: Option<String>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+/// One logged in session. A user has one per device, each with its own refresh token, and they
+/// live in the `sessions` collection rather than on the `User`.
 #[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
 pub struct AccessRecord {
     pub id: String,
@@ -79,15 +588,32 @@ pub struct AccessRecord {
     pub creation_time: String,
     pub expires: String,
     pub refresh_token: Option<String>,
+    /// When the refresh token stops working. Sessions from before refresh tokens expired have none.
+    #[serde(default)]
+    pub refresh_expires: Option<String>,
+    pub device: Option<String>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    /// Whether the tokens are stored as `TokenHasher` hashes, see `Verified::hashed`.
+    #[serde(default)]
+    pub hashed: bool,
+    /// Hashes of the refresh tokens this session has already swapped, newest last. Seeing one of
+    /// them again means the family leaked.
+    #[serde(default)]
+    pub used_refresh_tokens: Vec<String>,
 }
 
+/// How many spent refresh tokens a session remembers for reuse detection.
+pub const USED_REFRESH_TOKEN_LIMIT: usize = 100;
+
 impl AccessRecord {
-    pub fn new(user_id: String) -> Self {
+    pub fn new(user_id: String, client: &SessionClient, auth: &AuthConfig) -> Self {
         let creation_time = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
             .unwrap()
             .as_millis();
-        let expiration_time = creation_time + 1200000;
+        let expiration_time = creation_time + auth.access_token_ttl as u128 * 1000;
+        let refresh_expiration_time = creation_time + auth.refresh_token_ttl as u128 * 1000;
         return AccessRecord {
             id: bson::oid::ObjectId::new().unwrap().to_hex(),
             user_id: user_id,
@@ -95,9 +621,70 @@ impl AccessRecord {
             refresh_token: Some(AccessRecord::generate_random()),
             creation_time: creation_time.to_string(),
             expires: expiration_time.to_string(),
+            refresh_expires: Some(refresh_expiration_time.to_string()),
+            device: client.device.clone(),
+            user_agent: client.user_agent.clone(),
+            ip: client.ip.clone(),
+            hashed: false,
+            used_refresh_tokens: Vec::new(),
         };
     }
 
+    /// The copy that gets stored. The plaintext tokens only ever go back to the client.
+    pub fn hashed(&self, tokens: &TokenHasher) -> Self {
+        if self.hashed {
+            return self.clone();
+        }
+        AccessRecord {
+            access_token: tokens.hash(&self.access_token),
+            refresh_token: self.refresh_token.as_ref().map(|token| tokens.hash(token)),
+            hashed: true,
+            ..self.clone()
+        }
+    }
+
+    /// Swaps in new access and refresh tokens, keeping the session itself. The old refresh token
+    /// is remembered as spent, which only makes sense on a stored (hashed) record.
+    pub fn refresh(&mut self, auth: &AuthConfig) {
+        if let Some(spent) = self.refresh_token.take() {
+            self.used_refresh_tokens.push(spent);
+            let overflow = self.used_refresh_tokens.len().saturating_sub(USED_REFRESH_TOKEN_LIMIT);
+            self.used_refresh_tokens.drain(..overflow);
+        }
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        self.access_token = AccessRecord::generate_random();
+        self.refresh_token = Some(AccessRecord::generate_random());
+        self.hashed = false;
+        self.expires = (now + auth.access_token_ttl as u128 * 1000).to_string();
+        self.refresh_expires = Some((now + auth.refresh_token_ttl as u128 * 1000).to_string());
+    }
+
+    pub fn is_refresh_expired(&self) -> bool {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        match self.refresh_expires.as_ref().map(|expires| expires.parse::<u128>()) {
+            Some(Ok(expires)) => now >= expires,
+            Some(Err(_e)) => true,
+            None => false,
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        match self.expires.parse::<u128>() {
+            Ok(expires) => now >= expires,
+            Err(_e) => true,
+        }
+    }
+
     fn generate_random() -> String {
         return thread_rng().sample_iter(&Alphanumeric).take(256).collect();
     }
@@ -114,9 +701,10 @@ impl User {
         first_name: Option<String>,
         last_name: Option<String>,
         address: Option<String>,
-        phone_number: Option<String>
-    ) -> Result<Self, BcryptError> {
-        match User::hash_pw(password) {
+        phone_number: Option<String>,
+        auth: &AuthConfig
+    ) -> Result<Self, DatabaseError> {
+        match User::hash_pw(password, auth) {
             Ok(pw) => {
                 return Ok(User {
                     id: id,
@@ -125,11 +713,16 @@ impl User {
                     email: email,
                     access_level: access_level,
                     verify: verifid,
-                    access_record: None,
                     first_name: first_name,
                     last_name: last_name,
                     address: address,
-                    phone_number: phone_number
+                    phone_number: phone_number,
+                    password_reset: None,
+                    magic_link: None,
+                    email_change: None,
+                    deletion_scheduled: None,
+                    two_factor: None,
+                    linked_accounts: Vec::new()
                 });
             }
             Err(e) => {
@@ -138,11 +731,159 @@ impl User {
         }
     }
 
-    pub fn hash_pw(password: String) -> Result<String, BcryptError> {
-        return hash(password.as_bytes(), 5);
+    pub fn hash_pw(password: String, auth: &AuthConfig) -> Result<String, DatabaseError> {
+        return passwords::hash(&password, auth);
+    }
+
+    pub fn verify_pw(password: String, hashed: String) -> Result<bool, DatabaseError> {
+        return passwords::verify(&password, &hashed);
+    }
+
+    pub fn two_factor_enabled(&self) -> bool {
+        self.two_factor.as_ref().is_some_and(|two_factor| two_factor.enabled)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum PostStatus {
+    Draft,
+    Published,
+}
+
+impl PostStatus {
+    pub fn parse(status: &str) -> Option<PostStatus> {
+        match status {
+            "draft" => Some(PostStatus::Draft),
+            "published" => Some(PostStatus::Published),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PostStatus::Draft => "draft",
+            PostStatus::Published => "published",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Post {
+    pub id: String,
+    pub author_id: String,
+    pub title: String,
+    pub slug: String,
+    pub body: String,
+    pub status: PostStatus,
+    pub creation_time: String,
+    pub update_time: String,
+    pub publish_time: Option<String>,
+}
+
+impl Post {
+    pub fn new(
+        id: String,
+        author_id: String,
+        title: String,
+        slug: String,
+        body: String,
+        status: PostStatus,
+    ) -> Self {
+        let creation_time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis()
+            .to_string();
+        let publish_time = if status == PostStatus::Published {
+            Some(creation_time.clone())
+        } else {
+            None
+        };
+        Post {
+            id,
+            author_id,
+            title,
+            slug,
+            body,
+            status,
+            creation_time: creation_time.clone(),
+            update_time: creation_time,
+            publish_time,
+        }
+    }
+
+    /// Builds a url safe slug from a title, e.g. "Hello, World!" becomes "hello-world".
+    pub fn slugify(title: &str) -> String {
+        let mut slug = String::new();
+        for c in title.trim().to_lowercase().chars() {
+            if c.is_ascii_alphanumeric() {
+                slug.push(c);
+            } else if !slug.ends_with('-') && !slug.is_empty() {
+                slug.push('-');
+            }
+        }
+        slug.trim_end_matches('-').to_string()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum MediaVisibility {
+    Public,
+    Private,
+}
+
+impl MediaVisibility {
+    pub fn parse(visibility: &str) -> Option<MediaVisibility> {
+        match visibility {
+            "public" => Some(MediaVisibility::Public),
+            "private" => Some(MediaVisibility::Private),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Media {
+    pub id: String,
+    pub owner_id: String,
+    pub original_filename: String,
+    pub mime_type: String,
+    pub size: i64,
+    pub checksum: String,
+    pub visibility: MediaVisibility,
+    pub creation_time: String,
+}
+
+impl Media {
+    pub fn new(
+        id: String,
+        owner_id: String,
+        original_filename: String,
+        mime_type: String,
+        size: i64,
+        checksum: String,
+        visibility: MediaVisibility,
+    ) -> Self {
+        Media {
+            id,
+            owner_id,
+            original_filename,
+            mime_type,
+            size,
+            checksum,
+            visibility,
+            creation_time: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_millis()
+                .to_string(),
+        }
     }
 
-    pub fn verify_pw(password: String, hashed: String) -> Result<bool, BcryptError> {
-        return verify(password, &hashed);
+    /// Url the file is served from, private files additionally need the owner's credentials.
+    pub fn url(&self) -> String {
+        format!("/media/{}", self.id)
     }
 }
@@ -4,11 +4,14 @@ use threadpool::ThreadPool;
 use curl::easy::{Easy, List};
 use super::configuration::OauthConfig;
 use base64;
+use std::sync::{Arc, Mutex};
 
-#[derive(Clone, PartialEq, Debug)]
+#[derive(Clone, Debug)]
 pub struct Emailer {
     pub pool: ThreadPool,
     pub n_threads: usize,
+    /// Emails are collected here instead of being sent when set, see `Emailer::outbox`
+    pub outbox: Option<Arc<Mutex<Vec<Email>>>>,
 }
 
 impl Emailer {
@@ -16,11 +19,32 @@ impl Emailer {
     pub fn new(num_threads: usize) -> Emailer {
         Emailer{
             pool: ThreadPool::new(num_threads),
-            n_threads: num_threads
+            n_threads: num_threads,
+            outbox: None
+        }
+    }
+
+    /// An emailer that never sends anything, for tests and running without an email provider.
+    pub fn outbox() -> Emailer {
+        Emailer{
+            pool: ThreadPool::new(1),
+            n_threads: 1,
+            outbox: Some(Arc::new(Mutex::new(Vec::new())))
+        }
+    }
+
+    pub fn sent(&self) -> Vec<Email> {
+        match self.outbox {
+            Some(ref outbox) => outbox.lock().unwrap().clone(),
+            None => Vec::new()
         }
     }
 
     pub fn send_email(&self, email: Email, config: OauthConfig) {
+        if let Some(ref outbox) = self.outbox {
+            outbox.lock().unwrap().push(email);
+            return;
+        }
         self.pool.execute( move || {
             let sendable : SendableEmail = email.clone().into();
             let msg_64 = base64::encode_config(sendable.message_to_string().unwrap(), base64::URL_SAFE);
@@ -3,7 +3,13 @@ pub mod configuration;
 pub mod database;
 pub mod database_structures;
 pub mod server;
+pub mod social;
 pub mod database_errors;
 pub mod emailer;
 pub mod oauth;
-pub mod authorizer;
\ No newline at end of file
+pub mod passwords;
+pub mod authorizer;
+pub mod storage;
+pub mod tokens;
+pub mod totp;
+pub mod uploader;
\ No newline at end of file
@@ -9,7 +9,6 @@ use oauth2::{
     TokenResponse, TokenUrl,
 };
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
 use url::Url;
 use warp;
 
@@ -18,7 +17,7 @@ pub struct Oauth {
     pub name: String,
     pub config: OauthConfig,
     pub client: BasicClient,
-    pub server: Arc<Mutex<Server>>,
+    pub server: Server,
     pub csrf_token: oauth2::CsrfToken,
     pub auth_url: url::Url,
 }
@@ -48,7 +47,7 @@ impl Oauth {
             name: config.clone().name,
             config,
             client,
-            server: Arc::new(Mutex::new(serve.clone())),
+            server: serve,
             csrf_token,
             auth_url,
         }
@@ -66,7 +65,7 @@ impl Oauth {
                     self.config.refresh_token =
                         Some(tok.refresh_token().unwrap().secret().to_string());
                     match DatabaseController::add_oauth_record(
-                        &self.server.lock().unwrap(),
+                        &self.server,
                         self.config.clone(),
                     ) {
                         Ok(id) => {
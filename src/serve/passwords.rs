@@ -0,0 +1,135 @@
+use super::configuration::{AuthConfig, PasswordHashAlgorithm, PasswordPolicy};
+use super::database_errors::DatabaseError;
+use rand::{thread_rng, RngCore};
+use std::collections::HashSet;
+use std::fs;
+use std::sync::Arc;
+use validator::{ValidationError, ValidationErrors};
+
+/// Hashes `password` with the algorithm and cost `[auth]` currently asks for.
+pub fn hash(password: &str, auth: &AuthConfig) -> Result<String, DatabaseError> {
+    match algorithm(auth) {
+        PasswordHashAlgorithm::Argon2id => {
+            let mut salt = [0u8; 16];
+            thread_rng().fill_bytes(&mut salt);
+            argon2::hash_encoded(password.as_bytes(), &salt, &argon2_config(auth)).map_err(DatabaseError::Argon2Error)
+        }
+        PasswordHashAlgorithm::Bcrypt => bcrypt::hash(password, auth.hash_cost).map_err(DatabaseError::BcryptError),
+    }
+}
+
+/// Checks `password` against a stored hash of either kind, whatever the settings are now.
+pub fn verify(password: &str, hashed: &str) -> Result<bool, DatabaseError> {
+    if hashed.starts_with("$argon2") {
+        argon2::verify_encoded(hashed, password.as_bytes()).map_err(DatabaseError::Argon2Error)
+    } else {
+        bcrypt::verify(password, hashed).map_err(DatabaseError::BcryptError)
+    }
+}
+
+/// Whether `hashed` was made with another algorithm or cost than `hash` would use now.
+pub fn needs_rehash(hashed: &str, auth: &AuthConfig) -> bool {
+    match algorithm(auth) {
+        PasswordHashAlgorithm::Argon2id => !hashed.starts_with(&format!(
+            "$argon2id$v=19$m={},t={},p={}$",
+            auth.argon2_memory_cost, auth.argon2_time_cost, auth.argon2_parallelism
+        )),
+        // $2b$<cost>$<salt and hash>
+        PasswordHashAlgorithm::Bcrypt => {
+            let cost = hashed.split('$').nth(2).and_then(|cost| cost.parse::<u32>().ok());
+            !hashed.starts_with("$2") || cost != Some(auth.hash_cost)
+        }
+    }
+}
+
+fn algorithm(auth: &AuthConfig) -> PasswordHashAlgorithm {
+    auth.password_hash.clone().unwrap_or(PasswordHashAlgorithm::Argon2id)
+}
+
+fn argon2_config(auth: &AuthConfig) -> argon2::Config<'static> {
+    argon2::Config {
+        variant: argon2::Variant::Argon2id,
+        version: argon2::Version::Version13,
+        mem_cost: auth.argon2_memory_cost,
+        time_cost: auth.argon2_time_cost,
+        lanes: auth.argon2_parallelism,
+        ..argon2::Config::default()
+    }
+}
+
+/// Checks new passwords against `[auth.password_policy]`. The breached list is read once at
+/// startup.
+#[derive(Clone)]
+pub struct PasswordChecker {
+    policy: PasswordPolicy,
+    breached: Arc<HashSet<String>>,
+}
+
+impl PasswordChecker {
+    pub fn from_config(auth: &AuthConfig) -> Result<Self, DatabaseError> {
+        let breached = match &auth.password_policy.breached_passwords {
+            Some(path) => match fs::read_to_string(path) {
+                Ok(contents) => contents
+                    .lines()
+                    .map(|line| line.trim().to_string())
+                    .filter(|line| !line.is_empty())
+                    .collect(),
+                Err(e) => return Err(DatabaseError::IOError(e)),
+            },
+            None => HashSet::new(),
+        };
+        Ok(PasswordChecker {
+            policy: auth.password_policy.clone(),
+            breached: Arc::new(breached),
+        })
+    }
+
+    /// Every rule `password` breaks is reported against the `password` field.
+    pub fn check(&self, password: &str, username: &str, email: &str) -> Result<(), DatabaseError> {
+        let policy = &self.policy;
+        let mut errors = ValidationErrors::new();
+        let length = password.chars().count();
+        if length < policy.min_length {
+            add(&mut errors, "too_short", format!("must be at least {} characters", policy.min_length));
+        }
+        if length > policy.max_length {
+            add(&mut errors, "too_long", format!("must be at most {} characters", policy.max_length));
+        }
+        if policy.require_lowercase && !password.chars().any(char::is_lowercase) {
+            add(&mut errors, "lowercase", "must contain a lowercase letter".to_string());
+        }
+        if policy.require_uppercase && !password.chars().any(char::is_uppercase) {
+            add(&mut errors, "uppercase", "must contain an uppercase letter".to_string());
+        }
+        if policy.require_digit && !password.chars().any(|c| c.is_ascii_digit()) {
+            add(&mut errors, "digit", "must contain a digit".to_string());
+        }
+        if policy.require_symbol && password.chars().all(char::is_alphanumeric) {
+            add(&mut errors, "symbol", "must contain a symbol".to_string());
+        }
+        if policy.reject_personal {
+            let lowered = password.to_lowercase();
+            let local_part = email.split('@').next().unwrap_or(email);
+            if [username, email, local_part]
+                .iter()
+                .any(|personal| !personal.is_empty() && lowered == personal.to_lowercase())
+            {
+                add(&mut errors, "personal", "must not be your username or email address".to_string());
+            }
+        }
+        if self.breached.contains(password) {
+            add(&mut errors, "breached", "has appeared in a data breach, choose another".to_string());
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(DatabaseError::ValidationError(errors))
+        }
+    }
+}
+
+fn add(errors: &mut ValidationErrors, code: &'static str, message: String) {
+    let mut error = ValidationError::new(code);
+    error.message = Some(message.into());
+    errors.add("password", error);
+}
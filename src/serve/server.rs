@@ -1,6 +1,7 @@
 use super::configuration::Configuration;
 use super::database::DatabaseController;
 use super::database_errors::DatabaseError;
+use tokio::task;
 
 #[derive(Clone)]
 pub struct Server {
@@ -33,4 +34,18 @@ impl Server {
             }
         }
     }
+
+    /// Runs blocking `DatabaseController` work on tokio's blocking pool so request handlers never
+    /// stall the executor. `Server` is cheap to clone, the mongodb client inside is shared.
+    pub async fn run<T, F>(&self, f: F) -> Result<T, DatabaseError>
+    where
+        F: FnOnce(&Server) -> Result<T, DatabaseError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let server = self.clone();
+        match task::spawn_blocking(move || f(&server)).await {
+            Ok(result) => result,
+            Err(e) => Err(DatabaseError::TaskError(e)),
+        }
+    }
 }
@@ -0,0 +1,116 @@
+use super::configuration::SocialProvider;
+use super::database_errors::{DatabaseError, InvalidCredentialsError};
+use curl::easy::{Easy, List};
+use oauth2::basic::BasicClient;
+use oauth2::prelude::*;
+use oauth2::{
+    AuthUrl, AuthorizationCode, ClientId, ClientSecret, CsrfToken, PkceCodeVerifierS256, RedirectUrl,
+    ResponseType, Scope, TokenResponse, TokenUrl,
+};
+use serde_json::Value;
+use url::Url;
+
+/// What a provider's profile says about the user signing in.
+#[derive(Clone, Debug)]
+pub struct SocialProfile {
+    pub subject: String,
+    pub email: String,
+    pub email_verified: bool,
+}
+
+fn provider_error(provider: &SocialProvider, what: &str) -> DatabaseError {
+    DatabaseError::InvalidCredentialsError(InvalidCredentialsError::new(&format!(
+        "{} sign in failed: {}",
+        provider.name, what
+    )))
+}
+
+fn client(provider: &SocialProvider, hostname: &str) -> Result<BasicClient, DatabaseError> {
+    let parse = |url: &str| Url::parse(url).map_err(|_e| provider_error(provider, "its urls are misconfigured"));
+    let redirect_url = format!("https://{}/auth/{}/callback", hostname, provider.name);
+    let mut client = BasicClient::new(
+        ClientId::new(provider.client_id.clone()),
+        Some(ClientSecret::new(provider.client_secret.clone())),
+        AuthUrl::new(parse(&provider.auth_url)?),
+        Some(TokenUrl::new(parse(&provider.token_url)?)),
+    )
+    .set_redirect_url(RedirectUrl::new(parse(&redirect_url)?));
+    for scope in &provider.scope {
+        client = client.add_scope(Scope::new(scope.clone()));
+    }
+    Ok(client)
+}
+
+/// Where to send the user, along with the fresh state and PKCE verifier the callback has to
+/// match.
+pub fn authorization_url(provider: &SocialProvider, hostname: &str) -> Result<(Url, String, String), DatabaseError> {
+    let verifier = PkceCodeVerifierS256::new_random();
+    let (url, state) = client(provider, hostname)?.authorize_url_extension(
+        &ResponseType::new("code".to_string()),
+        CsrfToken::new_random,
+        &verifier.authorize_url_params(),
+    );
+    Ok((url, state.secret().to_string(), verifier.secret().to_string()))
+}
+
+/// Swaps the authorization code for the provider's access token. Blocks.
+pub fn exchange_code(
+    provider: &SocialProvider,
+    hostname: &str,
+    code: &str,
+    pkce_verifier: &str,
+) -> Result<String, DatabaseError> {
+    match client(provider, hostname)?
+        .exchange_code_extension(AuthorizationCode::new(code.to_string()), &[("code_verifier", pkce_verifier)])
+    {
+        Ok(token) => Ok(token.access_token().secret().to_string()),
+        Err(_e) => Err(provider_error(provider, "the authorization code was refused")),
+    }
+}
+
+/// Fetches and reads the signed in user's profile. Blocks.
+pub fn fetch_profile(provider: &SocialProvider, access_token: &str) -> Result<SocialProfile, DatabaseError> {
+    let unreachable = |_e: curl::Error| provider_error(provider, "the profile could not be fetched");
+    let mut body = Vec::new();
+    let mut easy = Easy::new();
+    let mut headers = List::new();
+    easy.url(&provider.userinfo_url).map_err(unreachable)?;
+    headers.append(&format!("Authorization: Bearer {}", access_token)).map_err(unreachable)?;
+    headers.append("Accept: application/json").map_err(unreachable)?;
+    easy.http_headers(headers).map_err(unreachable)?;
+    {
+        let mut transfer = easy.transfer();
+        transfer
+            .write_function(|data| {
+                body.extend_from_slice(data);
+                Ok(data.len())
+            })
+            .map_err(unreachable)?;
+        transfer.perform().map_err(unreachable)?;
+    }
+    if easy.response_code().map_err(unreachable)? != 200 {
+        return Err(provider_error(provider, "the profile could not be fetched"));
+    }
+    let profile: Value =
+        serde_json::from_slice(&body).map_err(|_e| provider_error(provider, "the profile was not json"))?;
+    let subject = match &profile[&provider.id_field] {
+        Value::String(subject) => subject.clone(),
+        Value::Number(subject) => subject.to_string(),
+        _ => return Err(provider_error(provider, "the profile has no id")),
+    };
+    let email = match profile[&provider.email_field].as_str() {
+        Some(email) => email.to_string(),
+        None => return Err(provider_error(provider, "the profile has no email address")),
+    };
+    let email_verified = provider.trust_provider_email
+        || match &profile[&provider.email_verified_field] {
+            Value::Bool(verified) => *verified,
+            Value::String(verified) => verified == "true",
+            _ => false,
+        };
+    Ok(SocialProfile {
+        subject,
+        email,
+        email_verified,
+    })
+}
@@ -0,0 +1,397 @@
+use super::super::configuration::OauthConfig;
+use super::super::database_errors::DatabaseError;
+use super::super::database_structures::{AccessRecord, LoginAttempts, LoginChallenge, Media, OauthState, Object, Post, SecurityEvent, TwoFactor, User};
+use super::{PostFilter, Storage};
+use std::sync::Mutex;
+
+/// Keeps everything in process memory. Nothing survives a restart, it's meant for tests and
+/// trying the server out without a Mongo instance.
+#[derive(Default)]
+pub struct MemoryStorage {
+    users: Mutex<Vec<User>>,
+    sessions: Mutex<Vec<AccessRecord>>,
+    security_events: Mutex<Vec<SecurityEvent>>,
+    login_attempts: Mutex<Vec<LoginAttempts>>,
+    login_challenges: Mutex<Vec<LoginChallenge>>,
+    objects: Mutex<Vec<Object>>,
+    oauth: Mutex<Vec<OauthConfig>>,
+    oauth_states: Mutex<Vec<OauthState>>,
+    posts: Mutex<Vec<Post>>,
+    media: Mutex<Vec<Media>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        MemoryStorage::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn name(&self) -> String {
+        "memory".to_string()
+    }
+
+    fn find_user(&self, username: &str, email: Option<&str>) -> Result<Option<User>, DatabaseError> {
+        let users = self.users.lock().unwrap();
+        Ok(users
+            .iter()
+            .find(|user| user.username == username || email == Some(user.email.as_str()))
+            .cloned())
+    }
+
+    fn find_user_by_email(&self, email: &str) -> Result<Option<User>, DatabaseError> {
+        let users = self.users.lock().unwrap();
+        Ok(users.iter().find(|user| user.email == email).cloned())
+    }
+
+    fn insert_user(&self, user: &User) -> Result<(), DatabaseError> {
+        self.users.lock().unwrap().push(user.clone());
+        Ok(())
+    }
+
+    fn update_user(&self, username: &str, user: &User) -> Result<(), DatabaseError> {
+        let mut users = self.users.lock().unwrap();
+        if let Some(existing) = users.iter_mut().find(|existing| existing.username == username) {
+            *existing = user.clone();
+        }
+        Ok(())
+    }
+
+    fn find_user_by_id(&self, id: &str) -> Result<Option<User>, DatabaseError> {
+        let users = self.users.lock().unwrap();
+        Ok(users.iter().find(|user| user.id == id).cloned())
+    }
+
+    fn find_user_by_linked_account(&self, provider: &str, subject: &str) -> Result<Option<User>, DatabaseError> {
+        let users = self.users.lock().unwrap();
+        Ok(users
+            .iter()
+            .find(|user| {
+                user.linked_accounts
+                    .iter()
+                    .any(|linked| linked.provider == provider && linked.subject == subject)
+            })
+            .cloned())
+    }
+
+    fn delete_user(&self, id: &str) -> Result<(), DatabaseError> {
+        self.users.lock().unwrap().retain(|user| user.id != id);
+        Ok(())
+    }
+
+    fn swap_two_factor(
+        &self,
+        user_id: &str,
+        previous: &TwoFactor,
+        two_factor: &TwoFactor,
+    ) -> Result<bool, DatabaseError> {
+        let mut users = self.users.lock().unwrap();
+        match users
+            .iter_mut()
+            .find(|user| user.id == user_id && user.two_factor.as_ref() == Some(previous))
+        {
+            Some(user) => {
+                user.two_factor = Some(two_factor.clone());
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn take_password_reset(&self, user_id: &str, reset_token: &str) -> Result<bool, DatabaseError> {
+        let mut users = self.users.lock().unwrap();
+        match users.iter_mut().find(|user| {
+            user.id == user_id && user.password_reset.as_ref().is_some_and(|reset| reset.reset_token == reset_token)
+        }) {
+            Some(user) => {
+                user.password_reset = None;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn take_magic_link(&self, user_id: &str, login_token: &str) -> Result<bool, DatabaseError> {
+        let mut users = self.users.lock().unwrap();
+        match users.iter_mut().find(|user| {
+            user.id == user_id && user.magic_link.as_ref().is_some_and(|link| link.login_token == login_token)
+        }) {
+            Some(user) => {
+                user.magic_link = None;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn list_users_pending_deletion(&self) -> Result<Vec<User>, DatabaseError> {
+        let users = self.users.lock().unwrap();
+        Ok(users.iter().filter(|user| user.deletion_scheduled.is_some()).cloned().collect())
+    }
+
+    fn insert_session(&self, session: &AccessRecord) -> Result<(), DatabaseError> {
+        self.sessions.lock().unwrap().push(session.clone());
+        Ok(())
+    }
+
+    fn find_session_by_access_token(&self, access_token: &str) -> Result<Option<AccessRecord>, DatabaseError> {
+        let sessions = self.sessions.lock().unwrap();
+        Ok(sessions
+            .iter()
+            .find(|session| session.access_token == access_token)
+            .cloned())
+    }
+
+    fn find_session_by_refresh_token(&self, refresh_token: &str) -> Result<Option<AccessRecord>, DatabaseError> {
+        let sessions = self.sessions.lock().unwrap();
+        Ok(sessions
+            .iter()
+            .find(|session| session.refresh_token.as_deref() == Some(refresh_token))
+            .cloned())
+    }
+
+    fn find_session_by_used_refresh_token(&self, refresh_token: &str) -> Result<Option<AccessRecord>, DatabaseError> {
+        let sessions = self.sessions.lock().unwrap();
+        Ok(sessions
+            .iter()
+            .find(|session| session.used_refresh_tokens.iter().any(|used| used == refresh_token))
+            .cloned())
+    }
+
+    fn replace_session(&self, session: &AccessRecord) -> Result<(), DatabaseError> {
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(existing) = sessions.iter_mut().find(|existing| existing.id == session.id) {
+            *existing = session.clone();
+        }
+        Ok(())
+    }
+
+    fn swap_session(&self, session: &AccessRecord, refresh_token: &str) -> Result<bool, DatabaseError> {
+        let mut sessions = self.sessions.lock().unwrap();
+        match sessions
+            .iter_mut()
+            .find(|existing| existing.id == session.id && existing.refresh_token.as_deref() == Some(refresh_token))
+        {
+            Some(existing) => {
+                *existing = session.clone();
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn delete_session(&self, id: &str) -> Result<(), DatabaseError> {
+        self.sessions.lock().unwrap().retain(|session| session.id != id);
+        Ok(())
+    }
+
+    fn delete_sessions(&self, user_id: &str) -> Result<(), DatabaseError> {
+        self.sessions.lock().unwrap().retain(|session| session.user_id != user_id);
+        Ok(())
+    }
+
+    fn list_sessions(&self, user_id: &str) -> Result<Vec<AccessRecord>, DatabaseError> {
+        let mut sessions: Vec<AccessRecord> = self
+            .sessions
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|session| session.user_id == user_id)
+            .cloned()
+            .collect();
+        sessions.sort_by(|a, b| b.creation_time.cmp(&a.creation_time));
+        Ok(sessions)
+    }
+
+    fn insert_security_event(&self, event: &SecurityEvent) -> Result<(), DatabaseError> {
+        self.security_events.lock().unwrap().push(event.clone());
+        Ok(())
+    }
+
+    fn list_security_events(&self, user_id: &str) -> Result<Vec<SecurityEvent>, DatabaseError> {
+        let mut events: Vec<SecurityEvent> = self
+            .security_events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|event| event.user_id == user_id)
+            .cloned()
+            .collect();
+        events.sort_by(|a, b| b.creation_time.cmp(&a.creation_time));
+        Ok(events)
+    }
+
+    fn delete_security_events(&self, user_id: &str) -> Result<(), DatabaseError> {
+        self.security_events.lock().unwrap().retain(|event| event.user_id != user_id);
+        Ok(())
+    }
+
+    fn find_login_attempts(&self, key: &str) -> Result<Option<LoginAttempts>, DatabaseError> {
+        let login_attempts = self.login_attempts.lock().unwrap();
+        Ok(login_attempts.iter().find(|attempts| attempts.key == key).cloned())
+    }
+
+    fn add_login_failure(&self, key: &str, now: &str, stale_before: &str) -> Result<LoginAttempts, DatabaseError> {
+        let mut login_attempts = self.login_attempts.lock().unwrap();
+        let index = match login_attempts.iter().position(|attempts| attempts.key == key) {
+            Some(index) => index,
+            None => {
+                login_attempts.push(LoginAttempts::new(key));
+                login_attempts.len() - 1
+            }
+        };
+        let attempts = &mut login_attempts[index];
+        let millis = |time: &str| time.parse::<u128>().unwrap_or(0);
+        if millis(&attempts.last_failure) < millis(stale_before) {
+            attempts.failures = 0;
+        }
+        attempts.failures += 1;
+        attempts.last_failure = now.to_string();
+        Ok(attempts.clone())
+    }
+
+    fn lock_login_attempts(&self, key: &str, until: &str) -> Result<(), DatabaseError> {
+        let mut login_attempts = self.login_attempts.lock().unwrap();
+        if let Some(attempts) = login_attempts.iter_mut().find(|attempts| attempts.key == key) {
+            let current = attempts.locked_until.as_ref().and_then(|time| time.parse::<u128>().ok());
+            if current < until.parse::<u128>().ok() {
+                attempts.locked_until = Some(until.to_string());
+            }
+        }
+        Ok(())
+    }
+
+    fn delete_login_attempts(&self, key: &str) -> Result<(), DatabaseError> {
+        self.login_attempts.lock().unwrap().retain(|attempts| attempts.key != key);
+        Ok(())
+    }
+
+    fn insert_login_challenge(&self, challenge: &LoginChallenge) -> Result<(), DatabaseError> {
+        self.login_challenges.lock().unwrap().push(challenge.clone());
+        Ok(())
+    }
+
+    fn find_login_challenge(&self, token: &str) -> Result<Option<LoginChallenge>, DatabaseError> {
+        let login_challenges = self.login_challenges.lock().unwrap();
+        Ok(login_challenges.iter().find(|challenge| challenge.token == token).cloned())
+    }
+
+    fn delete_login_challenge(&self, id: &str) -> Result<(), DatabaseError> {
+        self.login_challenges.lock().unwrap().retain(|challenge| challenge.id != id);
+        Ok(())
+    }
+
+    fn delete_login_challenges(&self, user_id: &str) -> Result<(), DatabaseError> {
+        self.login_challenges.lock().unwrap().retain(|challenge| challenge.user_id != user_id);
+        Ok(())
+    }
+
+    fn insert_object(&self, object: &Object) -> Result<(), DatabaseError> {
+        self.objects.lock().unwrap().push(object.clone());
+        Ok(())
+    }
+
+    fn remove_object(&self, id: &str) -> Result<(), DatabaseError> {
+        self.objects.lock().unwrap().retain(|object| object.id != id);
+        Ok(())
+    }
+
+    fn insert_oauth_state(&self, state: &OauthState) -> Result<(), DatabaseError> {
+        self.oauth_states.lock().unwrap().push(state.clone());
+        Ok(())
+    }
+
+    fn find_oauth_state(&self, state: &str) -> Result<Option<OauthState>, DatabaseError> {
+        let oauth_states = self.oauth_states.lock().unwrap();
+        Ok(oauth_states.iter().find(|pending| pending.state == state).cloned())
+    }
+
+    fn delete_oauth_state(&self, id: &str) -> Result<(), DatabaseError> {
+        self.oauth_states.lock().unwrap().retain(|pending| pending.id != id);
+        Ok(())
+    }
+
+    fn find_oauth_record(&self, name: &str) -> Result<Option<OauthConfig>, DatabaseError> {
+        let oauth = self.oauth.lock().unwrap();
+        Ok(oauth.iter().find(|oauth| oauth.name == name).cloned())
+    }
+
+    fn insert_oauth_record(&self, oauth: &OauthConfig) -> Result<(), DatabaseError> {
+        self.oauth.lock().unwrap().push(oauth.clone());
+        Ok(())
+    }
+
+    fn find_post(&self, id: Option<&str>, slug: Option<&str>) -> Result<Option<Post>, DatabaseError> {
+        let posts = self.posts.lock().unwrap();
+        Ok(posts
+            .iter()
+            .find(|post| {
+                (id.is_none() || id == Some(post.id.as_str()))
+                    && (slug.is_none() || slug == Some(post.slug.as_str()))
+            })
+            .cloned())
+    }
+
+    fn insert_post(&self, post: &Post) -> Result<(), DatabaseError> {
+        self.posts.lock().unwrap().push(post.clone());
+        Ok(())
+    }
+
+    fn replace_post(&self, post: &Post) -> Result<(), DatabaseError> {
+        let mut posts = self.posts.lock().unwrap();
+        if let Some(existing) = posts.iter_mut().find(|existing| existing.id == post.id) {
+            *existing = post.clone();
+        }
+        Ok(())
+    }
+
+    fn delete_post(&self, id: &str) -> Result<(), DatabaseError> {
+        self.posts.lock().unwrap().retain(|post| post.id != id);
+        Ok(())
+    }
+
+    fn list_posts(&self, filter: &PostFilter, skip: i64, limit: i64) -> Result<Vec<Post>, DatabaseError> {
+        let mut posts: Vec<Post> = self
+            .posts
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|post| filter.matches(post))
+            .cloned()
+            .collect();
+        posts.sort_by(|a, b| b.creation_time.cmp(&a.creation_time));
+        Ok(posts
+            .into_iter()
+            .skip(skip.max(0) as usize)
+            .take(limit.max(0) as usize)
+            .collect())
+    }
+
+    fn insert_media(&self, media: &Media) -> Result<(), DatabaseError> {
+        self.media.lock().unwrap().push(media.clone());
+        Ok(())
+    }
+
+    fn find_media(&self, id: &str) -> Result<Option<Media>, DatabaseError> {
+        let media = self.media.lock().unwrap();
+        Ok(media.iter().find(|media| media.id == id).cloned())
+    }
+
+    fn delete_media(&self, id: &str) -> Result<(), DatabaseError> {
+        self.media.lock().unwrap().retain(|media| media.id != id);
+        Ok(())
+    }
+
+    fn list_media(&self, owner_id: &str) -> Result<Vec<Media>, DatabaseError> {
+        let mut media: Vec<Media> = self
+            .media
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|media| media.owner_id == owner_id)
+            .cloned()
+            .collect();
+        media.sort_by(|a, b| b.creation_time.cmp(&a.creation_time));
+        Ok(media)
+    }
+}
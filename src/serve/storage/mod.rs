@@ -0,0 +1,110 @@
+use super::configuration::OauthConfig;
+use super::database_errors::DatabaseError;
+use super::database_structures::{AccessRecord, LoginAttempts, LoginChallenge, Media, OauthState, Object, Post, PostStatus, SecurityEvent, TwoFactor, User};
+
+pub mod memory;
+pub mod mongo;
+
+/// Which posts `Storage::list_posts` returns. Unset fields match everything.
+#[derive(Clone, Default, Debug)]
+pub struct PostFilter {
+    pub author_id: Option<String>,
+    pub status: Option<PostStatus>,
+}
+
+impl PostFilter {
+    pub fn matches(&self, post: &Post) -> bool {
+        (self.author_id.is_none() || self.author_id.as_ref() == Some(&post.author_id))
+            && (self.status.is_none() || self.status.as_ref() == Some(&post.status))
+    }
+}
+
+/// Everything `DatabaseController` persists. Implementations are blocking, callers run them
+/// through `Server::run`.
+pub trait Storage: Send + Sync {
+    /// Shown at startup.
+    fn name(&self) -> String;
+
+    /// Finds a user by username, or by email as well when one is given.
+    fn find_user(&self, username: &str, email: Option<&str>) -> Result<Option<User>, DatabaseError>;
+    fn find_user_by_email(&self, email: &str) -> Result<Option<User>, DatabaseError>;
+    fn insert_user(&self, user: &User) -> Result<(), DatabaseError>;
+    fn update_user(&self, username: &str, user: &User) -> Result<(), DatabaseError>;
+
+    fn find_user_by_id(&self, id: &str) -> Result<Option<User>, DatabaseError>;
+    fn find_user_by_linked_account(&self, provider: &str, subject: &str) -> Result<Option<User>, DatabaseError>;
+    fn delete_user(&self, id: &str) -> Result<(), DatabaseError>;
+    /// Clears the user's magic link only while its token is still `login_token`. Returns false if
+    /// another login used it first.
+    /// Clears the user's password reset only while its token is still `reset_token`. Returns false
+    /// if it was used or replaced first.
+    /// Replaces the user's TOTP settings only while they still match `previous`. Returns false if
+    /// another login used a code first.
+    fn swap_two_factor(
+        &self,
+        user_id: &str,
+        previous: &TwoFactor,
+        two_factor: &TwoFactor,
+    ) -> Result<bool, DatabaseError>;
+    fn take_password_reset(&self, user_id: &str, reset_token: &str) -> Result<bool, DatabaseError>;
+    fn take_magic_link(&self, user_id: &str, login_token: &str) -> Result<bool, DatabaseError>;
+    /// Users with a deletion scheduled, whether it's due yet or not.
+    fn list_users_pending_deletion(&self) -> Result<Vec<User>, DatabaseError>;
+
+    fn insert_session(&self, session: &AccessRecord) -> Result<(), DatabaseError>;
+    fn find_session_by_access_token(&self, access_token: &str) -> Result<Option<AccessRecord>, DatabaseError>;
+    fn find_session_by_refresh_token(&self, refresh_token: &str) -> Result<Option<AccessRecord>, DatabaseError>;
+    /// The session that has already swapped `refresh_token` for a new one.
+    fn find_session_by_used_refresh_token(&self, refresh_token: &str) -> Result<Option<AccessRecord>, DatabaseError>;
+    fn replace_session(&self, session: &AccessRecord) -> Result<(), DatabaseError>;
+    /// Replaces the stored session only while its refresh token is still `refresh_token`. Returns
+    /// false if another exchange swapped it first.
+    fn swap_session(&self, session: &AccessRecord, refresh_token: &str) -> Result<bool, DatabaseError>;
+    fn delete_session(&self, id: &str) -> Result<(), DatabaseError>;
+    /// Removes every session belonging to `user_id`.
+    fn delete_sessions(&self, user_id: &str) -> Result<(), DatabaseError>;
+    /// Newest first.
+    fn list_sessions(&self, user_id: &str) -> Result<Vec<AccessRecord>, DatabaseError>;
+
+    fn insert_security_event(&self, event: &SecurityEvent) -> Result<(), DatabaseError>;
+    /// Newest first.
+    fn list_security_events(&self, user_id: &str) -> Result<Vec<SecurityEvent>, DatabaseError>;
+    fn delete_security_events(&self, user_id: &str) -> Result<(), DatabaseError>;
+
+    fn find_login_attempts(&self, key: &str) -> Result<Option<LoginAttempts>, DatabaseError>;
+    /// Counts one failure under `key` in a single step so concurrent failures all count. A counter
+    /// whose last failure was before `stale_before` starts over. Returns the counter afterwards.
+    fn add_login_failure(&self, key: &str, now: &str, stale_before: &str) -> Result<LoginAttempts, DatabaseError>;
+    /// Locks `key` until `until`, never shortening a lock already in place.
+    fn lock_login_attempts(&self, key: &str, until: &str) -> Result<(), DatabaseError>;
+    fn delete_login_attempts(&self, key: &str) -> Result<(), DatabaseError>;
+
+    fn insert_login_challenge(&self, challenge: &LoginChallenge) -> Result<(), DatabaseError>;
+    fn find_login_challenge(&self, token: &str) -> Result<Option<LoginChallenge>, DatabaseError>;
+    fn delete_login_challenge(&self, id: &str) -> Result<(), DatabaseError>;
+    /// Removes every challenge belonging to `user_id`.
+    fn delete_login_challenges(&self, user_id: &str) -> Result<(), DatabaseError>;
+
+    fn insert_object(&self, object: &Object) -> Result<(), DatabaseError>;
+    fn remove_object(&self, id: &str) -> Result<(), DatabaseError>;
+
+    fn insert_oauth_state(&self, state: &OauthState) -> Result<(), DatabaseError>;
+    fn find_oauth_state(&self, state: &str) -> Result<Option<OauthState>, DatabaseError>;
+    fn delete_oauth_state(&self, id: &str) -> Result<(), DatabaseError>;
+
+    fn find_oauth_record(&self, name: &str) -> Result<Option<OauthConfig>, DatabaseError>;
+    fn insert_oauth_record(&self, oauth: &OauthConfig) -> Result<(), DatabaseError>;
+
+    fn find_post(&self, id: Option<&str>, slug: Option<&str>) -> Result<Option<Post>, DatabaseError>;
+    fn insert_post(&self, post: &Post) -> Result<(), DatabaseError>;
+    fn replace_post(&self, post: &Post) -> Result<(), DatabaseError>;
+    fn delete_post(&self, id: &str) -> Result<(), DatabaseError>;
+    /// Newest first.
+    fn list_posts(&self, filter: &PostFilter, skip: i64, limit: i64) -> Result<Vec<Post>, DatabaseError>;
+
+    fn insert_media(&self, media: &Media) -> Result<(), DatabaseError>;
+    fn find_media(&self, id: &str) -> Result<Option<Media>, DatabaseError>;
+    fn delete_media(&self, id: &str) -> Result<(), DatabaseError>;
+    /// Newest first.
+    fn list_media(&self, owner_id: &str) -> Result<Vec<Media>, DatabaseError>;
+}
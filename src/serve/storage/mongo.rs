@@ -0,0 +1,410 @@
+use super::super::configuration::OauthConfig;
+use super::super::database_errors::DatabaseError;
+use super::super::database_structures::{AccessRecord, LoginAttempts, LoginChallenge, Media, OauthState, Object, Post, SecurityEvent, TwoFactor, User};
+use super::{PostFilter, Storage};
+use bson::{doc, Document};
+use mongodb::options::{FindOneAndUpdateOptions, FindOptions, ReturnDocument};
+use mongodb::Database;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// The original backend, one collection per record type.
+pub struct MongoStorage {
+    pub database: Database,
+}
+
+impl MongoStorage {
+    pub fn new(database: Database) -> Self {
+        MongoStorage { database }
+    }
+
+    fn encode<T: Serialize>(value: &T) -> Result<Document, DatabaseError> {
+        match bson::to_bson(value) {
+            Ok(bson::Bson::Document(document)) => Ok(document),
+            Ok(_) => Err(DatabaseError::EncoderError(bson::EncoderError::Unknown(
+                "Records must encode to a document".to_string(),
+            ))),
+            Err(e) => Err(DatabaseError::EncoderError(e)),
+        }
+    }
+
+    fn find_one<T: DeserializeOwned>(&self, collection: &str, query: Document) -> Result<Option<T>, DatabaseError> {
+        match self.database.collection(collection).find_one(query, None) {
+            Ok(Some(document)) => match bson::from_bson::<T>(bson::Bson::Document(document)) {
+                Ok(value) => Ok(Some(value)),
+                Err(e) => Err(DatabaseError::DecoderError(e)),
+            },
+            Ok(None) => Ok(None),
+            Err(e) => Err(DatabaseError::Error(e)),
+        }
+    }
+
+    fn find_many<T: DeserializeOwned>(
+        &self,
+        collection: &str,
+        query: Document,
+        options: FindOptions,
+    ) -> Result<Vec<T>, DatabaseError> {
+        match self.database.collection(collection).find(query, options) {
+            Ok(cursor) => {
+                let mut values = Vec::new();
+                for result in cursor {
+                    match result {
+                        Ok(document) => match bson::from_bson::<T>(bson::Bson::Document(document)) {
+                            Ok(value) => values.push(value),
+                            Err(_e) => continue,
+                        },
+                        Err(e) => return Err(DatabaseError::Error(e)),
+                    }
+                }
+                Ok(values)
+            }
+            Err(e) => Err(DatabaseError::Error(e)),
+        }
+    }
+
+    fn insert<T: Serialize>(&self, collection: &str, value: &T) -> Result<(), DatabaseError> {
+        let document = MongoStorage::encode(value)?;
+        match self.database.collection(collection).insert_one(document, None) {
+            Ok(_result) => Ok(()),
+            Err(e) => Err(DatabaseError::Error(e)),
+        }
+    }
+
+    fn delete(&self, collection: &str, query: Document) -> Result<(), DatabaseError> {
+        match self.database.collection(collection).delete_one(query, None) {
+            Ok(_result) => Ok(()),
+            Err(e) => Err(DatabaseError::Error(e)),
+        }
+    }
+
+    fn delete_many(&self, collection: &str, query: Document) -> Result<(), DatabaseError> {
+        match self.database.collection(collection).delete_many(query, None) {
+            Ok(_result) => Ok(()),
+            Err(e) => Err(DatabaseError::Error(e)),
+        }
+    }
+}
+
+impl Storage for MongoStorage {
+    fn name(&self) -> String {
+        self.database.name().to_string()
+    }
+
+    fn find_user(&self, username: &str, email: Option<&str>) -> Result<Option<User>, DatabaseError> {
+        let query = match email {
+            Some(email) => doc! { "$or": [{"username" : username }, {"email": email}]},
+            None => doc! {"username" : username },
+        };
+        self.find_one("users", query)
+    }
+
+    fn find_user_by_email(&self, email: &str) -> Result<Option<User>, DatabaseError> {
+        self.find_one("users", doc! {"email": email})
+    }
+
+    fn insert_user(&self, user: &User) -> Result<(), DatabaseError> {
+        self.insert("users", user)
+    }
+
+    fn update_user(&self, username: &str, user: &User) -> Result<(), DatabaseError> {
+        let document = MongoStorage::encode(user)?;
+        match self
+            .database
+            .collection("users")
+            .update_one(doc! {"username": username}, document, None)
+        {
+            Ok(_result) => Ok(()),
+            Err(e) => Err(DatabaseError::Error(e)),
+        }
+    }
+
+    fn find_user_by_id(&self, id: &str) -> Result<Option<User>, DatabaseError> {
+        self.find_one("users", doc! {"id": id})
+    }
+
+    fn find_user_by_linked_account(&self, provider: &str, subject: &str) -> Result<Option<User>, DatabaseError> {
+        self.find_one(
+            "users",
+            doc! {"linked_accounts": {"$elemMatch": {"provider": provider, "subject": subject}}},
+        )
+    }
+
+    fn delete_user(&self, id: &str) -> Result<(), DatabaseError> {
+        self.delete("users", doc! {"id": id})
+    }
+
+    fn swap_two_factor(
+        &self,
+        user_id: &str,
+        previous: &TwoFactor,
+        two_factor: &TwoFactor,
+    ) -> Result<bool, DatabaseError> {
+        let previous = MongoStorage::encode(previous)?;
+        let two_factor = MongoStorage::encode(two_factor)?;
+        match self.database.collection("users").update_one(
+            doc! {"id": user_id, "two_factor": previous},
+            doc! {"$set": {"two_factor": two_factor}},
+            None,
+        ) {
+            Ok(result) => Ok(result.matched_count > 0),
+            Err(e) => Err(DatabaseError::Error(e)),
+        }
+    }
+
+    fn take_password_reset(&self, user_id: &str, reset_token: &str) -> Result<bool, DatabaseError> {
+        match self.database.collection("users").update_one(
+            doc! {"id": user_id, "password_reset.reset_token": reset_token},
+            doc! {"$set": {"password_reset": bson::Bson::Null}},
+            None,
+        ) {
+            Ok(result) => Ok(result.matched_count > 0),
+            Err(e) => Err(DatabaseError::Error(e)),
+        }
+    }
+
+    fn take_magic_link(&self, user_id: &str, login_token: &str) -> Result<bool, DatabaseError> {
+        match self.database.collection("users").update_one(
+            doc! {"id": user_id, "magic_link.login_token": login_token},
+            doc! {"$set": {"magic_link": bson::Bson::Null}},
+            None,
+        ) {
+            Ok(result) => Ok(result.matched_count > 0),
+            Err(e) => Err(DatabaseError::Error(e)),
+        }
+    }
+
+    fn list_users_pending_deletion(&self) -> Result<Vec<User>, DatabaseError> {
+        self.find_many("users", doc! {"deletion_scheduled": {"$type": "string"}}, FindOptions::default())
+    }
+
+    fn insert_session(&self, session: &AccessRecord) -> Result<(), DatabaseError> {
+        self.insert("sessions", session)
+    }
+
+    fn find_session_by_access_token(&self, access_token: &str) -> Result<Option<AccessRecord>, DatabaseError> {
+        self.find_one("sessions", doc! {"access_token": access_token})
+    }
+
+    fn find_session_by_refresh_token(&self, refresh_token: &str) -> Result<Option<AccessRecord>, DatabaseError> {
+        self.find_one("sessions", doc! {"refresh_token": refresh_token})
+    }
+
+    fn find_session_by_used_refresh_token(&self, refresh_token: &str) -> Result<Option<AccessRecord>, DatabaseError> {
+        self.find_one("sessions", doc! {"used_refresh_tokens": refresh_token})
+    }
+
+    fn replace_session(&self, session: &AccessRecord) -> Result<(), DatabaseError> {
+        let document = MongoStorage::encode(session)?;
+        match self
+            .database
+            .collection("sessions")
+            .replace_one(doc! {"id": session.id.clone()}, document, None)
+        {
+            Ok(_result) => Ok(()),
+            Err(e) => Err(DatabaseError::Error(e)),
+        }
+    }
+
+    fn swap_session(&self, session: &AccessRecord, refresh_token: &str) -> Result<bool, DatabaseError> {
+        let document = MongoStorage::encode(session)?;
+        match self.database.collection("sessions").replace_one(
+            doc! {"id": session.id.clone(), "refresh_token": refresh_token},
+            document,
+            None,
+        ) {
+            Ok(result) => Ok(result.matched_count > 0),
+            Err(e) => Err(DatabaseError::Error(e)),
+        }
+    }
+
+    fn delete_session(&self, id: &str) -> Result<(), DatabaseError> {
+        self.delete("sessions", doc! {"id": id})
+    }
+
+    fn delete_sessions(&self, user_id: &str) -> Result<(), DatabaseError> {
+        match self
+            .database
+            .collection("sessions")
+            .delete_many(doc! {"user_id": user_id}, None)
+        {
+            Ok(_result) => Ok(()),
+            Err(e) => Err(DatabaseError::Error(e)),
+        }
+    }
+
+    fn list_sessions(&self, user_id: &str) -> Result<Vec<AccessRecord>, DatabaseError> {
+        let options = FindOptions::builder()
+            .sort(doc! {"creation_time": -1})
+            .build();
+        self.find_many("sessions", doc! {"user_id": user_id}, options)
+    }
+
+    fn insert_security_event(&self, event: &SecurityEvent) -> Result<(), DatabaseError> {
+        self.insert("security_events", event)
+    }
+
+    fn list_security_events(&self, user_id: &str) -> Result<Vec<SecurityEvent>, DatabaseError> {
+        let options = FindOptions::builder()
+            .sort(doc! {"creation_time": -1})
+            .build();
+        self.find_many("security_events", doc! {"user_id": user_id}, options)
+    }
+
+    fn delete_security_events(&self, user_id: &str) -> Result<(), DatabaseError> {
+        self.delete_many("security_events", doc! {"user_id": user_id})
+    }
+
+    fn find_login_attempts(&self, key: &str) -> Result<Option<LoginAttempts>, DatabaseError> {
+        self.find_one("login_attempts", doc! {"key": key})
+    }
+
+    fn add_login_failure(&self, key: &str, now: &str, stale_before: &str) -> Result<LoginAttempts, DatabaseError> {
+        let collection = self.database.collection("login_attempts");
+        // Millisecond timestamps are all the same length, so they compare correctly as strings.
+        // Once a failure has been counted the counter isn't stale, so racing resets are harmless.
+        let stale = doc! {"key": key, "last_failure": {"$lt": stale_before}};
+        if let Err(e) = collection.update_one(stale, doc! {"$set": {"failures": 0}}, None) {
+            return Err(DatabaseError::Error(e));
+        }
+        let options = FindOneAndUpdateOptions::builder()
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .build();
+        let update = doc! {"$inc": {"failures": 1}, "$set": {"last_failure": now}};
+        match collection.find_one_and_update(doc! {"key": key}, update, options) {
+            Ok(Some(document)) => match bson::from_bson::<LoginAttempts>(bson::Bson::Document(document)) {
+                Ok(attempts) => Ok(attempts),
+                Err(e) => Err(DatabaseError::DecoderError(e)),
+            },
+            // The upsert always leaves a document behind
+            Ok(None) => Ok(LoginAttempts::new(key)),
+            Err(e) => Err(DatabaseError::Error(e)),
+        }
+    }
+
+    fn lock_login_attempts(&self, key: &str, until: &str) -> Result<(), DatabaseError> {
+        match self
+            .database
+            .collection("login_attempts")
+            .update_one(doc! {"key": key}, doc! {"$max": {"locked_until": until}}, None)
+        {
+            Ok(_result) => Ok(()),
+            Err(e) => Err(DatabaseError::Error(e)),
+        }
+    }
+
+    fn delete_login_attempts(&self, key: &str) -> Result<(), DatabaseError> {
+        self.delete("login_attempts", doc! {"key": key})
+    }
+
+    fn insert_login_challenge(&self, challenge: &LoginChallenge) -> Result<(), DatabaseError> {
+        self.insert("login_challenges", challenge)
+    }
+
+    fn find_login_challenge(&self, token: &str) -> Result<Option<LoginChallenge>, DatabaseError> {
+        self.find_one("login_challenges", doc! {"token": token})
+    }
+
+    fn delete_login_challenge(&self, id: &str) -> Result<(), DatabaseError> {
+        self.delete("login_challenges", doc! {"id": id})
+    }
+
+    fn delete_login_challenges(&self, user_id: &str) -> Result<(), DatabaseError> {
+        self.delete_many("login_challenges", doc! {"user_id": user_id})
+    }
+
+    fn insert_object(&self, object: &Object) -> Result<(), DatabaseError> {
+        self.insert("objects", object)
+    }
+
+    fn remove_object(&self, id: &str) -> Result<(), DatabaseError> {
+        self.delete("objects", doc! {"id": id})
+    }
+
+    fn insert_oauth_state(&self, state: &OauthState) -> Result<(), DatabaseError> {
+        self.insert("oauth_states", state)
+    }
+
+    fn find_oauth_state(&self, state: &str) -> Result<Option<OauthState>, DatabaseError> {
+        self.find_one("oauth_states", doc! {"state": state})
+    }
+
+    fn delete_oauth_state(&self, id: &str) -> Result<(), DatabaseError> {
+        self.delete("oauth_states", doc! {"id": id})
+    }
+
+    fn find_oauth_record(&self, name: &str) -> Result<Option<OauthConfig>, DatabaseError> {
+        self.find_one("oauth", doc! {"name": name})
+    }
+
+    fn insert_oauth_record(&self, oauth: &OauthConfig) -> Result<(), DatabaseError> {
+        self.insert("oauth", oauth)
+    }
+
+    fn find_post(&self, id: Option<&str>, slug: Option<&str>) -> Result<Option<Post>, DatabaseError> {
+        let mut query = doc! {};
+        if let Some(id) = id {
+            query.insert("id", id);
+        }
+        if let Some(slug) = slug {
+            query.insert("slug", slug);
+        }
+        self.find_one("posts", query)
+    }
+
+    fn insert_post(&self, post: &Post) -> Result<(), DatabaseError> {
+        self.insert("posts", post)
+    }
+
+    fn replace_post(&self, post: &Post) -> Result<(), DatabaseError> {
+        let document = MongoStorage::encode(post)?;
+        match self
+            .database
+            .collection("posts")
+            .replace_one(doc! {"id": post.id.clone()}, document, None)
+        {
+            Ok(_result) => Ok(()),
+            Err(e) => Err(DatabaseError::Error(e)),
+        }
+    }
+
+    fn delete_post(&self, id: &str) -> Result<(), DatabaseError> {
+        self.delete("posts", doc! {"id": id})
+    }
+
+    fn list_posts(&self, filter: &PostFilter, skip: i64, limit: i64) -> Result<Vec<Post>, DatabaseError> {
+        let mut query = doc! {};
+        if let Some(ref author_id) = filter.author_id {
+            query.insert("author_id", author_id.clone());
+        }
+        if let Some(ref status) = filter.status {
+            query.insert("status", status.as_str());
+        }
+        let options = FindOptions::builder()
+            .sort(doc! {"creation_time": -1})
+            .limit(limit)
+            .skip(skip)
+            .build();
+        self.find_many("posts", query, options)
+    }
+
+    fn insert_media(&self, media: &Media) -> Result<(), DatabaseError> {
+        self.insert("media", media)
+    }
+
+    fn find_media(&self, id: &str) -> Result<Option<Media>, DatabaseError> {
+        self.find_one("media", doc! {"id": id})
+    }
+
+    fn delete_media(&self, id: &str) -> Result<(), DatabaseError> {
+        self.delete("media", doc! {"id": id})
+    }
+
+    fn list_media(&self, owner_id: &str) -> Result<Vec<Media>, DatabaseError> {
+        let options = FindOptions::builder()
+            .sort(doc! {"creation_time": -1})
+            .build();
+        self.find_many("media", doc! {"owner_id": owner_id}, options)
+    }
+}
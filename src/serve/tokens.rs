@@ -0,0 +1,116 @@
+use super::database_errors::{DatabaseError, InvalidCredentialsError};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::sync::Arc;
+use std::time::SystemTime;
+use subtle::ConstantTimeEq;
+
+/// Hashes the secret tokens handed out to clients before they're stored. Hashes are keyed with
+/// `[auth] token_key` so a copy of the database alone isn't enough to forge a lookup.
+#[derive(Clone)]
+pub struct TokenHasher {
+    key: Arc<Vec<u8>>,
+}
+
+impl TokenHasher {
+    pub fn new(key: &str) -> Self {
+        TokenHasher {
+            key: Arc::new(key.as_bytes().to_vec()),
+        }
+    }
+
+    /// Hex encoded HMAC-SHA256 of `token`.
+    pub fn hash(&self, token: &str) -> String {
+        // HMAC takes keys of any length so this can't fail
+        let mut mac = Hmac::<Sha256>::new_varkey(&self.key).unwrap();
+        mac.input(token.as_bytes());
+        format!("{:x}", mac.result().code())
+    }
+
+    /// Checks `token` against a stored value, which is a hash unless the record predates hashing.
+    pub fn check(&self, token: &str, stored: &str, hashed: bool) -> bool {
+        if hashed {
+            constant_time_eq(&self.hash(token), stored)
+        } else {
+            constant_time_eq(token, stored)
+        }
+    }
+}
+
+/// Compares without bailing out at the first differing byte. Only the lengths leak.
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.as_bytes().ct_eq(b.as_bytes()).unwrap_u8() == 1
+}
+
+/// What a signed access token vouches for.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Claims {
+    /// The user's id.
+    pub sub: String,
+    /// The session the token was issued for, refreshing goes through it.
+    pub sid: String,
+    pub access_level: String,
+    /// Seconds since the epoch.
+    pub iat: u64,
+    pub exp: u64,
+}
+
+/// Issues and checks HS256 JWT access tokens for `access_tokens = "signed"`.
+#[derive(Clone)]
+pub struct TokenSigner {
+    key: Arc<Vec<u8>>,
+}
+
+impl TokenSigner {
+    pub fn new(key: &str) -> Self {
+        TokenSigner {
+            key: Arc::new(key.as_bytes().to_vec()),
+        }
+    }
+
+    fn header() -> String {
+        base64::encode_config(r#"{"alg":"HS256","typ":"JWT"}"#, base64::URL_SAFE_NO_PAD)
+    }
+
+    fn signature(&self, signing_input: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_varkey(&self.key).unwrap();
+        mac.input(signing_input.as_bytes());
+        base64::encode_config(mac.result().code(), base64::URL_SAFE_NO_PAD)
+    }
+
+    pub fn sign(&self, claims: &Claims) -> String {
+        // Claims only hold strings and integers so this can't fail
+        let payload = base64::encode_config(serde_json::to_vec(claims).unwrap(), base64::URL_SAFE_NO_PAD);
+        let signing_input = format!("{}.{}", TokenSigner::header(), payload);
+        let signature = self.signature(&signing_input);
+        format!("{}.{}", signing_input, signature)
+    }
+
+    /// The claims of a token this signer issued, as long as it hasn't expired.
+    pub fn verify(&self, token: &str) -> Result<Claims, DatabaseError> {
+        let invalid = || DatabaseError::InvalidCredentialsError(InvalidCredentialsError::new("Invalid access token"));
+        let parts: Vec<&str> = token.split('.').collect();
+        if parts.len() != 3 || !constant_time_eq(parts[0], &TokenSigner::header()) {
+            return Err(invalid());
+        }
+        let signature = self.signature(&format!("{}.{}", parts[0], parts[1]));
+        if !constant_time_eq(&signature, parts[2]) {
+            return Err(invalid());
+        }
+        let claims = base64::decode_config(parts[1], base64::URL_SAFE_NO_PAD)
+            .ok()
+            .and_then(|payload| serde_json::from_slice::<Claims>(&payload).ok())
+            .ok_or_else(invalid)?;
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        if claims.exp <= now {
+            return Err(DatabaseError::InvalidCredentialsError(InvalidCredentialsError::new(
+                "The access token has expired",
+            )));
+        }
+        Ok(claims)
+    }
+}
@@ -0,0 +1,60 @@
+use super::tokens::constant_time_eq;
+use hmac::{Hmac, Mac};
+use rand::{thread_rng, RngCore};
+use sha1::Sha1;
+use std::time::SystemTime;
+use url::percent_encoding::{utf8_percent_encode, USERINFO_ENCODE_SET};
+
+/// Seconds each code stays current for.
+pub const PERIOD: u64 = 30;
+const DIGITS: usize = 6;
+/// Codes from this many periods either side of now still work, to allow for clock drift.
+const SKEW: u64 = 1;
+const ALPHABET: base32::Alphabet = base32::Alphabet::RFC4648 { padding: false };
+
+/// A new random 160 bit secret, base32 encoded as authenticator apps expect.
+pub fn generate_secret() -> String {
+    let mut secret = [0u8; 20];
+    thread_rng().fill_bytes(&mut secret);
+    base32::encode(ALPHABET, &secret)
+}
+
+/// The time step the current code belongs to.
+pub fn current_step() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        / PERIOD
+}
+
+/// The RFC 6238 code for `step`, or `None` when `secret` isn't valid base32.
+pub fn code(secret: &str, step: u64) -> Option<String> {
+    let key = base32::decode(ALPHABET, secret)?;
+    // HMAC takes keys of any length so this can't fail
+    let mut mac = Hmac::<Sha1>::new_varkey(&key).unwrap();
+    mac.input(&step.to_be_bytes());
+    let digest = mac.result().code();
+    let offset = (digest[digest.len() - 1] & 0xf) as usize;
+    let binary = u32::from_be_bytes([digest[offset] & 0x7f, digest[offset + 1], digest[offset + 2], digest[offset + 3]]);
+    Some(format!("{:0width$}", binary % 10u32.pow(DIGITS as u32), width = DIGITS))
+}
+
+/// The step `code` was generated for if it's close enough to now. Steps up to `last_step` are
+/// refused so an observed code can't be replayed.
+pub fn verify(secret: &str, code: &str, last_step: u64) -> Option<u64> {
+    let now = current_step();
+    (now.saturating_sub(SKEW)..=now + SKEW).find(|&step| {
+        step > last_step && self::code(secret, step).is_some_and(|expected| constant_time_eq(&expected, code))
+    })
+}
+
+/// The `otpauth://` URI authenticator apps read from a QR code.
+pub fn provisioning_uri(issuer: &str, account: &str, secret: &str) -> String {
+    let issuer = utf8_percent_encode(issuer, USERINFO_ENCODE_SET).to_string();
+    let account = utf8_percent_encode(account, USERINFO_ENCODE_SET).to_string();
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        issuer, account, secret, issuer, DIGITS, PERIOD
+    )
+}
@@ -0,0 +1,242 @@
+use super::api::auth;
+use super::api::errors;
+use super::api::requests::{self, UploadFields};
+use super::api::v1::APIResponse;
+use super::configuration::{MediaConfig, RolesConfig};
+use super::database::DatabaseController;
+use super::database_errors::{DatabaseError, InvalidCredentialsError, NotFoundError};
+use super::database_structures::{Media, MediaVisibility, Principal};
+use super::server::Server;
+use futures::TryStreamExt;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use warp;
+use warp::http::StatusCode;
+use warp::multipart::{FormData, Part};
+use warp::reply::Response;
+use warp::{Buf, Filter, Rejection, Reply};
+
+pub struct Upload {
+    pub filename: String,
+    pub mime_type: String,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Clone)]
+pub struct Uploader {
+    pub server: Server,
+    pub config: MediaConfig,
+    pub roles: RolesConfig,
+}
+
+impl Uploader {
+    pub fn new(server: Server, config: MediaConfig, roles: RolesConfig) -> Self {
+        Uploader {
+            server,
+            config,
+            roles,
+        }
+    }
+
+    pub fn route(self) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+        let uploader = self.clone();
+        let downloader = self.clone();
+        // The action style path answers 200 like the rest of the action api, /api/v1/ answers 201
+        let legacy = warp::path!("api" / u8 / "media").map(|_version: u8| StatusCode::OK);
+        let v1 = warp::path!("api" / "v1" / "media").map(|| StatusCode::CREATED);
+        let upload = warp::post()
+            .and(legacy.or(v1).unify())
+            .and(auth::optional(self.server.clone()))
+            .and(warp::multipart::form().max_length(self.config.max_size))
+            .and_then(move |created: StatusCode, user: Option<Principal>, form: FormData| {
+                uploader.clone().upload(created, user, form)
+            });
+        let download = warp::get()
+            .and(warp::path!("media" / String))
+            .and(auth::optional(self.server))
+            .and_then(move |id: String, user: Option<Principal>| {
+                let downloader = downloader.clone();
+                errors::rejected(async move {
+                    let server = downloader.server.clone();
+                    server
+                        .run(move |server| Ok(downloader.download(server, id, user)))
+                        .await
+                })
+            });
+        upload.or(download).unify()
+    }
+
+    async fn read_part(part: Part) -> Result<Vec<u8>, warp::Error> {
+        part.stream()
+            .try_fold(Vec::new(), |mut bytes, buf| {
+                bytes.extend_from_slice(buf.bytes());
+                async move { Ok(bytes) }
+            })
+            .await
+    }
+
+    async fn upload(
+        self,
+        created: StatusCode,
+        user: Option<Principal>,
+        form: FormData,
+    ) -> Result<Response, Rejection> {
+        let unreadable = |e: warp::Error| {
+            let e = requests::invalid_field("file", "unreadable", format!("The upload could not be read: {}", e));
+            Ok(errors::error_reply(&e).into_response())
+        };
+        let mut fields: HashMap<String, String> = HashMap::new();
+        let mut upload: Option<Upload> = None;
+        let parts: Result<Vec<Part>, warp::Error> = form.try_collect().await;
+        match parts {
+            Ok(parts) => {
+                for part in parts {
+                    let name = part.name().to_string();
+                    let filename = part.filename().unwrap_or("").to_string();
+                    let mime_type = part
+                        .content_type()
+                        .unwrap_or("application/octet-stream")
+                        .to_string();
+                    match Uploader::read_part(part).await {
+                        Ok(bytes) => {
+                            if name == "file" {
+                                upload = Some(Upload {
+                                    filename,
+                                    mime_type,
+                                    bytes,
+                                });
+                            } else {
+                                fields.insert(name, String::from_utf8_lossy(&bytes).to_string());
+                            }
+                        }
+                        Err(e) => return unreadable(e),
+                    }
+                }
+            }
+            Err(e) => return unreadable(e),
+        }
+
+        let uploader = self.clone();
+        let stored = self
+            .server
+            .run(move |server| uploader.store(server, user, &fields, upload))
+            .await;
+        match stored {
+            Ok(media) => Ok(warp::reply::with_status(
+                warp::reply::json(&APIResponse {
+                    status: "success".to_string(),
+                    message: Some(media.url()),
+                    data: Some(media),
+                }),
+                created,
+            )
+            .into_response()),
+            Err(e) => Err(warp::reject::custom(e)),
+        }
+    }
+
+    fn store(
+        &self,
+        server: &Server,
+        user: Option<Principal>,
+        fields: &HashMap<String, String>,
+        upload: Option<Upload>,
+    ) -> Result<Media, DatabaseError> {
+        let upload = match upload {
+            Some(upload) => upload,
+            None => return Err(requests::invalid_field("file", "required", "Missing file field".to_string())),
+        };
+        if !self.config.allowed_types.contains(&upload.mime_type) {
+            return Err(requests::invalid_field(
+                "file",
+                "mime_type",
+                format!("{} is not an allowed media type", upload.mime_type),
+            ));
+        }
+        let upload_fields = requests::from_data::<UploadFields>(fields)?;
+        let visibility = upload_fields
+            .visibility
+            .and_then(|visibility| MediaVisibility::parse(&visibility))
+            .unwrap_or(MediaVisibility::Public);
+
+        // A bearer token takes precedence over credentials sent as form fields
+        let user = match user {
+            Some(user) => user,
+            None => match (fields.get("username"), fields.get("access_token")) {
+                (Some(username), Some(access_token)) => {
+                    Principal::from(&DatabaseController::authenticate(server, username, access_token)?)
+                }
+                _ => {
+                    return Err(DatabaseError::InvalidCredentialsError(
+                        InvalidCredentialsError::new("Missing username or access_token field"),
+                    ))
+                }
+            },
+        };
+        self.roles.require(&user, "upload_media")?;
+        let id = match DatabaseController::add_object(server, "media")? {
+            Some(id) => id,
+            None => {
+                return Err(DatabaseError::NotFoundError(NotFoundError::new(
+                    "Could not create a media object",
+                )))
+            }
+        };
+        let checksum = format!("{:x}", Sha256::digest(&upload.bytes));
+        let dir = Path::new(&self.config.storage_dir);
+        if let Err(e) = fs::create_dir_all(dir).and_then(|_| fs::write(dir.join(&id), &upload.bytes)) {
+            DatabaseController::remove_object(server, &id)?;
+            return Err(DatabaseError::IOError(e));
+        }
+        let media = Media::new(
+            id,
+            user.id,
+            upload.filename,
+            upload.mime_type,
+            upload.bytes.len() as i64,
+            checksum,
+            visibility,
+        );
+        DatabaseController::add_media(server, &media)
+    }
+
+    fn download(
+        &self,
+        server: &Server,
+        id: String,
+        user: Option<Principal>,
+    ) -> Response {
+        let media = match DatabaseController::find_media(server, &id) {
+            Ok(media) => media,
+            Err(e) => return errors::error_reply(&e).into_response(),
+        };
+        if media.visibility == MediaVisibility::Private {
+            // Private files are reported as missing to anyone but the owner, who has to send a
+            // bearer token so the credentials stay out of URLs and logs
+            if user.is_none_or(|user| user.id != media.owner_id) {
+                return errors::error_reply(&DatabaseError::NotFoundError(NotFoundError::new(
+                    "The requested media was not found",
+                )))
+                .into_response();
+            }
+        }
+        match fs::read(Path::new(&self.config.storage_dir).join(&media.id)) {
+            Ok(bytes) => {
+                let cache = match media.visibility {
+                    MediaVisibility::Public => "public, max-age=86400",
+                    MediaVisibility::Private => "private, no-store",
+                };
+                warp::http::Response::builder()
+                    .header("content-type", media.mime_type)
+                    .header("content-length", bytes.len())
+                    .header("cache-control", cache)
+                    .header("x-content-type-options", "nosniff")
+                    .body(bytes.into())
+                    .unwrap()
+            }
+            Err(e) => errors::error_reply(&DatabaseError::IOError(e)).into_response(),
+        }
+    }
+}
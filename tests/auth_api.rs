@@ -0,0 +1,1703 @@
+use qamaits::serve::api::errors;
+use qamaits::serve::api::v1::API;
+use qamaits::serve::authorizer::Authorizer;
+use qamaits::serve::configuration::{AccessTokenMode, ConfigWrapper, Configuration, SocialProvider, StorageBackend};
+use qamaits::serve::database::DatabaseController;
+use qamaits::serve::database_structures::{AccessRecord, LoginAttempts, LoginChallenge, SessionClient};
+use qamaits::serve::emailer::Emailer;
+use qamaits::serve::server::Server;
+use qamaits::serve::tokens::Claims;
+use qamaits::serve::totp;
+use qamaits::serve::uploader::Uploader;
+use lettre::SendableEmail;
+use curl::easy::{Easy, List};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+struct Harness {
+    server: Server,
+    emailer: Emailer,
+    config: Configuration,
+}
+
+impl Harness {
+    /// A server on the in-memory backend whose emailer keeps everything in its outbox.
+    fn new() -> Harness {
+        Harness::with_config(|_config| {})
+    }
+
+    /// Like `new` with `configure` applied to the settings first.
+    fn with_config<F: FnOnce(&mut Configuration)>(configure: F) -> Harness {
+        let mut config = ConfigWrapper::new("settings").unwrap().configuration;
+        // The shipped Argon2 cost takes seconds per hash in a debug build
+        config.auth.argon2_memory_cost = 1024;
+        config.auth.argon2_time_cost = 1;
+        // Requests sent over a socket come from here, as if through a local proxy
+        config.server.trusted_proxies = vec!["127.0.0.1".to_string()];
+        configure(&mut config);
+        config.database.backend = Some(StorageBackend::Memory);
+        // settings.toml ships a placeholder that doesn't parse as an address
+        config.email.from_address = "noreply@example.com".to_string();
+        let server = Server::instance(&config).unwrap();
+        // Registration refuses to go ahead without credentials for the email provider
+        let provider = config
+            .oauth
+            .auths
+            .iter()
+            .find(|auth| auth.name == config.email.provider)
+            .cloned()
+            .unwrap();
+        DatabaseController::add_oauth_record(&server, provider).unwrap();
+        Harness {
+            server,
+            emailer: Emailer::outbox(),
+            config,
+        }
+    }
+
+    fn routes(&self) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+        API::setup(
+            Arc::new(Mutex::new(self.emailer.clone())),
+            self.server.clone(),
+            Arc::new(self.config.clone()),
+        )
+        .or(Authorizer::new().route())
+        .or(Uploader::new(self.server.clone(), self.config.media.clone(), self.config.roles.clone()).route())
+        .recover(errors::handle_rejection)
+    }
+
+    /// A multipart upload of `file`, a mime type and its bytes, along with the form `fields`.
+    async fn upload(&self, token: &str, file: Option<(&str, &[u8])>, fields: &[(&str, &str)]) -> (StatusCode, Value) {
+        let mut form = Vec::new();
+        if let Some((mime_type, bytes)) = file {
+            form.extend_from_slice(b"--boundary\r\nContent-Disposition: form-data; name=\"file\"; filename=\"f\"\r\n");
+            form.extend_from_slice(format!("Content-Type: {}\r\n\r\n", mime_type).as_bytes());
+            form.extend_from_slice(bytes);
+            form.extend_from_slice(b"\r\n");
+        }
+        for (name, value) in fields {
+            let part = format!("--boundary\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n", name, value);
+            form.extend_from_slice(part.as_bytes());
+        }
+        form.extend_from_slice(b"--boundary--\r\n");
+        let response = warp::test::request()
+            .method("POST")
+            .path("/api/v1/media")
+            .header("authorization", format!("Bearer {}", token))
+            .header("content-type", "multipart/form-data; boundary=boundary")
+            .body(form)
+            .reply(&self.routes())
+            .await;
+        let body = serde_json::from_slice(response.body()).unwrap_or(Value::Null);
+        (response.status(), body)
+    }
+
+    async fn send(&self, method: &str, path: &str, token: Option<&str>, body: Option<Value>) -> (StatusCode, Value) {
+        let mut request = warp::test::request().method(method).path(path);
+        if let Some(token) = token {
+            request = request.header("authorization", format!("Bearer {}", token));
+        }
+        if let Some(body) = body {
+            request = request.json(&body);
+        }
+        let response = request.reply(&self.routes()).await;
+        let body = serde_json::from_slice(response.body()).unwrap_or(Value::Null);
+        (response.status(), body)
+    }
+
+    async fn register(&self, username: &str, email: &str) -> (StatusCode, Value) {
+        self.send(
+            "POST",
+            "/api/v1/users",
+            None,
+            Some(json!({"username": username, "password": "correct horse", "email": email})),
+        )
+        .await
+    }
+
+    /// The code from the last email sent, only hashes of it are stored.
+    fn emailed_code(&self) -> String {
+        let email: SendableEmail = self.emailer.sent().pop().unwrap().into();
+        let message = email.message_to_string().unwrap();
+        let start = message.find("code is: ").unwrap() + "code is: ".len();
+        message[start..].chars().take_while(|c| c.is_ascii_alphanumeric()).collect()
+    }
+
+    async fn verify(&self, username: &str, verify_token: &str) -> (StatusCode, Value) {
+        let body = json!({
+            "username": username,
+            "verify_token": verify_token,
+            "verify_code": self.emailed_code(),
+        });
+        self.send("POST", "/api/v1/users/verify", None, Some(body)).await
+    }
+
+    async fn login(&self, username: &str, password: &str) -> (StatusCode, Value) {
+        let body = json!({"username": username, "password": password});
+        self.send("POST", "/api/v1/sessions", None, Some(body)).await
+    }
+
+    async fn request_magic_link(&self, email: &str) -> (StatusCode, Value) {
+        let body = json!({"email": email});
+        self.send("POST", "/api/v1/sessions/magic_link", None, Some(body)).await
+    }
+
+    /// Sends a request over a real socket from 127.0.0.1. `warp::test` requests have no peer
+    /// address, so this is for whatever depends on it.
+    async fn send_over_socket(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &[String],
+        body: Option<Value>,
+    ) -> (StatusCode, Value) {
+        let (address, server) = warp::serve(self.routes()).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let url = format!("http://{}{}", address, path);
+        let method = method.to_string();
+        let headers = headers.to_vec();
+        tokio::task::spawn_blocking(move || {
+            let mut easy = Easy::new();
+            let mut list = List::new();
+            easy.url(&url).unwrap();
+            if let Some(body) = body {
+                list.append("Content-Type: application/json").unwrap();
+                easy.post_fields_copy(body.to_string().as_bytes()).unwrap();
+            }
+            easy.custom_request(&method).unwrap();
+            for header in headers {
+                list.append(&header).unwrap();
+            }
+            easy.http_headers(list).unwrap();
+            let mut response = Vec::new();
+            {
+                let mut transfer = easy.transfer();
+                transfer
+                    .write_function(|data| {
+                        response.extend_from_slice(data);
+                        Ok(data.len())
+                    })
+                    .unwrap();
+                transfer.perform().unwrap();
+            }
+            let status = StatusCode::from_u16(easy.response_code().unwrap() as u16).unwrap();
+            (status, serde_json::from_slice(&response).unwrap_or(Value::Null))
+        })
+        .await
+        .unwrap()
+    }
+
+    /// Tries the right password for `username` over a socket with `forwarded` as `X-Forwarded-For`.
+    async fn login_forwarded(&self, username: &str, forwarded: &str) -> StatusCode {
+        let body = json!({"username": username, "password": "correct horse"});
+        let headers = [format!("X-Forwarded-For: {}", forwarded)];
+        self.send_over_socket("POST", "/api/v1/sessions", &headers, Some(body)).await.0
+    }
+
+    /// Logs in as if from a named device behind the proxy, returning the response body.
+    async fn login_on(&self, username: &str, device: &str) -> Value {
+        let body = json!({"username": username, "password": "correct horse", "device": device});
+        let headers = [
+            format!("User-Agent: {} browser", device),
+            "X-Forwarded-For: 203.0.113.7".to_string(),
+        ];
+        let (status, body) = self.send_over_socket("POST", "/api/v1/sessions", &headers, Some(body)).await;
+        assert_eq!(status, StatusCode::CREATED, "{}", body);
+        body
+    }
+
+    /// Registers and verifies `username`, returning the verify token.
+    async fn verified_user(&self, username: &str, email: &str) -> String {
+        let (status, body) = self.register(username, email).await;
+        assert_eq!(status, StatusCode::CREATED, "{}", body);
+        let verify_token = body["data"]["verify_token"].as_str().unwrap().to_string();
+        let (status, body) = self.verify(username, &verify_token).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        verify_token
+    }
+
+    /// A verified `username` at `access_level`, returning an access token for them.
+    async fn member(&self, username: &str, access_level: &str) -> String {
+        self.verified_user(username, &format!("{}@example.com", username)).await;
+        let storage = &self.server.database.storage;
+        let mut user = storage.find_user(username, None).unwrap().unwrap();
+        user.access_level = access_level.to_string();
+        storage.update_user(username, &user).unwrap();
+        let (status, body) = self.login(username, "correct horse").await;
+        assert_eq!(status, StatusCode::CREATED, "{}", body);
+        body["data"]["access_token"].as_str().unwrap().to_string()
+    }
+}
+
+fn error_code(body: &Value) -> u64 {
+    body["data"]["code"].as_u64().unwrap()
+}
+
+/// The fields named in a validation failure.
+fn invalid_fields(body: &Value) -> Vec<String> {
+    body["data"]["fields"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|field| field["field"].as_str().unwrap().to_string())
+        .collect()
+}
+
+/// `token` with its payload swapped for `claims` but the original signature kept.
+fn forge(token: &str, claims: &Claims) -> String {
+    let parts: Vec<&str> = token.split('.').collect();
+    let payload = base64::encode_config(serde_json::to_vec(claims).unwrap(), base64::URL_SAFE_NO_PAD);
+    format!("{}.{}.{}", parts[0], payload, parts[2])
+}
+
+#[tokio::test]
+async fn register_verify_login_exchange_logout() {
+    let harness = Harness::new();
+    let (status, body) = harness.register("alice", "alice@example.com").await;
+    assert_eq!(status, StatusCode::CREATED, "{}", body);
+    assert!(body["data"]["user_id"].is_string());
+    assert_eq!(harness.emailer.sent().len(), 1);
+
+    let verify_token = body["data"]["verify_token"].as_str().unwrap().to_string();
+    let (status, body) = harness.verify("alice", &verify_token).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["data"]["verify_token"], verify_token.as_str());
+
+    let (status, body) = harness.login("alice", "correct horse").await;
+    assert_eq!(status, StatusCode::CREATED, "{}", body);
+    let access_token = body["data"]["access_token"].as_str().unwrap().to_string();
+    let refresh_token = body["data"]["refresh_token"].as_str().unwrap().to_string();
+
+    let (status, body) = harness.send("GET", "/api/v1/users/me", Some(&access_token), None).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["data"]["username"], "alice");
+
+    let exchange = json!({
+        "username": "alice",
+        "access_token": access_token,
+        "refresh_token": refresh_token,
+    });
+    let (status, body) = harness.send("POST", "/api/v1/sessions/refresh", None, Some(exchange)).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let new_access_token = body["data"]["access_token"].as_str().unwrap().to_string();
+    assert_ne!(new_access_token, access_token);
+
+    let (status, _body) = harness.send("GET", "/api/v1/users/me", Some(&access_token), None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let (status, body) = harness.send("DELETE", "/api/v1/sessions", Some(&new_access_token), None).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+
+    let (status, body) = harness.send("GET", "/api/v1/users/me", Some(&new_access_token), None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(error_code(&body), 1);
+}
+
+#[tokio::test]
+async fn login_with_wrong_password_fails() {
+    let harness = Harness::new();
+    harness.verified_user("bob", "bob@example.com").await;
+    let (status, body) = harness.login("bob", "battery staple").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(error_code(&body), 1);
+    assert_eq!(body["data"]["error"], "invalid_credentials");
+}
+
+#[tokio::test]
+async fn login_before_verification_fails() {
+    let harness = Harness::new();
+    let (status, _body) = harness.register("dave", "dave@example.com").await;
+    assert_eq!(status, StatusCode::CREATED);
+    let (status, body) = harness.login("dave", "correct horse").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(error_code(&body), 1);
+}
+
+#[tokio::test]
+async fn expired_verification_code_is_rejected() {
+    let harness = Harness::new();
+    let (_status, body) = harness.register("carol", "carol@example.com").await;
+    let verify_token = body["data"]["verify_token"].as_str().unwrap().to_string();
+
+    let storage = &harness.server.database.storage;
+    let mut user = storage.find_user("carol", None).unwrap().unwrap();
+    let mut verify = user.verify.clone().unwrap();
+    verify.expiration_time = "0".to_string();
+    user.verify = Some(verify);
+    storage.update_user("carol", &user).unwrap();
+
+    let (status, body) = harness.verify("carol", &verify_token).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(error_code(&body), 1);
+    let user = storage.find_user("carol", None).unwrap().unwrap();
+    assert!(!user.verify.unwrap().verified);
+}
+
+#[tokio::test]
+async fn wrong_verification_code_is_rejected() {
+    let harness = Harness::new();
+    let (_status, body) = harness.register("erin", "erin@example.com").await;
+    let body = json!({
+        "username": "erin",
+        "verify_token": body["data"]["verify_token"],
+        "verify_code": "nope",
+    });
+    let (status, _body) = harness.send("POST", "/api/v1/users/verify", None, Some(body)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn duplicate_username_is_rejected() {
+    let harness = Harness::new();
+    harness.verified_user("frank", "frank@example.com").await;
+    let (status, body) = harness.register("frank", "other@example.com").await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(error_code(&body), 3);
+    assert_eq!(body["message"], "There is already someone wth that username");
+}
+
+#[tokio::test]
+async fn duplicate_email_is_rejected() {
+    let harness = Harness::new();
+    harness.verified_user("grace", "grace@example.com").await;
+    let (status, body) = harness.register("heidi", "grace@example.com").await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(error_code(&body), 3);
+    assert_eq!(body["message"], "There is already an account with that email address");
+}
+
+#[tokio::test]
+async fn invalid_registration_lists_every_field() {
+    let harness = Harness::new();
+    let body = json!({"username": "x", "password": "", "email": "not an email"});
+    let (status, body) = harness.send("POST", "/api/v1/users", None, Some(body)).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(error_code(&body), 5);
+    assert_eq!(invalid_fields(&body), vec!["email", "password", "username"]);
+}
+
+#[tokio::test]
+async fn legacy_actions_report_failures_with_status_codes() {
+    let harness = Harness::new();
+    harness.verified_user("ivan", "ivan@example.com").await;
+    let body = json!({"data": {"username": "ivan", "password": "wrong"}});
+    let (status, body) = harness.send("POST", "/api/1/login", None, Some(body)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(error_code(&body), 1);
+
+    let body = json!({"data": {"username": "ivan", "password": "correct horse"}});
+    let (status, body) = harness.send("POST", "/api/1/login", None, Some(body)).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["status"], "success");
+}
+
+#[tokio::test]
+async fn unknown_oauth_provider_is_not_found() {
+    let harness = Harness::new();
+    let (status, body) = harness.send("GET", "/oauth-validate/nobody?code=a&state=b", None, None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(error_code(&body), 2);
+}
+
+#[tokio::test]
+async fn password_reset_replaces_password_and_ends_sessions() {
+    let harness = Harness::new();
+    harness.verified_user("judy", "judy@example.com").await;
+    let (_status, body) = harness.login("judy", "correct horse").await;
+    let access_token = body["data"]["access_token"].as_str().unwrap().to_string();
+
+    let body = json!({"email": "judy@example.com"});
+    let (status, body) = harness.send("POST", "/api/v1/password_resets", None, Some(body)).await;
+    assert_eq!(status, StatusCode::CREATED, "{}", body);
+    assert_eq!(harness.emailer.sent().len(), 2);
+    let reset_token = body["data"]["reset_token"].as_str().unwrap().to_string();
+    let reset_code = harness.emailed_code();
+
+    let body = json!({
+        "username": "judy",
+        "reset_token": reset_token,
+        "reset_code": reset_code,
+        "password": "battery staple",
+    });
+    let (status, body) = harness.send("POST", "/api/v1/password_resets/confirm", None, Some(body.clone())).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+
+    let (status, _body) = harness.send("GET", "/api/v1/users/me", Some(&access_token), None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _body) = harness.login("judy", "correct horse").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, body) = harness.login("judy", "battery staple").await;
+    assert_eq!(status, StatusCode::CREATED, "{}", body);
+}
+
+#[tokio::test]
+async fn password_reset_rejects_wrong_or_expired_code() {
+    let harness = Harness::with_config(|config| config.auth.lockout.reset_attempts = 3);
+    harness.verified_user("mallory", "mallory@example.com").await;
+    let request = json!({"email": "mallory@example.com"});
+    let (_status, body) = harness.send("POST", "/api/v1/password_resets", None, Some(request.clone())).await;
+    let reset_token = body["data"]["reset_token"].as_str().unwrap().to_string();
+    let confirm = |reset_token: &str, reset_code: &str| {
+        json!({
+            "username": "mallory",
+            "reset_token": reset_token,
+            "reset_code": reset_code,
+            "password": "battery staple",
+        })
+    };
+
+    // Enough wrong codes throw the reset away, so even the right one stops working
+    for _ in 0..3 {
+        let (status, body) =
+            harness.send("POST", "/api/v1/password_resets/confirm", None, Some(confirm(&reset_token, "nope"))).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(error_code(&body), 1);
+    }
+    let (status, _body) = harness
+        .send("POST", "/api/v1/password_resets/confirm", None, Some(confirm(&reset_token, &harness.emailed_code())))
+        .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let storage = &harness.server.database.storage;
+    assert!(storage.find_user("mallory", None).unwrap().unwrap().password_reset.is_none());
+
+    let (_status, body) = harness.send("POST", "/api/v1/password_resets", None, Some(request)).await;
+    let reset_token = body["data"]["reset_token"].as_str().unwrap().to_string();
+    let mut user = storage.find_user("mallory", None).unwrap().unwrap();
+    let mut reset = user.password_reset.clone().unwrap();
+    reset.expiration_time = "0".to_string();
+    user.password_reset = Some(reset);
+    storage.update_user("mallory", &user).unwrap();
+
+    let (status, _body) = harness
+        .send("POST", "/api/v1/password_resets/confirm", None, Some(confirm(&reset_token, &harness.emailed_code())))
+        .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn reset_and_login_emails_are_throttled_and_say_nothing_about_accounts() {
+    let harness = Harness::new();
+    harness.verified_user("olaf", "olaf@example.com").await;
+    let sent = harness.emailer.sent().len();
+    for path in ["/api/v1/password_resets", "/api/v1/sessions/magic_link"].iter() {
+        let mut replies = Vec::new();
+        for email in ["olaf@example.com", "olaf@example.com", "nobody@example.com"].iter() {
+            let (status, body) = harness.send("POST", path, None, Some(json!({ "email": email }))).await;
+            assert_eq!(status, StatusCode::CREATED, "{}", body);
+            replies.push(body);
+        }
+        // Only the first request within the interval sends anything, the rest look the same
+        assert_eq!(replies[0]["message"], replies[1]["message"]);
+        assert_eq!(replies[0]["message"], replies[2]["message"]);
+        let token = |reply: &Value| reply["data"].as_object().unwrap().values().next().unwrap().clone();
+        assert_eq!(token(&replies[0]).as_str().unwrap().len(), token(&replies[2]).as_str().unwrap().len());
+    }
+    assert_eq!(harness.emailer.sent().len(), sent + 2);
+
+    // Without a mail account every address gets the same failure
+    let mut harness = Harness::new();
+    harness.verified_user("olaf", "olaf@example.com").await;
+    harness.config.email.provider = "nowhere".to_string();
+    for path in ["/api/v1/password_resets", "/api/v1/sessions/magic_link"].iter() {
+        let (status, known) = harness.send("POST", path, None, Some(json!({"email": "olaf@example.com"}))).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        let (status, unknown) = harness.send("POST", path, None, Some(json!({"email": "nobody@example.com"}))).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(known, unknown);
+    }
+}
+
+#[tokio::test]
+async fn expired_verification_can_be_resent_and_verified_by_link() {
+    let harness = Harness::new();
+    let (_status, body) = harness.register("niaj", "niaj@example.com").await;
+    let old_token = body["data"]["verify_token"].as_str().unwrap().to_string();
+
+    let resend = json!({"username": "niaj"});
+    let (status, body) = harness.send("POST", "/api/v1/users/verify/resend", None, Some(resend.clone())).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(error_code(&body), 9);
+
+    let storage = &harness.server.database.storage;
+    let mut user = storage.find_user("niaj", None).unwrap().unwrap();
+    let mut verify = user.verify.clone().unwrap();
+    verify.expiration_time = "0".to_string();
+    verify.sent_time = Some("0".to_string());
+    user.verify = Some(verify);
+    storage.update_user("niaj", &user).unwrap();
+
+    let (status, body) = harness.send("POST", "/api/v1/users/verify/resend", None, Some(resend.clone())).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(harness.emailer.sent().len(), 2);
+    let verify_token = body["data"]["verify_token"].as_str().unwrap().to_string();
+    assert_ne!(verify_token, old_token);
+
+    let (status, _body) = harness.send("POST", "/api/v1/users/verify/resend", None, Some(resend.clone())).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+
+    let link = format!(
+        "/api/v1/users/verify?username=niaj&verify_token={}&verify_code={}",
+        verify_token,
+        harness.emailed_code()
+    );
+    let (status, body) = harness.send("GET", &link, None, None).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let (status, body) = harness.login("niaj", "correct horse").await;
+    assert_eq!(status, StatusCode::CREATED, "{}", body);
+
+    let (status, _body) = harness.send("POST", "/api/v1/users/verify/resend", None, Some(resend)).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+}
+
+#[tokio::test]
+async fn sessions_are_independent_per_device() {
+    let harness = Harness::new();
+    harness.verified_user("olivia", "olivia@example.com").await;
+    let laptop = harness.login_on("olivia", "laptop").await;
+    let phone = harness.login_on("olivia", "phone").await;
+    let tablet = harness.login_on("olivia", "tablet").await;
+    let laptop_token = laptop["data"]["access_token"].as_str().unwrap();
+    let phone_token = phone["data"]["access_token"].as_str().unwrap();
+    let tablet_token = tablet["data"]["access_token"].as_str().unwrap();
+    assert_ne!(laptop_token, phone_token);
+
+    let (status, body) = harness.send("GET", "/api/v1/sessions", Some(laptop_token), None).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let sessions = body["data"].as_array().unwrap();
+    assert_eq!(sessions.len(), 3);
+    let current: Vec<&Value> = sessions.iter().filter(|session| session["current"] == true).collect();
+    assert_eq!(current.len(), 1);
+    assert_eq!(current[0]["device"], "laptop");
+    assert_eq!(current[0]["user_agent"], "laptop browser");
+    assert_eq!(current[0]["ip"], "203.0.113.7");
+    assert!(current[0].get("access_token").is_none());
+
+    // Logging out on the laptop leaves the phone signed in
+    let (status, _body) = harness.send("DELETE", "/api/v1/sessions", Some(laptop_token), None).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _body) = harness.send("GET", "/api/v1/users/me", Some(laptop_token), None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _body) = harness.send("GET", "/api/v1/users/me", Some(phone_token), None).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let path = format!("/api/v1/sessions/{}", tablet["data"]["session_id"].as_str().unwrap());
+    let (status, _body) = harness.send("DELETE", &path, Some(phone_token), None).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _body) = harness.send("GET", "/api/v1/users/me", Some(tablet_token), None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _body) = harness.send("DELETE", &path, Some(phone_token), None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let (_status, other) = harness.login("olivia", "correct horse").await;
+    let other_token = other["data"]["access_token"].as_str().unwrap();
+    let (status, _body) = harness.send("DELETE", "/api/v1/sessions/all", Some(phone_token), None).await;
+    assert_eq!(status, StatusCode::OK);
+    for token in &[phone_token, other_token] {
+        let (status, _body) = harness.send("GET", "/api/v1/users/me", Some(token), None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+}
+
+#[tokio::test]
+async fn refreshing_one_session_keeps_the_others() {
+    let harness = Harness::new();
+    harness.verified_user("peggy", "peggy@example.com").await;
+    let (_status, first) = harness.login("peggy", "correct horse").await;
+    let (_status, second) = harness.login("peggy", "correct horse").await;
+
+    let exchange = json!({
+        "username": "peggy",
+        "access_token": first["data"]["access_token"],
+        "refresh_token": first["data"]["refresh_token"],
+    });
+    let (status, body) = harness.send("POST", "/api/v1/sessions/refresh", None, Some(exchange)).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+
+    let second_token = second["data"]["access_token"].as_str().unwrap();
+    let (status, _body) = harness.send("GET", "/api/v1/users/me", Some(second_token), None).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let exchange = json!({"refresh_token": "not a refresh token"});
+    let (status, _body) = harness.send("POST", "/api/v1/sessions/refresh", None, Some(exchange)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn reusing_a_refresh_token_revokes_its_session() {
+    let harness = Harness::new();
+    harness.verified_user("trent", "trent@example.com").await;
+    let (_status, stolen) = harness.login("trent", "correct horse").await;
+    let (_status, other) = harness.login("trent", "correct horse").await;
+
+    // The refresh token alone is enough
+    let exchange = json!({"refresh_token": stolen["data"]["refresh_token"]});
+    let (status, body) = harness.send("POST", "/api/v1/sessions/refresh", None, Some(exchange.clone())).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let rotated = body["data"]["access_token"].as_str().unwrap().to_string();
+    let rotated_refresh = body["data"]["refresh_token"].clone();
+
+    let (status, body) = harness.send("POST", "/api/v1/sessions/refresh", None, Some(exchange)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(error_code(&body), 1);
+
+    // Everything issued to that session is dead, other sessions are untouched
+    let (status, _body) = harness.send("GET", "/api/v1/users/me", Some(&rotated), None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let exchange = json!({"refresh_token": rotated_refresh});
+    let (status, _body) = harness.send("POST", "/api/v1/sessions/refresh", None, Some(exchange)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let other_token = other["data"]["access_token"].as_str().unwrap();
+    let (status, _body) = harness.send("GET", "/api/v1/users/me", Some(other_token), None).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let storage = &harness.server.database.storage;
+    let user = storage.find_user("trent", None).unwrap().unwrap();
+    let events = storage.list_security_events(&user.id).unwrap();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].kind, "refresh_token_reuse");
+    assert_eq!(events[0].session_id.as_deref(), stolen["data"]["session_id"].as_str());
+}
+
+#[tokio::test]
+async fn concurrent_refreshes_with_one_token_count_as_reuse() {
+    let harness = Harness::new();
+    harness.verified_user("ursula", "ursula@example.com").await;
+    let (_status, body) = harness.login("ursula", "correct horse").await;
+    let exchange = json!({"refresh_token": body["data"]["refresh_token"]});
+    let refreshes = (0..8).map(|_| harness.send("POST", "/api/v1/sessions/refresh", None, Some(exchange.clone())));
+    let statuses: Vec<StatusCode> = futures::future::join_all(refreshes)
+        .await
+        .into_iter()
+        .map(|(status, _body)| status)
+        .collect();
+    assert_eq!(statuses.iter().filter(|status| **status == StatusCode::OK).count(), 1);
+    assert!(statuses.contains(&StatusCode::UNAUTHORIZED));
+
+    let storage = &harness.server.database.storage;
+    let user = storage.find_user("ursula", None).unwrap().unwrap();
+    assert!(storage.list_sessions(&user.id).unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn tokens_are_only_stored_hashed() {
+    let harness = Harness::new();
+    let verify_token = harness.verified_user("quinn", "quinn@example.com").await;
+    let storage = &harness.server.database.storage;
+    let tokens = &harness.server.database.tokens;
+    let user = storage.find_user("quinn", None).unwrap().unwrap();
+    let verify = user.verify.unwrap();
+    assert!(verify.hashed);
+    assert_eq!(verify.verify_token, tokens.hash(&verify_token));
+
+    let (_status, body) = harness.login("quinn", "correct horse").await;
+    let access_token = body["data"]["access_token"].as_str().unwrap();
+    let refresh_token = body["data"]["refresh_token"].as_str().unwrap();
+    let sessions = storage.list_sessions(&user.id).unwrap();
+    assert_eq!(sessions.len(), 1);
+    assert_eq!(sessions[0].access_token, tokens.hash(access_token));
+    assert_eq!(sessions[0].refresh_token.as_deref(), Some(tokens.hash(refresh_token).as_str()));
+
+    // What's in the database doesn't work as a token
+    let (status, _body) = harness.send("GET", "/api/v1/users/me", Some(&sessions[0].access_token), None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn plaintext_sessions_are_migrated_on_use() {
+    let harness = Harness::new();
+    harness.verified_user("rupert", "rupert@example.com").await;
+    let storage = &harness.server.database.storage;
+    let user = storage.find_user("rupert", None).unwrap().unwrap();
+    let legacy = AccessRecord::new(user.id.clone(), &SessionClient::default(), &harness.config.auth);
+    assert!(!legacy.hashed);
+    storage.insert_session(&legacy).unwrap();
+
+    let (status, body) = harness.send("GET", "/api/v1/users/me", Some(&legacy.access_token), None).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let stored = storage.list_sessions(&user.id).unwrap().pop().unwrap();
+    assert!(stored.hashed);
+    assert_eq!(stored.access_token, harness.server.database.tokens.hash(&legacy.access_token));
+
+    let (status, _body) = harness.send("GET", "/api/v1/users/me", Some(&legacy.access_token), None).await;
+    assert_eq!(status, StatusCode::OK);
+    let exchange = json!({
+        "username": "rupert",
+        "access_token": legacy.access_token,
+        "refresh_token": legacy.refresh_token,
+    });
+    let (status, body) = harness.send("POST", "/api/v1/sessions/refresh", None, Some(exchange)).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+}
+
+#[tokio::test]
+async fn signed_access_tokens_are_checked_by_signature() {
+    let harness = Harness::with_config(|config| {
+        config.auth.access_tokens = Some(AccessTokenMode::Signed);
+        config.auth.signing_key = Some("a signing key only these tests know".to_string());
+    });
+    harness.verified_user("sybil", "sybil@example.com").await;
+    let (status, body) = harness.login("sybil", "correct horse").await;
+    assert_eq!(status, StatusCode::CREATED, "{}", body);
+    let access_token = body["data"]["access_token"].as_str().unwrap().to_string();
+    let refresh_token = body["data"]["refresh_token"].as_str().unwrap().to_string();
+    assert_eq!(access_token.split('.').count(), 3);
+
+    let signer = harness.server.database.signer.clone().unwrap();
+    let claims = signer.verify(&access_token).unwrap();
+    assert_eq!(claims.sid, body["data"]["session_id"].as_str().unwrap());
+    assert_eq!(claims.access_level, "subscriber");
+
+    let (status, body) = harness.send("GET", "/api/v1/users/me", Some(&access_token), None).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["data"]["username"], "sybil");
+
+    // Permissions are read off the claims, so a change only shows once the token is refreshed
+    let storage = &harness.server.database.storage;
+    let mut user = storage.find_user("sybil", None).unwrap().unwrap();
+    user.access_level = "admin".to_string();
+    storage.update_user("sybil", &user).unwrap();
+    let (status, _body) = harness.send("DELETE", "/api/v1/users/sybil/lockout", Some(&access_token), None).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let mut forged = claims.clone();
+    forged.access_level = "admin".to_string();
+    let forged = forge(&access_token, &forged);
+    let (status, _body) = harness.send("GET", "/api/v1/users/me", Some(&forged), None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let expired = signer.sign(&Claims { exp: claims.iat - 1, ..claims.clone() });
+    let (status, body) = harness.send("GET", "/api/v1/users/me", Some(&expired), None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["message"], "The access token has expired");
+
+    let exchange = json!({
+        "username": "sybil",
+        "access_token": access_token,
+        "refresh_token": refresh_token,
+    });
+    let (status, body) = harness.send("POST", "/api/v1/sessions/refresh", None, Some(exchange)).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let access_token = body["data"]["access_token"].as_str().unwrap().to_string();
+    let refresh_token = body["data"]["refresh_token"].as_str().unwrap().to_string();
+    assert_eq!(signer.verify(&access_token).unwrap().access_level, "admin");
+    let (status, body) = harness.send("DELETE", "/api/v1/users/sybil/lockout", Some(&access_token), None).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+
+    // Logging out revokes the refresh token and the account routes, the rest of the access token
+    // runs out on its own
+    let (status, _body) = harness.send("DELETE", "/api/v1/sessions", Some(&access_token), None).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _body) = harness.send("GET", "/api/v1/users/me", Some(&access_token), None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _body) = harness.send("GET", "/api/v1/media", Some(&access_token), None).await;
+    assert_eq!(status, StatusCode::OK);
+    let exchange = json!({
+        "username": "sybil",
+        "access_token": access_token,
+        "refresh_token": refresh_token,
+    });
+    let (status, _body) = harness.send("POST", "/api/v1/sessions/refresh", None, Some(exchange)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn signed_access_tokens_refuse_a_guessable_key() {
+    let mut config = ConfigWrapper::new("settings").unwrap().configuration;
+    config.database.backend = Some(StorageBackend::Memory);
+    config.auth.access_tokens = Some(AccessTokenMode::Signed);
+    for key in ["SIGNING_KEY", "", "short"].iter() {
+        config.auth.signing_key = Some(key.to_string());
+        assert!(Server::instance(&config).is_err(), "{:?} was accepted", key);
+    }
+    config.auth.signing_key = Some("a signing key only these tests know".to_string());
+    assert!(Server::instance(&config).is_ok());
+}
+
+#[tokio::test]
+async fn code_format_and_lifetimes_come_from_config() {
+    let harness = Harness::with_config(|config| {
+        config.auth.code_length = 8;
+        config.auth.code_alphabet = "0123456789".to_string();
+        config.auth.refresh_token_ttl = 0;
+    });
+    let (status, body) = harness.register("uma", "uma@example.com").await;
+    assert_eq!(status, StatusCode::CREATED, "{}", body);
+    let code = harness.emailed_code();
+    assert_eq!(code.len(), 8);
+    assert!(code.chars().all(|c| c.is_ascii_digit()));
+
+    let verify_token = body["data"]["verify_token"].as_str().unwrap().to_string();
+    let (status, body) = harness.verify("uma", &verify_token).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let (_status, body) = harness.login("uma", "correct horse").await;
+    let exchange = json!({"refresh_token": body["data"]["refresh_token"]});
+    let (status, body) = harness.send("POST", "/api/v1/sessions/refresh", None, Some(exchange)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["message"], "The refresh token has expired, please login");
+}
+
+#[tokio::test]
+async fn legacy_bcrypt_passwords_are_rehashed_on_login() {
+    let harness = Harness::new();
+    harness.verified_user("victor", "victor@example.com").await;
+    let storage = &harness.server.database.storage;
+    let mut user = storage.find_user("victor", None).unwrap().unwrap();
+    assert!(user.password.starts_with("$argon2id$v=19$m=1024,t=1,p=1$"));
+    user.password = bcrypt::hash("correct horse", 5).unwrap();
+    storage.update_user("victor", &user).unwrap();
+
+    let (status, _body) = harness.login("victor", "wrong horse").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert!(storage.find_user("victor", None).unwrap().unwrap().password.starts_with("$2"));
+
+    let (status, body) = harness.login("victor", "correct horse").await;
+    assert_eq!(status, StatusCode::CREATED, "{}", body);
+    let rehashed = storage.find_user("victor", None).unwrap().unwrap().password;
+    assert!(rehashed.starts_with("$argon2id$"));
+    let (status, body) = harness.login("victor", "correct horse").await;
+    assert_eq!(status, StatusCode::CREATED, "{}", body);
+    assert_eq!(storage.find_user("victor", None).unwrap().unwrap().password, rehashed);
+}
+
+#[tokio::test]
+async fn passwords_must_meet_the_policy() {
+    let breached = std::env::temp_dir().join(format!("breached-{}.txt", std::process::id()));
+    std::fs::write(&breached, "password1\nCorrect-Horse-1\n").unwrap();
+    let harness = Harness::with_config(|config| {
+        let policy = &mut config.auth.password_policy;
+        policy.min_length = 10;
+        policy.require_uppercase = true;
+        policy.require_digit = true;
+        policy.require_symbol = true;
+        policy.breached_passwords = Some(breached.to_str().unwrap().to_string());
+    });
+    let register = |password: &str| {
+        json!({"username": "wendy", "password": password, "email": "wendy.99@example.com"})
+    };
+
+    let (status, body) = harness.send("POST", "/api/v1/users", None, Some(register("short"))).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(error_code(&body), 5);
+    let codes: Vec<&str> = body["data"]["fields"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|field| field["code"].as_str().unwrap())
+        .collect();
+    assert_eq!(codes, vec!["too_short", "uppercase", "digit", "symbol"]);
+    assert_eq!(body["data"]["fields"][0]["message"], "must be at least 10 characters");
+
+    for (password, code) in &[("Correct-Horse-1", "breached"), ("WENDY.99@example.com", "personal")] {
+        let (status, body) = harness.send("POST", "/api/v1/users", None, Some(register(password))).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["data"]["fields"][0]["code"], *code, "{}", body);
+    }
+
+    let (status, body) = harness.send("POST", "/api/v1/users", None, Some(register("Battery-Staple-9"))).await;
+    assert_eq!(status, StatusCode::CREATED, "{}", body);
+    std::fs::remove_file(&breached).unwrap();
+}
+
+#[tokio::test]
+async fn repeated_failed_logins_lock_the_account_until_unlocked() {
+    let harness = Harness::with_config(|config| {
+        config.auth.lockout.account_attempts = 3;
+        config.auth.lockout.base_delay = 60;
+    });
+    harness.verified_user("xavier", "xavier@example.com").await;
+    for _ in 0..2 {
+        let (status, body) = harness.login("xavier", "wrong horse").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["message"], "Invalid Passoword");
+    }
+    let (status, body) = harness.login("xavier", "wrong horse").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["message"], "Invalid Passoword, the account is locked for 60 seconds");
+
+    // Even the right password is turned away while locked
+    let (status, body) = harness.login("xavier", "correct horse").await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(error_code(&body), 9);
+    assert!(body["message"].as_str().unwrap().starts_with("Too many failed logins"));
+
+    harness.verified_user("yvonne", "yvonne@example.com").await;
+    let storage = &harness.server.database.storage;
+    let mut admin = storage.find_user("yvonne", None).unwrap().unwrap();
+    let (_status, body) = harness.login("yvonne", "correct horse").await;
+    let token = body["data"]["access_token"].as_str().unwrap().to_string();
+    let (status, _body) = harness.send("DELETE", "/api/v1/users/xavier/lockout", Some(&token), None).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    admin.access_level = "admin".to_string();
+    storage.update_user("yvonne", &admin).unwrap();
+    let (status, body) = harness.send("DELETE", "/api/v1/users/xavier/lockout", Some(&token), None).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+
+    let (status, body) = harness.login("xavier", "correct horse").await;
+    assert_eq!(status, StatusCode::CREATED, "{}", body);
+    let user = storage.find_user("xavier", None).unwrap().unwrap();
+    let kinds: Vec<String> = storage
+        .list_security_events(&user.id)
+        .unwrap()
+        .into_iter()
+        .map(|event| event.kind)
+        .collect();
+    assert!(kinds.contains(&"account_locked".to_string()));
+    assert!(kinds.contains(&"account_unlocked".to_string()));
+}
+
+#[tokio::test]
+async fn concurrent_failed_logins_are_all_counted() {
+    let harness = Harness::with_config(|config| {
+        config.auth.lockout.account_attempts = 100;
+    });
+    harness.verified_user("wanda", "wanda@example.com").await;
+    let logins = (0..16).map(|_| harness.login("wanda", "wrong horse"));
+    for (status, _body) in futures::future::join_all(logins).await {
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+    let storage = &harness.server.database.storage;
+    let user = storage.find_user("wanda", None).unwrap().unwrap();
+    let attempts = storage.find_login_attempts(&LoginAttempts::account_key(&user.id)).unwrap().unwrap();
+    assert_eq!(attempts.failures, 16);
+}
+
+#[tokio::test]
+async fn failed_logins_from_one_address_lock_that_address() {
+    // Behind the proxy the address it saw counts, not whatever the client put in front of it
+    let harness = Harness::with_config(|config| {
+        config.auth.lockout.ip_attempts = 2;
+    });
+    harness.verified_user("zelda", "zelda@example.com").await;
+    assert_eq!(harness.login_forwarded("nobody", "198.51.100.7").await, StatusCode::NOT_FOUND);
+    assert_eq!(harness.login_forwarded("no one", "198.51.100.7").await, StatusCode::NOT_FOUND);
+    assert_eq!(harness.login_forwarded("zelda", "192.0.2.9, 198.51.100.7").await, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(harness.login_forwarded("zelda", "198.51.100.8").await, StatusCode::CREATED);
+
+    // Straight from a client the header is ignored, so changing it doesn't get past the lock
+    let harness = Harness::with_config(|config| {
+        config.auth.lockout.ip_attempts = 2;
+        config.server.trusted_proxies = Vec::new();
+    });
+    harness.verified_user("zelda", "zelda@example.com").await;
+    assert_eq!(harness.login_forwarded("nobody", "192.0.2.1").await, StatusCode::NOT_FOUND);
+    assert_eq!(harness.login_forwarded("no one", "192.0.2.2").await, StatusCode::NOT_FOUND);
+    assert_eq!(harness.login_forwarded("zelda", "192.0.2.3").await, StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test]
+async fn totp_logins_need_a_second_step() {
+    let harness = Harness::new();
+    harness.verified_user("alice", "alice@example.com").await;
+    let (_status, body) = harness.login("alice", "correct horse").await;
+    let token = body["data"]["access_token"].as_str().unwrap().to_string();
+
+    let (status, body) = harness.send("POST", "/api/v1/users/me/two_factor", Some(&token), None).await;
+    assert_eq!(status, StatusCode::CREATED, "{}", body);
+    let secret = body["data"]["secret"].as_str().unwrap().to_string();
+    let uri = body["data"]["provisioning_uri"].as_str().unwrap();
+    assert!(uri.starts_with("otpauth://totp/qamaits:alice?secret="), "{}", uri);
+    let recovery_codes = body["data"]["recovery_codes"].as_array().unwrap().clone();
+    assert_eq!(recovery_codes.len(), 10);
+
+    // Until confirmed, logins are unchanged
+    let (status, _body) = harness.login("alice", "correct horse").await;
+    assert_eq!(status, StatusCode::CREATED);
+    let confirm = |code: String| json!({ "code": code });
+    let (status, _body) = harness
+        .send("POST", "/api/v1/users/me/two_factor/confirm", Some(&token), Some(confirm("000000".to_string())))
+        .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let step = totp::current_step();
+    let code = totp::code(&secret, step).unwrap();
+    let (status, body) = harness
+        .send("POST", "/api/v1/users/me/two_factor/confirm", Some(&token), Some(confirm(code.clone())))
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["data"]["two_factor"], true);
+
+    let challenge = |harness: &Harness| {
+        let routes = harness.routes();
+        async move {
+            let response = warp::test::request()
+                .method("POST")
+                .path("/api/v1/sessions")
+                .json(&json!({"username": "alice", "password": "correct horse"}))
+                .reply(&routes)
+                .await;
+            assert_eq!(response.status(), StatusCode::ACCEPTED);
+            let body: Value = serde_json::from_slice(response.body()).unwrap();
+            assert_eq!(body["data"]["two_factor_required"], true);
+            body["data"]["challenge_token"].as_str().unwrap().to_string()
+        }
+    };
+    let finish = |challenge_token: &str, code: &str| json!({"challenge_token": challenge_token, "code": code});
+
+    // The code that confirmed enrollment can't be replayed
+    let challenge_token = challenge(&harness).await;
+    let (status, body) = harness
+        .send("POST", "/api/v1/sessions/two_factor", None, Some(finish(&challenge_token, &code)))
+        .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["message"], "Invalid two factor code");
+    let recovery = recovery_codes[0].as_str().unwrap().to_uppercase();
+    let (status, body) = harness
+        .send("POST", "/api/v1/sessions/two_factor", None, Some(finish(&challenge_token, &recovery)))
+        .await;
+    assert_eq!(status, StatusCode::CREATED, "{}", body);
+    let session_token = body["data"]["access_token"].as_str().unwrap().to_string();
+    let (status, _body) = harness.send("GET", "/api/v1/users/me", Some(&session_token), None).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // Challenges and recovery codes are single use
+    let (status, _body) = harness
+        .send("POST", "/api/v1/sessions/two_factor", None, Some(finish(&challenge_token, &recovery)))
+        .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let challenge_token = challenge(&harness).await;
+    let (status, _body) = harness
+        .send("POST", "/api/v1/sessions/two_factor", None, Some(finish(&challenge_token, &recovery)))
+        .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let next = totp::code(&secret, step + 1).unwrap();
+    let (status, body) = harness
+        .send("POST", "/api/v1/sessions/two_factor", None, Some(finish(&challenge_token, &next)))
+        .await;
+    assert_eq!(status, StatusCode::CREATED, "{}", body);
+
+    // Two logins racing with the same recovery code get one session between them
+    let challenge_tokens = vec![challenge(&harness).await, challenge(&harness).await];
+    let recovery = recovery_codes[1].as_str().unwrap();
+    let logins = challenge_tokens.iter().map(|challenge_token| {
+        harness.send("POST", "/api/v1/sessions/two_factor", None, Some(finish(challenge_token, recovery)))
+    });
+    let results = futures::future::join_all(logins).await;
+    let sessions = results.iter().filter(|(status, _body)| *status == StatusCode::CREATED).count();
+    assert_eq!(sessions, 1, "{:?}", results);
+
+    let user = harness.server.database.storage.find_user("alice", None).unwrap().unwrap();
+    let stored = user.two_factor.unwrap();
+    assert_eq!(stored.recovery_codes.len(), 8);
+    assert!(!stored.recovery_codes.iter().any(|code| recovery_codes.contains(&json!(code))));
+
+    let (status, body) = harness
+        .send("DELETE", "/api/v1/users/me/two_factor", Some(&token), Some(json!({"password": "correct horse"})))
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let (status, _body) = harness.login("alice", "correct horse").await;
+    assert_eq!(status, StatusCode::CREATED);
+}
+
+#[tokio::test]
+async fn wrong_totp_codes_count_across_password_logins() {
+    let harness = Harness::with_config(|config| {
+        config.auth.lockout.account_attempts = 3;
+    });
+    harness.verified_user("bruno", "bruno@example.com").await;
+    let (_status, body) = harness.login("bruno", "correct horse").await;
+    let token = body["data"]["access_token"].as_str().unwrap().to_string();
+    let (_status, body) = harness.send("POST", "/api/v1/users/me/two_factor", Some(&token), None).await;
+    let code = totp::code(body["data"]["secret"].as_str().unwrap(), totp::current_step()).unwrap();
+    let (status, body) = harness
+        .send("POST", "/api/v1/users/me/two_factor/confirm", Some(&token), Some(json!({ "code": code })))
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+
+    // Getting the password right again doesn't earn more guesses at the code
+    for _ in 0..3 {
+        let (status, body) = harness.login("bruno", "correct horse").await;
+        assert_eq!(status, StatusCode::ACCEPTED, "{}", body);
+        let finish = json!({"challenge_token": body["data"]["challenge_token"], "code": "not a code"});
+        let (status, _body) = harness.send("POST", "/api/v1/sessions/two_factor", None, Some(finish)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+    let (status, _body) = harness.login("bruno", "correct horse").await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test]
+async fn wrong_current_passwords_count_towards_the_lockout() {
+    let harness = Harness::with_config(|config| {
+        config.auth.lockout.account_attempts = 2;
+        config.auth.lockout.base_delay = 60;
+    });
+    harness.verified_user("cleo", "cleo@example.com").await;
+    let (_status, body) = harness.login("cleo", "correct horse").await;
+    let token = body["data"]["access_token"].as_str().unwrap().to_string();
+
+    let body = json!({"current_password": "wrong horse", "new_password": "battery staple"});
+    let (status, body) = harness.send("PUT", "/api/v1/users/me/password", Some(&token), Some(body)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["message"], "Invalid Passoword");
+    let body = json!({"password": "wrong horse"});
+    let (status, body) = harness.send("DELETE", "/api/v1/users/me/two_factor", Some(&token), Some(body)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["message"], "Invalid Passoword, the account is locked for 60 seconds");
+
+    // The lock holds even for the right password, here and at login
+    let body = json!({"password": "correct horse"});
+    let (status, _body) = harness.send("DELETE", "/api/v1/users/me", Some(&token), Some(body)).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    let (status, _body) = harness.login("cleo", "correct horse").await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+}
+
+/// A stand-in sign in provider on a local port. It only hands out a token for `good-code` with
+/// the verifier matching the challenge in `expected_challenge`, and serves `profile` for it.
+fn mock_provider(expected_challenge: Arc<Mutex<String>>, profile: Arc<Mutex<Value>>) -> SocialProvider {
+    let token = warp::post()
+        .and(warp::path("token"))
+        .and(warp::body::form())
+        .map(move |form: HashMap<String, String>| {
+            let verifier = form.get("code_verifier").cloned().unwrap_or_default();
+            let challenge = base64::encode_config(&Sha256::digest(verifier.as_bytes()), base64::URL_SAFE_NO_PAD);
+            if form.get("code").map(String::as_str) == Some("good-code") && challenge == *expected_challenge.lock().unwrap() {
+                let body = json!({"access_token": "provider-token", "token_type": "bearer"});
+                warp::reply::with_status(warp::reply::json(&body), StatusCode::OK)
+            } else {
+                let body = json!({"error": "invalid_grant"});
+                warp::reply::with_status(warp::reply::json(&body), StatusCode::BAD_REQUEST)
+            }
+        });
+    let userinfo = warp::get()
+        .and(warp::path("userinfo"))
+        .and(warp::header::<String>("authorization"))
+        .map(move |authorization: String| {
+            if authorization == "Bearer provider-token" {
+                warp::reply::with_status(warp::reply::json(&*profile.lock().unwrap()), StatusCode::OK)
+            } else {
+                warp::reply::with_status(warp::reply::json(&json!({})), StatusCode::UNAUTHORIZED)
+            }
+        });
+    let (address, server) = warp::serve(token.or(userinfo)).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+    SocialProvider {
+        name: "mock".to_string(),
+        client_id: "client".to_string(),
+        client_secret: "secret".to_string(),
+        auth_url: format!("http://{}/authorize", address),
+        token_url: format!("http://{}/token", address),
+        userinfo_url: format!("http://{}/userinfo", address),
+        scope: vec!["openid".to_string(), "email".to_string()],
+        id_field: "sub".to_string(),
+        email_field: "email".to_string(),
+        email_verified_field: "email_verified".to_string(),
+        trust_provider_email: false,
+    }
+}
+
+#[tokio::test]
+async fn social_logins_create_or_link_accounts_by_verified_email() {
+    let challenge = Arc::new(Mutex::new(String::new()));
+    let profile = Arc::new(Mutex::new(json!({"sub": "1001", "email": "bob@example.com", "email_verified": true})));
+    let provider = mock_provider(challenge.clone(), profile.clone());
+    let harness = Harness::with_config(|config| config.social.providers = vec![provider]);
+
+    // Each start has its own state and PKCE challenge
+    let start = || async {
+        let (status, body) = harness.send("GET", "/auth/mock/start", None, None).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        let url = url::Url::parse(body["data"]["authorization_url"].as_str().unwrap()).unwrap();
+        let params: HashMap<String, String> = url.query_pairs().into_owned().collect();
+        assert_eq!(params["code_challenge_method"], "S256");
+        assert_eq!(params["client_id"], "client");
+        *challenge.lock().unwrap() = params["code_challenge"].clone();
+        params["state"].clone()
+    };
+    let callback = |state: &str| format!("/auth/mock/callback?code=good-code&state={}", state);
+    let first = start().await;
+    let second = start().await;
+    assert_ne!(first, second);
+
+    let (status, _body) = harness.send("GET", &callback("forged"), None, None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, body) = harness.send("GET", &callback(&second), None, None).await;
+    assert_eq!(status, StatusCode::CREATED, "{}", body);
+    let token = body["data"]["access_token"].as_str().unwrap().to_string();
+    let (status, body) = harness.send("GET", "/api/v1/users/me", Some(&token), None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["data"]["username"], "bob");
+    assert_eq!(body["data"]["email"], "bob@example.com");
+    // States are single use
+    let (status, _body) = harness.send("GET", &callback(&second), None, None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    // An existing account with the same verified email gets linked rather than duplicated
+    harness.verified_user("carol", "carol@example.com").await;
+    *profile.lock().unwrap() = json!({"sub": "1002", "email": "carol@example.com", "email_verified": true});
+    let state = start().await;
+    let (status, body) = harness.send("GET", &callback(&state), None, None).await;
+    assert_eq!(status, StatusCode::CREATED, "{}", body);
+    let storage = &harness.server.database.storage;
+    let carol = storage.find_user("carol", None).unwrap().unwrap();
+    assert_eq!(carol.linked_accounts.len(), 1);
+    assert_eq!(carol.linked_accounts[0].subject, "1002");
+
+    // Once linked the provider's id is what counts, and unverified emails are never trusted
+    *profile.lock().unwrap() = json!({"sub": "1002", "email": "changed@example.com", "email_verified": false});
+    let state = start().await;
+    let (status, body) = harness.send("GET", &callback(&state), None, None).await;
+    assert_eq!(status, StatusCode::CREATED, "{}", body);
+    *profile.lock().unwrap() = json!({"sub": "1003", "email": "carol@example.com", "email_verified": false});
+    let state = start().await;
+    let (status, body) = harness.send("GET", &callback(&state), None, None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["message"], "mock has not verified carol@example.com");
+
+    let (status, _body) = harness.send("GET", "/auth/nobody/start", None, None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn social_login_trust_and_lifetime_come_from_config() {
+    let challenge = Arc::new(Mutex::new(String::new()));
+    let profile = Arc::new(Mutex::new(json!({"sub": "2001", "email": "dina@example.com"})));
+    let mut provider = mock_provider(challenge.clone(), profile.clone());
+    let start = |harness: &Harness| {
+        let routes = harness.routes();
+        let challenge = challenge.clone();
+        async move {
+            let response = warp::test::request().method("GET").path("/auth/mock/start").reply(&routes).await;
+            let body: Value = serde_json::from_slice(response.body()).unwrap();
+            let url = url::Url::parse(body["data"]["authorization_url"].as_str().unwrap()).unwrap();
+            let params: HashMap<String, String> = url.query_pairs().into_owned().collect();
+            *challenge.lock().unwrap() = params["code_challenge"].clone();
+            format!("/auth/mock/callback?code=good-code&state={}", params["state"])
+        }
+    };
+
+    // A profile that doesn't say the address is verified only counts from a trusted provider
+    let harness = Harness::with_config(|config| config.social.providers = vec![provider.clone()]);
+    let (status, body) = harness.send("GET", &start(&harness).await, None, None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["message"], "mock has not verified dina@example.com");
+    provider.trust_provider_email = true;
+    let harness = Harness::with_config(|config| config.social.providers = vec![provider.clone()]);
+    let (status, body) = harness.send("GET", &start(&harness).await, None, None).await;
+    assert_eq!(status, StatusCode::CREATED, "{}", body);
+
+    let harness = Harness::with_config(|config| {
+        config.social.providers = vec![provider.clone()];
+        config.auth.social_login_ttl = 0;
+    });
+    let (status, body) = harness.send("GET", &start(&harness).await, None, None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["message"], "Invalid or expired sign in state, please start again");
+}
+
+#[tokio::test]
+async fn magic_links_log_in_once_under_the_login_rules() {
+    let harness = Harness::with_config(|config| {
+        config.auth.lockout.account_attempts = 2;
+        config.auth.lockout.base_delay = 60;
+    });
+    harness.verified_user("zelda", "zelda@example.com").await;
+    let (status, body) = harness.request_magic_link("zelda@example.com").await;
+    assert_eq!(status, StatusCode::CREATED, "{}", body);
+    let login_token = body["data"]["login_token"].as_str().unwrap().to_string();
+    let link = format!(
+        "/api/v1/sessions/magic_link/confirm?username=zelda&login_token={}&login_code={}",
+        login_token,
+        harness.emailed_code()
+    );
+    // Opening the link only asks for a confirmation, and the confirmation logs in once
+    let (status, body) = harness.send("GET", &link, None, None).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let confirmation = body["data"].clone();
+    let (status, body) =
+        harness.send("POST", "/api/v1/sessions/magic_link/confirm", None, Some(confirmation.clone())).await;
+    assert_eq!(status, StatusCode::CREATED, "{}", body);
+    let access_token = body["data"]["access_token"].as_str().unwrap().to_string();
+    let (status, _body) = harness.send("GET", "/api/v1/users/me", Some(&access_token), None).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, body) =
+        harness.send("POST", "/api/v1/sessions/magic_link/confirm", None, Some(confirmation)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["message"], "Invalid or expired login code");
+
+    // Wrong codes count towards the lockout like wrong passwords
+    let (_status, body) = harness.request_magic_link("zelda@example.com").await;
+    let login_token = body["data"]["login_token"].as_str().unwrap().to_string();
+    let login_code = harness.emailed_code();
+    let confirm = |login_code: &str| json!({"username": "zelda", "login_token": login_token, "login_code": login_code});
+    let (status, body) = harness.send("POST", "/api/v1/sessions/magic_link/confirm", None, Some(confirm("nope"))).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["message"], "Invalid or expired login code, the account is locked for 60 seconds");
+    let (status, _body) =
+        harness.send("POST", "/api/v1/sessions/magic_link/confirm", None, Some(confirm(&login_code))).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+
+    // Unverified accounts can't log in this way either
+    let (status, _body) = harness.register("yusuf", "yusuf@example.com").await;
+    assert_eq!(status, StatusCode::CREATED);
+    let (_status, body) = harness.request_magic_link("yusuf@example.com").await;
+    let body = json!({
+        "username": "yusuf",
+        "login_token": body["data"]["login_token"],
+        "login_code": harness.emailed_code(),
+    });
+    let (status, body) = harness.send("POST", "/api/v1/sessions/magic_link/confirm", None, Some(body)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["message"], "Account has not yet been verified");
+
+    // The same link confirmed several times at once still gives a single session
+    harness.verified_user("xena", "xena@example.com").await;
+    let (_status, body) = harness.request_magic_link("xena@example.com").await;
+    let body = json!({
+        "username": "xena",
+        "login_token": body["data"]["login_token"],
+        "login_code": harness.emailed_code(),
+    });
+    let confirmations =
+        (0..4).map(|_| harness.send("POST", "/api/v1/sessions/magic_link/confirm", None, Some(body.clone())));
+    let results = futures::future::join_all(confirmations).await;
+    let sessions = results.iter().filter(|(status, _body)| *status == StatusCode::CREATED).count();
+    assert_eq!(sessions, 1, "{:?}", results);
+}
+
+#[tokio::test]
+async fn profile_password_and_email_can_be_changed() {
+    let harness = Harness::new();
+    harness.verified_user("abel", "abel@example.com").await;
+    let (_status, body) = harness.login("abel", "correct horse").await;
+    let token = body["data"]["access_token"].as_str().unwrap().to_string();
+    let (_status, body) = harness.login("abel", "correct horse").await;
+    let other_token = body["data"]["access_token"].as_str().unwrap().to_string();
+
+    let body = json!({"first_name": "Abel", "phone_number": "555-0100"});
+    let (status, body) = harness.send("PATCH", "/api/v1/users/me", Some(&token), Some(body)).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["data"]["first_name"], "Abel");
+    let body = json!({"phone_number": ""});
+    let (_status, body) = harness.send("PATCH", "/api/v1/users/me", Some(&token), Some(body)).await;
+    assert_eq!(body["data"]["first_name"], "Abel");
+    assert!(body["data"]["phone_number"].is_null());
+
+    let body = json!({"current_password": "wrong horse", "new_password": "battery staple"});
+    let (status, _body) = harness.send("PUT", "/api/v1/users/me/password", Some(&token), Some(body)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let body = json!({"current_password": "correct horse", "new_password": "short"});
+    let (status, _body) = harness.send("PUT", "/api/v1/users/me/password", Some(&token), Some(body)).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    let body = json!({"current_password": "correct horse", "new_password": "battery staple"});
+    let (status, body) = harness.send("PUT", "/api/v1/users/me/password", Some(&token), Some(body)).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let (status, _body) = harness.send("GET", "/api/v1/users/me", Some(&other_token), None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _body) = harness.login("abel", "battery staple").await;
+    assert_eq!(status, StatusCode::CREATED);
+
+    harness.verified_user("beth", "beth@example.com").await;
+    let body = json!({"email": "beth@example.com"});
+    let (status, _body) = harness.send("POST", "/api/v1/users/me/email", Some(&token), Some(body)).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    let body = json!({"email": "abel@example.org"});
+    let (status, body) = harness.send("POST", "/api/v1/users/me/email", Some(&token), Some(body)).await;
+    assert_eq!(status, StatusCode::CREATED, "{}", body);
+    let verify_token = body["data"]["verify_token"].as_str().unwrap().to_string();
+    let email: SendableEmail = harness.emailer.sent().last().unwrap().clone().into();
+    assert_eq!(email.envelope().to()[0].to_string(), "abel@example.org");
+    let verify_code = harness.emailed_code();
+
+    // The old address is still the one in use until the new one is confirmed
+    let (_status, body) = harness.send("GET", "/api/v1/users/me", Some(&token), None).await;
+    assert_eq!(body["data"]["email"], "abel@example.com");
+    assert_eq!(body["data"]["pending_email"], "abel@example.org");
+    let body = json!({"verify_token": verify_token, "verify_code": "wrong"});
+    let (status, _body) = harness.send("POST", "/api/v1/users/me/email/confirm", Some(&token), Some(body)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let body = json!({"verify_token": verify_token, "verify_code": verify_code});
+    let (status, body) = harness.send("POST", "/api/v1/users/me/email/confirm", Some(&token), Some(body)).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["data"]["email"], "abel@example.org");
+    assert!(body["data"]["pending_email"].is_null());
+}
+
+#[tokio::test]
+async fn accounts_can_be_exported_and_deleted_after_a_grace_period() {
+    let harness = Harness::new();
+    harness.verified_user("cora", "cora@example.com").await;
+    let storage = &harness.server.database.storage;
+    let mut user = storage.find_user("cora", None).unwrap().unwrap();
+    user.access_level = "author".to_string();
+    storage.update_user("cora", &user).unwrap();
+    let (_status, body) = harness.login("cora", "correct horse").await;
+    let token = body["data"]["access_token"].as_str().unwrap().to_string();
+    let body = json!({"title": "Hello", "body": "First post"});
+    let (status, body) = harness.send("POST", "/api/v1/posts", Some(&token), Some(body)).await;
+    assert_eq!(status, StatusCode::CREATED, "{}", body);
+    let post_id = body["data"]["id"].as_str().unwrap().to_string();
+
+    let (status, body) = harness.send("GET", "/api/v1/users/me/export", Some(&token), None).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["data"]["profile"]["username"], "cora");
+    assert_eq!(body["data"]["verified"], true);
+    assert_eq!(body["data"]["sessions"].as_array().unwrap().len(), 1);
+    assert_eq!(body["data"]["posts"][0]["id"], post_id.as_str());
+    assert!(!body.to_string().contains(&token));
+
+    let body = json!({"password": "wrong horse"});
+    let (status, _body) = harness.send("DELETE", "/api/v1/users/me", Some(&token), Some(body)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let body = json!({"password": "correct horse"});
+    let (status, body) = harness.send("DELETE", "/api/v1/users/me", Some(&token), Some(body)).await;
+    assert_eq!(status, StatusCode::ACCEPTED, "{}", body);
+    let (status, _body) = harness.send("GET", "/api/v1/users/me", Some(&token), None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    // Logging in during the grace period keeps the account
+    let (_status, body) = harness.login("cora", "correct horse").await;
+    let token = body["data"]["access_token"].as_str().unwrap().to_string();
+    let (_status, body) = harness.send("GET", "/api/v1/users/me", Some(&token), None).await;
+    assert!(body["data"]["deletion_scheduled"].is_null());
+    let body = json!({"password": "correct horse"});
+    let (status, _body) = harness.send("DELETE", "/api/v1/users/me", Some(&token), Some(body)).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+
+    // Half finished logins and failure counters go along with the account
+    let (status, _body) = harness.login("cora", "wrong horse").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let body = json!({"email": "cora@example.com"});
+    let (status, _body) = harness.send("POST", "/api/v1/password_resets", None, Some(body)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let body = json!({"username": "cora", "reset_token": "nope", "reset_code": "nope", "password": "battery staple"});
+    let (status, _body) = harness.send("POST", "/api/v1/password_resets/confirm", None, Some(body)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let server = &harness.server;
+    let challenge = LoginChallenge::new(&user.id, &SessionClient::default(), &harness.config.auth);
+    let challenge = challenge.hashed(&server.database.tokens);
+    storage.insert_login_challenge(&challenge).unwrap();
+
+    assert_eq!(DatabaseController::purge_deleted_users(server, &harness.config).unwrap(), 0);
+    let mut user = storage.find_user("cora", None).unwrap().unwrap();
+    let account_key = LoginAttempts::account_key(&user.id);
+    let reset_key = LoginAttempts::reset_key(&user.password_reset.as_ref().unwrap().reset_token);
+    assert!(storage.find_login_attempts(&account_key).unwrap().is_some());
+    assert!(storage.find_login_attempts(&reset_key).unwrap().is_some());
+    user.deletion_scheduled = Some("0".to_string());
+    storage.update_user("cora", &user).unwrap();
+    assert_eq!(DatabaseController::purge_deleted_users(server, &harness.config).unwrap(), 1);
+    assert!(storage.find_user("cora", None).unwrap().is_none());
+    assert!(storage.find_post(Some(&post_id), None).unwrap().is_none());
+    assert!(storage.list_security_events(&user.id).unwrap().is_empty());
+    assert!(storage.find_login_challenge(&challenge.token).unwrap().is_none());
+    assert!(storage.find_login_attempts(&account_key).unwrap().is_none());
+    assert!(storage.find_login_attempts(&reset_key).unwrap().is_none());
+    let (status, _body) = harness.login("cora", "correct horse").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn social_logins_take_unverified_accounts_away_from_squatters() {
+    let challenge = Arc::new(Mutex::new(String::new()));
+    let profile = Arc::new(Mutex::new(json!({"sub": "2001", "email": "victim@example.com", "email_verified": true})));
+    let provider = mock_provider(challenge.clone(), profile);
+    let harness = Harness::with_config(|config| config.social.providers = vec![provider]);
+    // Registered with someone else's address and a password the squatter knows, never verified
+    let (status, _body) = harness.register("mallory", "victim@example.com").await;
+    assert_eq!(status, StatusCode::CREATED);
+
+    let (_status, body) = harness.send("GET", "/auth/mock/start", None, None).await;
+    let url = url::Url::parse(body["data"]["authorization_url"].as_str().unwrap()).unwrap();
+    let params: HashMap<String, String> = url.query_pairs().into_owned().collect();
+    *challenge.lock().unwrap() = params["code_challenge"].clone();
+    let callback = format!("/auth/mock/callback?code=good-code&state={}", params["state"]);
+    let (status, body) = harness.send("GET", &callback, None, None).await;
+    assert_eq!(status, StatusCode::CREATED, "{}", body);
+
+    let user = harness.server.database.storage.find_user("mallory", None).unwrap().unwrap();
+    assert!(user.verify.unwrap().verified);
+    assert_eq!(user.linked_accounts.len(), 1);
+    // The squatter's password no longer gets in
+    let (status, body) = harness.login("mallory", "correct horse").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["message"], "Invalid Passoword");
+}
+
+#[tokio::test]
+async fn only_listing_your_own_posts_needs_a_token() {
+    let harness = Harness::new();
+    let token = harness.member("olga", "author").await;
+    let posts = [
+        json!({"title": "Draft", "body": "Not yet"}),
+        json!({"title": "Out", "body": "Now", "status": "published"}),
+    ];
+    for post in posts.iter() {
+        let (status, body) = harness.send("POST", "/api/v1/posts", Some(&token), Some(post.clone())).await;
+        assert_eq!(status, StatusCode::CREATED, "{}", body);
+    }
+
+    let (status, body) = harness.send("GET", "/api/v1/posts?mine=false", Some(&token), None).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["data"].as_array().unwrap().len(), 1);
+    let (status, _body) = harness.send("GET", "/api/v1/posts?mine=false", None, None).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _body) = harness.send("GET", "/api/v1/posts?mine=true", None, None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, body) = harness.send("GET", "/api/v1/posts?mine=true", Some(&token), None).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["data"].as_array().unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn unusable_post_input_is_a_validation_error() {
+    let harness = Harness::new();
+    let token = harness.member("pavel", "author").await;
+    let body = json!({"title": "???", "body": "Punctuation only"});
+    let (status, body) = harness.send("POST", "/api/v1/posts", Some(&token), Some(body)).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(error_code(&body), 5);
+    assert_eq!(invalid_fields(&body), vec!["slug"]);
+
+    let body = json!({"title": "Words", "body": "Fine"});
+    let (_status, body) = harness.send("POST", "/api/v1/posts", Some(&token), Some(body)).await;
+    let path = format!("/api/v1/posts/{}", body["data"]["id"].as_str().unwrap());
+    let (status, body) = harness.send("PATCH", &path, Some(&token), Some(json!({"slug": "!!!"}))).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(invalid_fields(&body), vec!["slug"]);
+
+    let body = json!({"data": {}});
+    let (status, body) = harness.send("POST", "/api/1/get_post", None, Some(body)).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(invalid_fields(&body), vec!["id"]);
+}
+
+#[tokio::test]
+async fn unusable_uploads_are_a_validation_error() {
+    let dir = std::env::temp_dir().join(format!("qamaits-media-{}-uploads", std::process::id()));
+    let harness = Harness::with_config(|config| {
+        config.media.storage_dir = dir.to_str().unwrap().to_string();
+    });
+    let token = harness.member("rosa", "author").await;
+    let (status, body) = harness.upload(&token, None, &[("visibility", "public")]).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY, "{}", body);
+    assert_eq!(invalid_fields(&body), vec!["file"]);
+    let (status, body) = harness.upload(&token, Some(("text/html", b"<script></script>")), &[]).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY, "{}", body);
+    assert_eq!(invalid_fields(&body), vec!["file"]);
+    let (status, body) = harness.upload(&token, Some(("image/png", b"png")), &[]).await;
+    assert_eq!(status, StatusCode::CREATED, "{}", body);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn admins_change_access_levels_but_not_their_own() {
+    let harness = Harness::new();
+    let admin = harness.member("sven", "admin").await;
+    let author = harness.member("tove", "author").await;
+    let change = |level: &str| json!({ "access_level": level });
+    let path = "/api/v1/users/tove/access_level";
+
+    let (status, _body) = harness.send("PUT", path, Some(&author), Some(change("editor"))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, body) = harness.send("PUT", path, Some(&admin), Some(change("overlord"))).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(invalid_fields(&body), vec!["access_level"]);
+    let (status, body) = harness.send("PUT", path, Some(&admin), Some(change("editor"))).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["data"]["access_level"], "editor");
+
+    let path = "/api/v1/users/sven/access_level";
+    let (status, body) = harness.send("PUT", path, Some(&admin), Some(change("subscriber"))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["message"], "You can't change your own access level");
+    let user = harness.server.database.storage.find_user("sven", None).unwrap().unwrap();
+    assert_eq!(user.access_level, "admin");
+}
+
+#[tokio::test]
+async fn drafts_are_only_shown_to_their_author_and_editors() {
+    let harness = Harness::new();
+    let author = harness.member("ines", "author").await;
+    let other = harness.member("jonas", "author").await;
+    let editor = harness.member("kai", "editor").await;
+    let body = json!({"title": "Work in progress", "body": "Soon"});
+    let (_status, body) = harness.send("POST", "/api/v1/posts", Some(&author), Some(body)).await;
+    assert_eq!(body["data"]["status"], "draft");
+    let by_id = format!("/api/v1/posts/{}", body["data"]["id"].as_str().unwrap());
+    let by_slug = "/api/v1/posts/work-in-progress";
+
+    for path in [by_id.as_str(), by_slug].iter() {
+        let (status, _body) = harness.send("GET", path, None, None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _body) = harness.send("GET", path, Some(&other), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, body) = harness.send("GET", path, Some(&author), None).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        let (status, _body) = harness.send("GET", path, Some(&editor), None).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    let (status, _body) = harness.send("PATCH", &by_id, Some(&author), Some(json!({"status": "published"}))).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, body) = harness.send("GET", by_slug, None, None).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["data"]["title"], "Work in progress");
+}
+
+#[tokio::test]
+async fn private_media_is_missing_for_everyone_but_its_owner() {
+    let dir = std::env::temp_dir().join(format!("qamaits-media-{}-private", std::process::id()));
+    let harness = Harness::with_config(|config| {
+        config.media.storage_dir = dir.to_str().unwrap().to_string();
+    });
+    let owner = harness.member("lena", "author").await;
+    let other = harness.member("milo", "author").await;
+    let (status, body) = harness.upload(&owner, Some(("image/png", b"secret")), &[("visibility", "private")]).await;
+    assert_eq!(status, StatusCode::CREATED, "{}", body);
+    let path = format!("/media/{}", body["data"]["id"].as_str().unwrap());
+
+    let (status, _body) = harness.send("GET", &path, None, None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _body) = harness.send("GET", &path, Some(&other), None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _body) = harness.send("GET", &path, Some(&owner), None).await;
+    assert_eq!(status, StatusCode::OK);
+    // Credentials in the query string aren't taken, even the owner's
+    let query = format!("{}?username=lena&access_token={}", path, owner);
+    let (status, _body) = harness.send("GET", &query, None, None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let (_status, body) = harness.upload(&owner, Some(("image/png", b"public")), &[]).await;
+    let path = format!("/media/{}", body["data"]["id"].as_str().unwrap());
+    let (status, _body) = harness.send("GET", &path, None, None).await;
+    assert_eq!(status, StatusCode::OK);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn editors_may_change_anyones_posts_and_media() {
+    let dir = std::env::temp_dir().join(format!("qamaits-media-{}-overrides", std::process::id()));
+    let harness = Harness::with_config(|config| {
+        config.media.storage_dir = dir.to_str().unwrap().to_string();
+    });
+    let author = harness.member("nils", "author").await;
+    let other = harness.member("oona", "author").await;
+    let editor = harness.member("pia", "editor").await;
+    let body = json!({"title": "Mine", "body": "All mine"});
+    let (_status, body) = harness.send("POST", "/api/v1/posts", Some(&author), Some(body)).await;
+    let post = format!("/api/v1/posts/{}", body["data"]["id"].as_str().unwrap());
+    let (_status, body) = harness.upload(&author, Some(("image/png", b"picture")), &[]).await;
+    let media = format!("/api/v1/media/{}", body["data"]["id"].as_str().unwrap());
+
+    let edit = json!({"title": "Edited"});
+    let (status, body) = harness.send("PATCH", &post, Some(&other), Some(edit.clone())).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["message"], "The author access level does not have the edit_any_post permission");
+    let (status, _body) = harness.send("DELETE", &post, Some(&other), None).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _body) = harness.send("DELETE", &media, Some(&other), None).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let (status, body) = harness.send("PATCH", &post, Some(&editor), Some(edit)).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["data"]["title"], "Edited");
+    let (status, _body) = harness.send("DELETE", &post, Some(&editor), None).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _body) = harness.send("DELETE", &media, Some(&editor), None).await;
+    assert_eq!(status, StatusCode::OK);
+    let _ = std::fs::remove_dir_all(&dir);
+}